@@ -44,6 +44,9 @@ async fn main() {
 
   let message = Message {
     transaction_id: trans_id.as_ref().to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
     body: MessageBody::Request(Request::FindNode(FindNodeRequest {
       id: table_id,
       target: table_id,
@@ -75,7 +75,7 @@ async fn announce_and_lookup() {
   let mut search = a_node.search(info_hash_2, false);
   log::info!("Assert a node search the 'foo' from b node.");
   assert_eq!(search.next().await, Some(b_addr));
-  
+
   // create a new node c and start it.
   let (c_node, c_addr) =
     create_v4_node("c_node", Some(vec![bootstrap_node_addr])).await;
@@ -84,7 +84,7 @@ async fn announce_and_lookup() {
   let mut search = c_node.search(info_hash_1, false);
   log::info!("Assert c node search the 'foo' from a node and b node.");
   while let Some(node_addr) = search.next().await {
-    assert!(vec![a_addr, b_addr].contains(&node_addr));
+    assert!([a_addr, b_addr].contains(&node_addr));
   }
 
   let (d_node, _d_addr) = create_v4_node("d_node", Some(vec![c_addr])).await;
@@ -0,0 +1,169 @@
+//! Bloom filters to estimate the size of a swarm (BEP33).
+//!
+//! Reference link:
+//!   http://bittorrent.org/beps/bep_0033.html
+//!
+//! Nodes answer a scrape with one filter of the IP addresses of the seeds
+//! and one of the other peers they store. The filters of several nodes can be
+//! merged, so the estimation does not count twice the peers announced to
+//! multiple nodes.
+
+use std::{fmt, net::IpAddr};
+
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use serde_bytes::{ByteBuf, Bytes};
+use sha1::{Digest, Sha1};
+
+/// Length of the filter in bytes.
+pub const BLOOM_FILTER_LEN: usize = 256;
+
+// Number of bits of the filter (m in the BEP).
+const NUM_BITS: usize = BLOOM_FILTER_LEN * 8;
+// Number of bits set for every element (k in the BEP).
+const NUM_HASHES: usize = 2;
+
+/// A 2048 bit bloom filter of IP addresses.
+#[derive(Clone, PartialEq, Eq)]
+pub struct BloomFilter(Box<[u8; BLOOM_FILTER_LEN]>);
+
+impl BloomFilter {
+  /// Create an empty filter.
+  pub fn new() -> BloomFilter {
+    BloomFilter(Box::new([0u8; BLOOM_FILTER_LEN]))
+  }
+
+  /// Add the IP address to the filter.
+  pub fn insert(&mut self, ip: IpAddr) {
+    let hash = match ip {
+      IpAddr::V4(ip) => Sha1::digest(ip.octets()),
+      IpAddr::V6(ip) => Sha1::digest(ip.octets()),
+    };
+
+    for i in 0..NUM_HASHES {
+      let index =
+        (hash[2 * i] as usize | (hash[2 * i + 1] as usize) << 8) % NUM_BITS;
+      self.0[index / 8] |= 1 << (index % 8);
+    }
+  }
+
+  /// Merge the elements of the other filter into this one.
+  pub fn union(&mut self, other: &BloomFilter) {
+    for (byte, other) in self.0.iter_mut().zip(other.0.iter()) {
+      *byte |= other;
+    }
+  }
+
+  /// Estimate the number of distinct addresses in the filter.
+  pub fn estimate(&self) -> usize {
+    let zeros = self
+      .0
+      .iter()
+      .map(|b| b.count_zeros() as usize)
+      .sum::<usize>();
+    if zeros == NUM_BITS {
+      return 0;
+    }
+    // A full filter would give an infinite estimation.
+    let zeros = zeros.max(1) as f64;
+    let m = NUM_BITS as f64;
+
+    let estimate =
+      (zeros / m).ln() / (NUM_HASHES as f64 * (1.0 - 1.0 / m).ln());
+
+    estimate.round() as usize
+  }
+
+  /// Whether no address was added to the filter.
+  pub fn is_empty(&self) -> bool {
+    self.0.iter().all(|b| *b == 0)
+  }
+
+  pub fn as_bytes(&self) -> &[u8; BLOOM_FILTER_LEN] {
+    &self.0
+  }
+}
+
+impl Default for BloomFilter {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl From<[u8; BLOOM_FILTER_LEN]> for BloomFilter {
+  fn from(bytes: [u8; BLOOM_FILTER_LEN]) -> Self {
+    BloomFilter(Box::new(bytes))
+  }
+}
+
+impl fmt::Debug for BloomFilter {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "BloomFilter(~{})", self.estimate())
+  }
+}
+
+impl Serialize for BloomFilter {
+  fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+    Bytes::new(&self.0[..]).serialize(s)
+  }
+}
+
+impl<'de> Deserialize<'de> for BloomFilter {
+  fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+    let bytes = ByteBuf::deserialize(d)?;
+    let bytes =
+      <[u8; BLOOM_FILTER_LEN]>::try_from(bytes.as_ref()).map_err(|_| {
+        D::Error::invalid_length(bytes.len(), &"256 bytes bloom filter")
+      })?;
+
+    Ok(BloomFilter(Box::new(bytes)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+  use crate::bloom::BloomFilter;
+  use pretty_assertions::assert_eq;
+
+  // Test vector from the BEP.
+  fn bep_filter() -> BloomFilter {
+    let mut filter = BloomFilter::new();
+
+    for i in 0..=255u8 {
+      filter.insert(IpAddr::V4(Ipv4Addr::new(192, 0, 2, i)));
+    }
+    for i in 0..=0x3e7u16 {
+      filter.insert(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i)));
+    }
+
+    filter
+  }
+
+  #[test]
+  fn positive_estimate_bep_vector() {
+    assert_eq!(bep_filter().estimate(), 1225);
+  }
+
+  #[test]
+  fn positive_union_counts_shared_addresses_once() {
+    let mut a = BloomFilter::new();
+    let mut b = BloomFilter::new();
+
+    for i in 0..20u8 {
+      a.insert(IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)));
+      b.insert(IpAddr::V4(Ipv4Addr::new(10, 0, 0, i + 10)));
+    }
+    a.union(&b);
+
+    assert_eq!(a.estimate(), 30);
+  }
+
+  #[test]
+  fn positive_empty_filter() {
+    let filter = BloomFilter::new();
+
+    assert!(filter.is_empty());
+    assert_eq!(filter.estimate(), 0);
+  }
+}
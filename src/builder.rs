@@ -1,20 +1,41 @@
 use std::{
-  collections::HashSet, io, net::SocketAddr, pin::Pin, time::Duration,
+  collections::HashSet,
+  io,
+  net::{IpAddr, SocketAddr},
+  path::PathBuf,
+  pin::Pin,
+  time::Duration,
 };
 
 use futures_util::Stream;
 use tokio::{
-  sync::{mpsc, oneshot},
+  sync::{mpsc, oneshot, watch},
   task::{self, JoinHandle},
 };
 
 use crate::{
+  dual_stack::DualStackDht,
   id::{InfoHash, NodeId},
-  routing::table::RoutingTable,
-  worker::{DhtHandler, OneShotTask, Socket, StartLookup, State},
-  SocketTrait,
+  item::{self, MutableItem, PUBLIC_KEY_LEN},
+  message::{Response, Value},
+  resolver::{self, ResolveStream},
+  routing::{node::NodeHandle, persist::SavedTable, table::RoutingTable},
+  security,
+  worker::{
+    AnnounceOutcome, AnnounceResult, AnnounceStatus, DhtHandler, OneShotTask,
+    RpcError, RpcRequest, Scrape, SearchEvent, SearchOptions, ShutdownHook,
+    Sibling, Socket, StartItemLookup, StartLookup, StartMutableItemLookup,
+    StartNodesLookup, StartRpc, StartScrape, State,
+  },
+  IpVersion, SocketTrait,
 };
 
+/// Lookups running at the same time unless configured otherwise.
+const DEFAULT_MAX_CONCURRENT_LOOKUPS: usize = 32;
+
+/// Lookups waiting for a running one to complete unless configured otherwise.
+const DEFAULT_MAX_QUEUED_LOOKUPS: usize = 1024;
+
 /// Maintains a Distributed Hash (Routing Table).
 ///
 /// This type is cheaply clone able where each clone refers to the same underlying
@@ -28,11 +49,13 @@ use crate::{
 /// `MainlineDht` instances, one bound to an IPv4 and the other to an IPv6 address.
 /// It is recommended the both instances use the same node id ([`DhtBuilder::set_node_id`]).
 /// Any lookup should then be performed on both instances and their results aggregated.
+/// [`DhtBuilder::start_dual_stack`] does all of this, and lets the instances share the
+/// nodes they learn of the other address family, see [`DualStackDht`].
 pub struct MainlineDht {
   name: String,
   send: mpsc::UnboundedSender<OneShotTask>,
+  external_addr: watch::Receiver<Option<SocketAddr>>,
   // used for graceful shutdown.
-  #[allow(dead_code)]
   dht_handler: JoinHandle<()>,
 }
 
@@ -45,16 +68,42 @@ impl MainlineDht {
       read_only: true,
       announce_port: None,
       node_id: None,
+      external_ip: None,
+      enforce_node_id: false,
+      client_version: None,
+      max_concurrent_lookups: DEFAULT_MAX_CONCURRENT_LOOKUPS,
+      max_queued_lookups: DEFAULT_MAX_QUEUED_LOOKUPS,
+      shutdown_hooks: Vec::new(),
+      saved_table: None,
+      table_file: None,
     }
   }
 
   /// Start the MainlineDht with the given DhtBuilder.
   fn with_builder(name: String, builder: DhtBuilder, socket: Socket) -> Self {
     let (command_tx, command_rx) = mpsc::unbounded_channel();
+    Self::with_channel(name, builder, socket, command_tx, command_rx, None)
+  }
+
+  /// Start the MainlineDht with the given DhtBuilder, receiving its commands
+  /// on the given channel.
+  fn with_channel(
+    name: String,
+    builder: DhtBuilder,
+    socket: Socket,
+    command_tx: mpsc::UnboundedSender<OneShotTask>,
+    command_rx: mpsc::UnboundedReceiver<OneShotTask>,
+    sibling: Option<Sibling>,
+  ) -> Self {
+    let (external_addr_tx, external_addr_rx) = watch::channel(None);
 
-    // TODO: Utilize the security extension.
-    let routing_table =
-      RoutingTable::new(builder.node_id.unwrap_or_else(rand::random));
+    let node_id = builder.node_id_for(&socket);
+    let routing_table = RoutingTable::new(node_id);
+    let restored_nodes = builder
+      .saved_table
+      .as_ref()
+      .map(SavedTable::node_handles)
+      .unwrap_or_default();
 
     let log_name = name.clone();
     let mainline_name = name.clone();
@@ -62,12 +111,19 @@ impl MainlineDht {
     let handler = DhtHandler::new(
       name,
       routing_table,
+      builder.node_id.is_some(),
       socket,
-      builder.read_only,
+      builder.enforce_node_id,
       builder.routers,
       builder.nodes,
+      restored_nodes,
       builder.announce_port,
+      builder.max_concurrent_lookups,
+      builder.max_queued_lookups,
       command_rx,
+      external_addr_tx,
+      sibling,
+      builder.shutdown_hooks,
     );
 
     if command_tx.send(OneShotTask::StartBootstrap()).is_err() {
@@ -82,6 +138,7 @@ impl MainlineDht {
     MainlineDht {
       name: mainline_name,
       send: command_tx,
+      external_addr: external_addr_rx,
       dht_handler,
     }
   }
@@ -132,15 +189,35 @@ impl MainlineDht {
   ///
   /// If the initial bootstrap has not finished, the search will be queued and executed once
   /// the bootstrap has completed.
+  ///
+  /// Concurrent searches for the same InfoHash share a single lookup, a search joining it
+  /// late gets the peers found so far first.
+  ///
+  /// Dropping the returned stream, or calling [`SearchStream::cancel`], stops the search
+  /// along with its announce.
   pub fn search(&self, info_hash: InfoHash, announce: bool) -> SearchStream {
+    self.search_with(info_hash, SearchOptions::new().set_announce(announce))
+  }
+
+  /// Perform a search for the given InfoHash with the given options.
+  ///
+  /// See [`search`](Self::search), the options also allow to stop the search
+  /// early and to tell what to announce.
+  pub fn search_with(
+    &self,
+    info_hash: InfoHash,
+    options: SearchOptions,
+  ) -> SearchStream {
     let (tx, rx) = mpsc::unbounded_channel();
 
     if self
       .send
       .send(OneShotTask::StartLookup(StartLookup {
         info_hash,
-        announce,
+        options,
         tx,
+        outcome_tx: None,
+        events_tx: None,
       }))
       .is_err()
     {
@@ -153,12 +230,409 @@ impl MainlineDht {
     SearchStream(rx)
   }
 
+  /// Perform a search for the given InfoHash, reporting its progress.
+  ///
+  /// The stream yields the peers found along with the requests sent and
+  /// answered, and ends with [`SearchEvent::Finished`] once the search
+  /// completed.
+  pub fn search_events(
+    &self,
+    info_hash: InfoHash,
+    options: SearchOptions,
+  ) -> SearchEventStream {
+    let (tx, _peers) = mpsc::unbounded_channel();
+    let (events_tx, events_rx) = mpsc::unbounded_channel();
+
+    if self
+      .send
+      .send(OneShotTask::StartLookup(StartLookup {
+        info_hash,
+        options,
+        tx,
+        outcome_tx: None,
+        events_tx: Some(events_tx),
+      }))
+      .is_err()
+    {
+      log::error!(
+        "[{}]failed to start search - DhtHandler has shut down",
+        self.name
+      );
+    }
+
+    SearchEventStream(events_rx)
+  }
+
+  /// Announce ourselves for the given InfoHash on the closest nodes and
+  /// report what each of them answered.
+  ///
+  /// If a recent search for the InfoHash left us with tokens that are still
+  /// valid, the announces go straight to those nodes without a new search.
+  /// Once the tokens are too old, those nodes are asked for new ones first.
+  ///
+  /// Resolves once every announce was accepted, rejected or timed out.
+  pub async fn announce(
+    &self,
+    info_hash: InfoHash,
+  ) -> io::Result<Vec<AnnounceResult>> {
+    let (tx, _peers) = mpsc::unbounded_channel();
+    let (outcome_tx, mut outcome_rx) = mpsc::unbounded_channel();
+
+    self
+      .send
+      .send(OneShotTask::StartLookup(StartLookup {
+        info_hash,
+        options: SearchOptions::new()
+          .set_announce(true)
+          .set_announce_only(true),
+        tx,
+        outcome_tx: Some(outcome_tx),
+        events_tx: None,
+      }))
+      .map_err(|_| io::Error::other("DhtHandler has shutdown."))?;
+
+    // The channel is closed once the lookup is completed.
+    let mut results = Vec::new();
+    while let Some(result) = outcome_rx.recv().await {
+      results.push(result);
+    }
+
+    Ok(results)
+  }
+
+  /// Announce ourselves for the given InfoHash on the given port now, then
+  /// again periodically until [`stop_announcing`](Self::stop_announcing).
+  ///
+  /// The announces run in the background, after the searches of the user. Calling it again
+  /// for the same InfoHash only changes the port of the next announces.
+  pub fn start_announcing(
+    &self,
+    info_hash: InfoHash,
+    port: u16,
+  ) -> io::Result<()> {
+    self
+      .send
+      .send(OneShotTask::StartAnnouncing(info_hash, port))
+      .map_err(|_| io::Error::other("DhtHandler has shutdown."))
+  }
+
+  /// Stop announcing ourselves for the given InfoHash. An announce in progress
+  /// still runs to its end.
+  pub fn stop_announcing(&self, info_hash: InfoHash) -> io::Result<()> {
+    self
+      .send
+      .send(OneShotTask::StopAnnouncing(info_hash))
+      .map_err(|_| io::Error::other("DhtHandler has shutdown."))
+  }
+
+  /// Get the status of the periodic announce of the given InfoHash, `None` if
+  /// we do not announce it.
+  pub async fn announce_status(
+    &self,
+    info_hash: InfoHash,
+  ) -> Option<AnnounceStatus> {
+    let (tx, rx) = oneshot::channel();
+
+    if self
+      .send
+      .send(OneShotTask::GetAnnounceStatus(info_hash, tx))
+      .is_err()
+    {
+      None
+    } else {
+      rx.await.ok().flatten()
+    }
+  }
+
+  /// Check whether the node at the given address is reachable.
+  pub async fn ping(&self, addr: SocketAddr) -> Result<Response, RpcError> {
+    self.send_rpc(addr, RpcRequest::Ping).await
+  }
+
+  /// Ask the node at the given address for the nodes it knows closest to the
+  /// target.
+  pub async fn find_node(
+    &self,
+    addr: SocketAddr,
+    target: NodeId,
+  ) -> Result<Response, RpcError> {
+    self.send_rpc(addr, RpcRequest::FindNode { target }).await
+  }
+
+  /// Ask the node at the given address for the peers of the InfoHash, the
+  /// response carries the token to announce ourselves with.
+  pub async fn get_peers(
+    &self,
+    addr: SocketAddr,
+    info_hash: InfoHash,
+  ) -> Result<Response, RpcError> {
+    self
+      .send_rpc(addr, RpcRequest::GetPeers { info_hash })
+      .await
+  }
+
+  /// Announce ourselves as a peer of the InfoHash to the node at the given
+  /// address, with the token of a previous `get_peers`.
+  ///
+  /// If no port is given, the node will use the source port of the request
+  /// (implied port).
+  pub async fn announce_peer(
+    &self,
+    addr: SocketAddr,
+    info_hash: InfoHash,
+    token: Vec<u8>,
+    port: Option<u16>,
+  ) -> Result<Response, RpcError> {
+    let request = RpcRequest::AnnouncePeer {
+      info_hash,
+      token,
+      port,
+    };
+    self.send_rpc(addr, request).await
+  }
+
+  /// Send the request to a single node and wait for its response, or for the
+  /// request to time out.
+  async fn send_rpc(
+    &self,
+    addr: SocketAddr,
+    request: RpcRequest,
+  ) -> Result<Response, RpcError> {
+    let (tx, rx) = oneshot::channel();
+
+    self
+      .send
+      .send(OneShotTask::StartRpc(StartRpc { addr, request, tx }))
+      .map_err(|_| RpcError::Shutdown)?;
+
+    rx.await.unwrap_or(Err(RpcError::Shutdown))
+  }
+
+  /// Estimate the number of seeds and peers of the given InfoHash (BEP33),
+  /// without connecting to the peers.
+  ///
+  /// Returns `None` if none of the nodes close to the InfoHash supports
+  /// scraping.
+  pub async fn scrape(&self, info_hash: InfoHash) -> Option<Scrape> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    self
+      .send
+      .send(OneShotTask::StartScrape(StartScrape { info_hash, tx }))
+      .ok()?;
+
+    rx.recv().await
+  }
+
+  /// Find the nodes closest to the given target in the keyspace.
+  ///
+  /// Returns up to 8 nodes which answered our find_node queries, sorted by
+  /// their distance to the target.
+  pub async fn find_closest_nodes(&self, target: NodeId) -> Vec<NodeHandle> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    if self
+      .send
+      .send(OneShotTask::StartNodesLookup(StartNodesLookup {
+        target,
+        tx,
+      }))
+      .is_err()
+    {
+      return Vec::new();
+    }
+
+    rx.recv().await.unwrap_or_default()
+  }
+
+  /// Retrieve the immutable item (BEP44) stored under the given target, which
+  /// is the SHA-1 hash of its bencoded value.
+  ///
+  /// Returns `None` if none of the nodes close to the target has the item.
+  pub async fn get_immutable(&self, target: InfoHash) -> Option<Value> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    self
+      .send
+      .send(OneShotTask::StartItemLookup(StartItemLookup {
+        target,
+        put: None,
+        tx,
+        outcome_tx: None,
+      }))
+      .ok()?;
+
+    rx.recv().await
+  }
+
+  /// Store an immutable item (BEP44) on the nodes closest to its target.
+  ///
+  /// Returns the target the item can be retrieved with, once the closest
+  /// nodes answered. Fails if none of them stored the item.
+  pub async fn put_immutable(&self, value: Value) -> io::Result<InfoHash> {
+    let target = item::immutable_target(&value)
+      .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+    let (tx, _items) = mpsc::unbounded_channel();
+    let (outcome_tx, outcome_rx) = mpsc::unbounded_channel();
+
+    self
+      .send
+      .send(OneShotTask::StartItemLookup(StartItemLookup {
+        target,
+        put: Some(value),
+        tx,
+        outcome_tx: Some(outcome_tx),
+      }))
+      .map_err(|_| io::Error::other("DhtHandler has shutdown."))?;
+
+    put_result(target, outcome_rx).await
+  }
+
+  /// Retrieve the mutable item (BEP44) of the given public key and salt.
+  ///
+  /// Returns the version with the highest sequence number found on the closest
+  /// nodes, or `None` if none of them has the item.
+  pub async fn get_mutable(
+    &self,
+    public_key: [u8; PUBLIC_KEY_LEN],
+    salt: Option<Vec<u8>>,
+  ) -> Option<MutableItem> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    self
+      .send
+      .send(OneShotTask::StartMutableItemLookup(
+        StartMutableItemLookup {
+          public_key,
+          salt,
+          put: None,
+          cas: None,
+          tx,
+          outcome_tx: None,
+        },
+      ))
+      .ok()?;
+
+    rx.recv().await
+  }
+
+  /// Publish a new version of a mutable item (BEP44) on the nodes closest to
+  /// its target, see [`MutableItem::sign`].
+  ///
+  /// If `cas` is given, the nodes only accept the item if the version they
+  /// store has that sequence number.
+  ///
+  /// Returns the target the item can be retrieved with, once the closest
+  /// nodes answered. Fails if none of them stored the item, with the error
+  /// one of them answered if any (e.g. 301 when `cas` does not match).
+  pub async fn put_mutable(
+    &self,
+    item: MutableItem,
+    cas: Option<i64>,
+  ) -> io::Result<InfoHash> {
+    item
+      .verify()
+      .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+    let target = item.target();
+    let (tx, _items) = mpsc::unbounded_channel();
+    let (outcome_tx, outcome_rx) = mpsc::unbounded_channel();
+
+    self
+      .send
+      .send(OneShotTask::StartMutableItemLookup(
+        StartMutableItemLookup {
+          public_key: item.public_key,
+          salt: item.salt.clone(),
+          put: Some(item),
+          cas,
+          tx,
+          outcome_tx: Some(outcome_tx),
+        },
+      ))
+      .map_err(|_| io::Error::other("DhtHandler has shutdown."))?;
+
+    put_result(target, outcome_rx).await
+  }
+
+  /// Follow the torrent published in the mutable item of the given public key
+  /// and salt (BEP46).
+  ///
+  /// The item is polled at the given interval, and whenever a newer version
+  /// points to another info hash, a search for its peers is started. Once the
+  /// search is over, the next poll searches the peers of the info hash again.
+  /// The returned stream yields the found peers along with their info hash.
+  pub fn resolve(
+    &self,
+    public_key: [u8; PUBLIC_KEY_LEN],
+    salt: Option<Vec<u8>>,
+    poll_interval: Duration,
+  ) -> ResolveStream {
+    resolver::start(self.send.clone(), public_key, salt, poll_interval)
+  }
+
+  /// Crawl the DHT for the info hashes stored by the nodes (BEP51).
+  ///
+  /// The nodes are sampled in rounds starting from random places in the
+  /// keyspace, and a node is not sampled again before the interval it asked
+  /// for has elapsed. Every info hash is yielded once, unless it was forgotten
+  /// among the last 100000 ones. The crawl runs until the returned stream is
+  /// dropped.
+  pub fn crawl_infohashes(&self) -> CrawlStream {
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    if self.send.send(OneShotTask::StartCrawl(tx)).is_err() {
+      log::error!(
+        "[{}]failed to start crawl - DhtHandler has shut down",
+        self.name
+      );
+    }
+
+    CrawlStream(rx)
+  }
+
+  /// Our external address as reported by the other nodes (BEP42), if enough
+  /// of them agreed on it.
+  pub fn external_addr(&self) -> Option<SocketAddr> {
+    *self.external_addr.borrow()
+  }
+
+  /// Get notified whenever our estimated external address changes.
+  pub fn watch_external_addr(&self) -> watch::Receiver<Option<SocketAddr>> {
+    self.external_addr.clone()
+  }
+
+  /// Shut the DHT down. No command is accepted anymore and the running lookups are
+  /// aborted, then the shutdown hooks are called and the socket is closed.
+  /// The commands sent meanwhile, e.g. by a [`resolve`](Self::resolve) stream,
+  /// are answered if they only query the DHT, the lookups they ask for are
+  /// dropped.
+  ///
+  /// Resolves once the task running the DHT has exited.
+  pub async fn shutdown(self) {
+    self.stop(None).await
+  }
+
+  /// Same as [`shutdown`](Self::shutdown), except the lookups which announce us or
+  /// store an item, running or queued, are given up to `timeout` to complete.
+  pub async fn shutdown_after_announces(self, timeout: Duration) {
+    self.stop(Some(timeout)).await
+  }
+
+  async fn stop(self, timeout: Option<Duration>) {
+    // The handler may have stopped already, then there is only its task to wait for.
+    self.send.send(OneShotTask::Shutdown(timeout)).unwrap_or(());
+
+    if let Err(error) = self.dht_handler.await {
+      log::error!("[{}] DhtHandler failed: {}", self.name, error);
+    }
+  }
+
   /// Get the local address this DHT instance is bound to.
   pub async fn local_addr(&self) -> io::Result<SocketAddr> {
     let (tx, rx) = oneshot::channel();
 
     fn error() -> io::Error {
-      io::Error::new(io::ErrorKind::Other, "DhtHandler has shutdown.")
+      io::Error::other("DhtHandler has shutdown.")
     }
 
     self
@@ -172,7 +646,15 @@ impl MainlineDht {
 
 /// Stream returned from [`MainlineDht::search()`]
 #[must_use = "streams do nothing unless polled"]
-pub struct SearchStream(mpsc::UnboundedReceiver<SocketAddr>);
+pub struct SearchStream(pub(crate) mpsc::UnboundedReceiver<SocketAddr>);
+
+impl SearchStream {
+  /// Stop the search. The peers already found are still yielded, then the
+  /// stream ends.
+  pub fn cancel(&mut self) {
+    self.0.close()
+  }
+}
 
 impl Stream for SearchStream {
   type Item = SocketAddr;
@@ -185,16 +667,62 @@ impl Stream for SearchStream {
   }
 }
 
+/// Stream returned from [`MainlineDht::search_events()`]
+#[must_use = "streams do nothing unless polled"]
+pub struct SearchEventStream(mpsc::UnboundedReceiver<SearchEvent>);
+
+impl SearchEventStream {
+  /// Stop the search. The events already reported are still yielded, then
+  /// the stream ends.
+  pub fn cancel(&mut self) {
+    self.0.close()
+  }
+}
+
+impl Stream for SearchEventStream {
+  type Item = SearchEvent;
+
+  fn poll_next(
+    mut self: std::pin::Pin<&mut Self>,
+    cx: &mut std::task::Context<'_>,
+  ) -> std::task::Poll<Option<Self::Item>> {
+    Pin::new(&mut self.0).poll_recv(cx)
+  }
+}
+
+/// Stream returned from [`MainlineDht::crawl_infohashes()`]
+#[must_use = "streams do nothing unless polled"]
+pub struct CrawlStream(mpsc::UnboundedReceiver<InfoHash>);
+
+impl Stream for CrawlStream {
+  type Item = InfoHash;
+
+  fn poll_next(
+    mut self: std::pin::Pin<&mut Self>,
+    cx: &mut std::task::Context<'_>,
+  ) -> std::task::Poll<Option<Self::Item>> {
+    Pin::new(&mut self.0).poll_recv(cx)
+  }
+}
+
 // -------------------------- //
 
 /// Stores information for initializing a DHT.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct DhtBuilder {
   nodes: HashSet<SocketAddr>,
   routers: HashSet<String>,
   read_only: bool,
   announce_port: Option<u16>,
   node_id: Option<NodeId>,
+  external_ip: Option<IpAddr>,
+  enforce_node_id: bool,
+  client_version: Option<Vec<u8>>,
+  max_concurrent_lookups: usize,
+  max_queued_lookups: usize,
+  shutdown_hooks: Vec<ShutdownHook>,
+  saved_table: Option<SavedTable>,
+  table_file: Option<PathBuf>,
 }
 
 impl DhtBuilder {
@@ -243,13 +771,100 @@ impl DhtBuilder {
 
   /// Set the id of this node. If not provided, a random node id is generated.
   ///
-  /// NOTE: when creating a double-stack DHT (ipv4 + ipv6), it's recommended that
-  /// both DHTs use the same node id.
+  /// NOTE: when creating a double-stack DHT (ipv4 + ipv6) by hand, it's recommended
+  /// that both DHTs use the same node id. See [`start_dual_stack`](Self::start_dual_stack)
+  /// which takes care of it.
   pub fn set_node_id(mut self, id: NodeId) -> DhtBuilder {
     self.node_id = Some(id);
     self
   }
 
+  /// Set the external ip address of this node, which is used to generate a
+  /// node id conforming to the DHT security extension (BEP42).
+  ///
+  /// Ignored if the node id is set explicitly with `set_node_id`.
+  pub fn set_external_ip(mut self, ip: IpAddr) -> DhtBuilder {
+    self.external_ip = Some(ip);
+    self
+  }
+
+  /// Only add nodes to our routing table whose node id conforms to their ip
+  /// address as per the DHT security extension (BEP42).
+  ///
+  /// Nodes on local network addresses are always accepted. Defaults value is false.
+  pub fn set_enforce_node_id(mut self, enforce: bool) -> DhtBuilder {
+    self.enforce_node_id = enforce;
+    self
+  }
+
+  /// Set the client version string sent in the "v" key of every message,
+  /// two characters identifying the client followed by two bytes of version.
+  ///
+  /// If this is not supplied, no version is sent.
+  pub fn set_client_version(mut self, version: Vec<u8>) -> DhtBuilder {
+    self.client_version = Some(version);
+    self
+  }
+
+  /// Set how many lookups (searches, scrapes, item gets and puts) may run at
+  /// the same time, the others are queued until one completes. The searches
+  /// with [`SearchPriority::User`](crate::SearchPriority::User) start first.
+  ///
+  /// Defaults value is 32.
+  pub fn set_max_concurrent_lookups(mut self, max: usize) -> DhtBuilder {
+    self.max_concurrent_lookups = max.max(1);
+    self
+  }
+
+  /// Set how many lookups may wait for a running one to complete. Once as
+  /// many are queued, the new lookups are dropped and their streams end
+  /// without results. A search with
+  /// [`SearchPriority::User`](crate::SearchPriority::User) drops the newest
+  /// queued [`SearchPriority::Background`](crate::SearchPriority::Background)
+  /// one instead, if any.
+  ///
+  /// Defaults value is 1024.
+  pub fn set_max_queued_lookups(mut self, max: usize) -> DhtBuilder {
+    self.max_queued_lookups = max;
+    self
+  }
+
+  /// Add a function called with the routing table once the DHT stopped, to save its
+  /// state. See [`MainlineDht::shutdown`].
+  ///
+  /// The hooks run on a thread where blocking is fine, like writing a file.
+  ///
+  /// In a dual-stack DHT, it is called for the routing table of each instance.
+  pub fn add_shutdown_hook<F>(mut self, hook: F) -> DhtBuilder
+  where
+    F: Fn(&RoutingTable) + Send + Sync + 'static,
+  {
+    self.shutdown_hooks.push(ShutdownHook::new(hook));
+    self
+  }
+
+  /// Restore the node id and the nodes of a routing table saved by a previous run, see
+  /// [`SavedTable::from_table`]. The bootstrap pings the most recently seen of the
+  /// restored nodes instead of the routers, which are only contacted if they are gone.
+  ///
+  /// The restored node id is ignored if one is set with `set_node_id`. In a dual-stack
+  /// DHT, each instance restores the nodes of its address family.
+  pub fn restore_table(mut self, table: SavedTable) -> DhtBuilder {
+    self.saved_table = Some(table);
+    self
+  }
+
+  /// Restore the routing table from the given file when starting, if it exists, and
+  /// save it there once the DHT stopped. See [`restore_table`](Self::restore_table) and
+  /// [`MainlineDht::shutdown`].
+  ///
+  /// In a dual-stack DHT, the IPv6 instance uses its own file, named after the given
+  /// one with ".v6" appended.
+  pub fn set_table_file<P: Into<PathBuf>>(mut self, path: P) -> DhtBuilder {
+    self.table_file = Some(path.into());
+    self
+  }
+
   /// Start a mainline DHT with current configuration and bind it to the provided socket.
   /// Fails only if `socket.local_addr()` fails
   pub fn start<S: SocketTrait + Send + Sync + 'static>(
@@ -257,7 +872,172 @@ impl DhtBuilder {
     name: &str,
     socket: S,
   ) -> io::Result<MainlineDht> {
-    let socket = Socket::new(socket)?;
-    Ok(MainlineDht::with_builder(name.to_string(), self, socket))
+    let socket =
+      Socket::new(socket, self.read_only, self.client_version.clone())?;
+    let builder = self.with_table_file();
+    Ok(MainlineDht::with_builder(name.to_string(), builder, socket))
   }
+
+  /// Start a dual-stack DHT with current configuration, bound to the provided IPv4 and
+  /// IPv6 sockets.
+  ///
+  /// Both instances use the same node id, which is never regenerated for our external
+  /// address since it is shared. Fails if `local_addr()` fails or if the sockets are not
+  /// bound to an IPv4 and an IPv6 address respectively.
+  pub fn start_dual_stack<S4, S6>(
+    self,
+    name: &str,
+    socket_v4: S4,
+    socket_v6: S6,
+  ) -> io::Result<DualStackDht>
+  where
+    S4: SocketTrait + Send + Sync + 'static,
+    S6: SocketTrait + Send + Sync + 'static,
+  {
+    let mut socket_v4 =
+      Socket::new(socket_v4, self.read_only, self.client_version.clone())?;
+    let mut socket_v6 =
+      Socket::new(socket_v6, self.read_only, self.client_version.clone())?;
+
+    if socket_v4.ip_version() != IpVersion::V4
+      || socket_v6.ip_version() != IpVersion::V6
+    {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "expected an IPv4 and an IPv6 socket",
+      ));
+    }
+
+    socket_v4.set_dual_stack();
+    socket_v6.set_dual_stack();
+
+    // Each instance bootstraps against the nodes of its address family, and
+    // saves its routing table in its own file.
+    let (mut v4_builder, mut v6_builder) = (self.clone(), self);
+    v4_builder.nodes.retain(|addr| addr.is_ipv4());
+    v6_builder.nodes.retain(|addr| addr.is_ipv6());
+    v6_builder.table_file = v6_builder.table_file.map(v6_table_file);
+    let v4_builder = v4_builder.with_table_file();
+    let v6_builder = v6_builder.with_table_file();
+
+    let node_id = v4_builder.node_id_for(&socket_v4);
+    let v4_builder = v4_builder.set_node_id(node_id);
+    let v6_builder = v6_builder.set_node_id(node_id);
+
+    let (v4_tx, v4_rx) = mpsc::unbounded_channel();
+    let (v6_tx, v6_rx) = mpsc::unbounded_channel();
+    let (v4_sibling, v6_sibling) = Sibling::pair(&v4_tx, &v6_tx);
+
+    let v4 = MainlineDht::with_channel(
+      name.to_string(),
+      v4_builder,
+      socket_v4,
+      v4_tx,
+      v4_rx,
+      Some(v4_sibling),
+    );
+    let v6 = MainlineDht::with_channel(
+      name.to_string(),
+      v6_builder,
+      socket_v6,
+      v6_tx,
+      v6_rx,
+      Some(v6_sibling),
+    );
+
+    Ok(DualStackDht::new(node_id, v4, v6))
+  }
+
+  /// Restore the routing table saved in the table file, if any, and save it
+  /// there on shutdown.
+  fn with_table_file(mut self) -> DhtBuilder {
+    let path = match self.table_file.take() {
+      Some(path) => path,
+      None => return self,
+    };
+
+    if self.saved_table.is_none() {
+      match SavedTable::load(&path) {
+        Ok(table) => self.saved_table = Some(table),
+        // Nothing was saved yet.
+        Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+        Err(error) => log::warn!(
+          "Failed to restore the routing table from {}: {}",
+          path.display(),
+          error
+        ),
+      }
+    }
+
+    self.add_shutdown_hook(move |table| {
+      if let Err(error) = SavedTable::from_table(table).save(&path) {
+        log::warn!(
+          "Failed to save the routing table to {}: {}",
+          path.display(),
+          error
+        );
+      }
+    })
+  }
+
+  /// The node id to start with on the given socket.
+  fn node_id_for(&self, socket: &Socket) -> NodeId {
+    // Keep the node id of the restored routing table, so that the other nodes
+    // still find us where they knew us.
+    let node_id = self
+      .node_id
+      .or_else(|| self.saved_table.as_ref().map(|table| table.id));
+
+    // Derive our node id from the external ip when we know it (BEP42), the
+    // local address is only usable if it is not a local network address.
+    node_id.unwrap_or_else(|| {
+      let local_ip =
+        Some(socket.local_addr().ip()).filter(|ip| !security::is_exempt(*ip));
+
+      match self.external_ip.or(local_ip) {
+        Some(ip) => security::generate_secure_id(ip),
+        None => rand::random(),
+      }
+    })
+  }
+}
+
+/// Wait for the answers of the closest nodes to a put (BEP44), which
+/// succeeded if any of them stored the item.
+async fn put_result(
+  target: InfoHash,
+  mut outcome_rx: mpsc::UnboundedReceiver<AnnounceResult>,
+) -> io::Result<InfoHash> {
+  let mut stored = false;
+  let mut rejection = None;
+
+  // The channel is closed once the lookup finished and the nodes answered.
+  while let Some(result) = outcome_rx.recv().await {
+    match result.outcome {
+      AnnounceOutcome::Accepted => stored = true,
+      AnnounceOutcome::Rejected { code, message } => {
+        rejection = Some((code, message))
+      }
+      AnnounceOutcome::TimedOut => (),
+    }
+  }
+
+  match rejection {
+    _ if stored => Ok(target),
+    Some((code, message)) => Err(io::Error::other(format!(
+      "no node stored the item: {} {}",
+      code, message
+    ))),
+    None => Err(io::Error::new(
+      io::ErrorKind::TimedOut,
+      "no node stored the item",
+    )),
+  }
+}
+
+/// The file the IPv6 instance of a dual-stack DHT saves its routing table in.
+fn v6_table_file(path: PathBuf) -> PathBuf {
+  let mut path = path.into_os_string();
+  path.push(".v6");
+  path.into()
 }
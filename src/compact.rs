@@ -1,7 +1,7 @@
 use std::convert::TryInto;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 
-use crate::id::NODE_ID_LEN;
+use crate::id::{INFO_HASH_LEN, NODE_ID_LEN};
 
 const SOCKET_ADDR_V4_LEN: usize = 6;
 const SOCKET_ADDR_V6_LEN: usize = 18;
@@ -59,6 +59,64 @@ pub mod values {
   }
 }
 
+/// Serialize/deserialize a single optional `SocketAddr` in compact format.
+pub mod addr {
+  use std::net::SocketAddr;
+
+  use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+  use serde_bytes::{ByteBuf, Bytes};
+
+  pub fn serialize<S>(
+    addr: &Option<SocketAddr>,
+    s: S,
+  ) -> Result<S::Ok, S::Error>
+  where
+    S: Serializer,
+  {
+    match addr {
+      Some(addr) => {
+        s.serialize_bytes(Bytes::new(&super::encode_socket_addr(addr)))
+      }
+      None => s.serialize_none(),
+    }
+  }
+
+  pub fn deserialize<'de, D>(d: D) -> Result<Option<SocketAddr>, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let bytes = ByteBuf::deserialize(d)?;
+    let addr = super::decode_socket_addr(&bytes)
+      .ok_or_else(|| D::Error::invalid_length(bytes.len(), &"6 or 18 bytes"))?;
+
+    Ok(Some(addr))
+  }
+}
+
+/// Serialize/deserialize a single `SocketAddr` in compact format.
+pub mod socket_addr {
+  use std::net::SocketAddr;
+
+  use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+  use serde_bytes::{ByteBuf, Bytes};
+
+  pub fn serialize<S>(addr: &SocketAddr, s: S) -> Result<S::Ok, S::Error>
+  where
+    S: Serializer,
+  {
+    s.serialize_bytes(Bytes::new(&super::encode_socket_addr(addr)))
+  }
+
+  pub fn deserialize<'de, D>(d: D) -> Result<SocketAddr, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let bytes = ByteBuf::deserialize(d)?;
+    super::decode_socket_addr(&bytes)
+      .ok_or_else(|| D::Error::invalid_length(bytes.len(), &"6 or 18 bytes"))
+  }
+}
+
 fn decode_socket_addr(src: &[u8]) -> Option<SocketAddr> {
   if src.len() == SOCKET_ADDR_V4_LEN {
     let addr: [u8; 4] = src.get(..4)?.try_into().ok()?;
@@ -196,9 +254,79 @@ pub mod nodes_v6 {
   }
 }
 
+/// Serialize/deserialize `Vec` of `InfoHash` as concatenated byte string.
+pub mod info_hashes {
+  use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+  use serde_bytes::ByteBuf;
+
+  use crate::id::InfoHash;
+
+  pub fn serialize<S>(info_hashes: &[InfoHash], s: S) -> Result<S::Ok, S::Error>
+  where
+    S: Serializer,
+  {
+    let buffer: Vec<u8> = info_hashes
+      .iter()
+      .flat_map(|info_hash| info_hash.as_ref().iter().copied())
+      .collect();
+
+    s.serialize_bytes(&buffer)
+  }
+
+  pub fn deserialize<'de, D>(d: D) -> Result<Vec<InfoHash>, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    let buffer = ByteBuf::deserialize(d)?;
+    let chunks = buffer.chunks_exact(super::INFO_HASH_LEN);
+
+    if !chunks.remainder().is_empty() {
+      let msg = format!("multiple of {}", super::INFO_HASH_LEN);
+      return Err(D::Error::invalid_length(buffer.len(), &msg.as_ref()));
+    }
+
+    // `unwrap` is OK because the chunks have the exact length.
+    Ok(
+      chunks
+        .map(|chunk| InfoHash::try_from(chunk).unwrap())
+        .collect(),
+    )
+  }
+
+  /// Same as the parent module, for an optional field.
+  pub mod option {
+    use serde::{Deserializer, Serializer};
+
+    use crate::id::InfoHash;
+
+    pub fn serialize<S>(
+      info_hashes: &Option<Vec<InfoHash>>,
+      s: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+      S: Serializer,
+    {
+      match info_hashes {
+        Some(info_hashes) => super::serialize(info_hashes, s),
+        None => s.serialize_none(),
+      }
+    }
+
+    pub fn deserialize<'de, D>(d: D) -> Result<Option<Vec<InfoHash>>, D::Error>
+    where
+      D: Deserializer<'de>,
+    {
+      super::deserialize(d).map(Some)
+    }
+  }
+}
+
 #[cfg(test)]
 mod tests {
-  use crate::{id::NodeId, routing::node::NodeHandle};
+  use crate::{
+    id::{InfoHash, NodeId},
+    routing::node::NodeHandle,
+  };
   use pretty_assertions::assert_eq;
   use serde::{Deserialize, Serialize};
   use std::{
@@ -301,6 +429,40 @@ mod tests {
     );
   }
 
+  #[test]
+  fn encode_decode_addr() {
+    #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+    #[serde(transparent)]
+    struct Wrapper {
+      #[serde(with = "super::addr")]
+      addr: Option<SocketAddr>,
+    }
+
+    encode_decode(
+      &Wrapper {
+        addr: Some((Ipv4Addr::new(127, 0, 0, 1), 6789).into()),
+      },
+      &[b'6', b':', 127, 0, 0, 1, 26, 133],
+    );
+    encode_decode(
+      &Wrapper {
+        addr: Some(
+          (
+            Ipv6Addr::new(
+              0x2001, 0x0db8, 0x85a3, 0x0000, 0x0000, 0x8a2e, 0x0370, 0x7334,
+            ),
+            1234,
+          )
+            .into(),
+        ),
+      },
+      &[
+        b'1', b'8', b':', 0x20, 0x01, 0x0d, 0xb8, 0x85, 0xa3, 0x00, 0x00, 0x00,
+        0x00, 0x8a, 0x2e, 0x03, 0x70, 0x73, 0x34, 4, 210,
+      ],
+    );
+  }
+
   #[test]
   fn encode_decode_nodes_v4() {
     #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -379,6 +541,34 @@ mod tests {
     );
   }
 
+  #[test]
+  fn encode_decode_info_hashes() {
+    #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+    #[serde(transparent)]
+    struct Wrapper {
+      #[serde(with = "super::info_hashes")]
+      info_hashes: Vec<InfoHash>,
+    }
+
+    // empty
+    encode_decode(
+      &Wrapper {
+        info_hashes: Vec::new(),
+      },
+      b"0:",
+    );
+    // two
+    encode_decode(
+      &Wrapper {
+        info_hashes: vec![
+          InfoHash::from(*b"0123456789abcdefghij"),
+          InfoHash::from(*b"klmnopqrstuvwxyz0123"),
+        ],
+      },
+      b"40:0123456789abcdefghijklmnopqrstuvwxyz0123",
+    );
+  }
+
   #[test]
   fn attempt_to_encode_v4_nodes_as_v6() {
     #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -0,0 +1,186 @@
+//! A DHT running on both IPv4 and IPv6 (BEP32).
+
+use std::{
+  collections::HashSet,
+  io,
+  net::SocketAddr,
+  pin::Pin,
+  task::{Context, Poll},
+  time::Duration,
+};
+
+use futures_util::{Stream, StreamExt};
+
+use crate::{
+  builder::{MainlineDht, SearchStream},
+  id::{InfoHash, NodeId},
+  worker::{AnnounceResult, SearchOptions},
+};
+
+/// Maintains a DHT on both IPv4 and IPv6, started with
+/// [`DhtBuilder::start_dual_stack`](crate::DhtBuilder::start_dual_stack).
+///
+/// It owns one [`MainlineDht`] per address family, sharing the same node id. The nodes
+/// of the other address family one instance learns are added to the routing table of
+/// the other, and the requests asking for the contacts of both families are answered
+/// with the nodes of both routing tables.
+///
+/// The searches and announces run on both instances. Anything else can be done on the
+/// instances themselves, see [`v4`](Self::v4) and [`v6`](Self::v6).
+pub struct DualStackDht {
+  node_id: NodeId,
+  v4: MainlineDht,
+  v6: MainlineDht,
+}
+
+impl DualStackDht {
+  pub(crate) fn new(node_id: NodeId, v4: MainlineDht, v6: MainlineDht) -> Self {
+    DualStackDht { node_id, v4, v6 }
+  }
+
+  /// The node id shared by both instances.
+  pub fn node_id(&self) -> NodeId {
+    self.node_id
+  }
+
+  /// The instance bound to the IPv4 socket.
+  pub fn v4(&self) -> &MainlineDht {
+    &self.v4
+  }
+
+  /// The instance bound to the IPv6 socket.
+  pub fn v6(&self) -> &MainlineDht {
+    &self.v6
+  }
+
+  /// Waits the bootstrap of both instances completes, see
+  /// [`MainlineDht::bootstrapped`]. Returns whether at least one of them was
+  /// successful, the host may well have no IPv6 connectivity.
+  pub async fn bootstrapped(&self, timeout: Option<Duration>) -> bool {
+    let (v4, v6) = tokio::join!(
+      self.v4.bootstrapped(timeout),
+      self.v6.bootstrapped(timeout)
+    );
+    v4 || v6
+  }
+
+  /// Perform a search for the given InfoHash on both instances, see
+  /// [`MainlineDht::search`]. A peer found by both is yielded once.
+  pub fn search(
+    &self,
+    info_hash: InfoHash,
+    announce: bool,
+  ) -> DualStackSearchStream {
+    self.search_with(info_hash, SearchOptions::new().set_announce(announce))
+  }
+
+  /// Perform a search for the given InfoHash with the given options on both
+  /// instances, see [`MainlineDht::search_with`].
+  pub fn search_with(
+    &self,
+    info_hash: InfoHash,
+    options: SearchOptions,
+  ) -> DualStackSearchStream {
+    DualStackSearchStream {
+      v4: self.v4.search_with(info_hash, options.clone()),
+      v6: self.v6.search_with(info_hash, options),
+      seen: HashSet::new(),
+    }
+  }
+
+  /// Announce ourselves for the given InfoHash on the closest nodes of both
+  /// address families, see [`MainlineDht::announce`].
+  pub async fn announce(
+    &self,
+    info_hash: InfoHash,
+  ) -> io::Result<Vec<AnnounceResult>> {
+    let (v4, v6) =
+      tokio::join!(self.v4.announce(info_hash), self.v6.announce(info_hash));
+
+    let mut results = v4?;
+    results.extend(v6?);
+    Ok(results)
+  }
+
+  /// Announce ourselves for the given InfoHash periodically on both instances,
+  /// see [`MainlineDht::start_announcing`].
+  pub fn start_announcing(
+    &self,
+    info_hash: InfoHash,
+    port: u16,
+  ) -> io::Result<()> {
+    self.v4.start_announcing(info_hash, port)?;
+    self.v6.start_announcing(info_hash, port)
+  }
+
+  /// Stop announcing ourselves for the given InfoHash on both instances.
+  pub fn stop_announcing(&self, info_hash: InfoHash) -> io::Result<()> {
+    self.v4.stop_announcing(info_hash)?;
+    self.v6.stop_announcing(info_hash)
+  }
+
+  /// Shut both instances down, see [`MainlineDht::shutdown`].
+  pub async fn shutdown(self) {
+    tokio::join!(self.v4.shutdown(), self.v6.shutdown());
+  }
+
+  /// Shut both instances down once their announces completed, see
+  /// [`MainlineDht::shutdown_after_announces`].
+  pub async fn shutdown_after_announces(self, timeout: Duration) {
+    tokio::join!(
+      self.v4.shutdown_after_announces(timeout),
+      self.v6.shutdown_after_announces(timeout)
+    );
+  }
+}
+
+/// Stream returned from [`DualStackDht::search()`]
+#[must_use = "streams do nothing unless polled"]
+pub struct DualStackSearchStream {
+  v4: SearchStream,
+  v6: SearchStream,
+  seen: HashSet<SocketAddr>,
+}
+
+impl DualStackSearchStream {
+  /// Stop the search on both instances. The peers already found are still
+  /// yielded, then the stream ends.
+  pub fn cancel(&mut self) {
+    self.v4.cancel();
+    self.v6.cancel();
+  }
+}
+
+impl Stream for DualStackSearchStream {
+  type Item = SocketAddr;
+
+  fn poll_next(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Self::Item>> {
+    let this = &mut *self;
+    let mut finished = 0;
+
+    for stream in [&mut this.v4, &mut this.v6] {
+      while let Poll::Ready(peer) = stream.poll_next_unpin(cx) {
+        match peer {
+          Some(peer) if this.seen.insert(peer) => {
+            return Poll::Ready(Some(peer))
+          }
+          // Already found by the other instance.
+          Some(_) => (),
+          None => {
+            finished += 1;
+            break;
+          }
+        }
+      }
+    }
+
+    if finished == 2 {
+      Poll::Ready(None)
+    } else {
+      Poll::Pending
+    }
+  }
+}
@@ -0,0 +1,229 @@
+//! Discovering our external address through the "ip" key which the nodes
+//! include in their responses (BEP42).
+//!
+//! Every response we receive is a vote for the address the responding node
+//! saw us on. A single node may only vote once in a while, and old votes are
+//! decayed so that we can follow an external address change (e.g. a new lease
+//! from the ISP).
+//!
+//! The votes are counted per ip only: a NAT may map us to another port for
+//! every node, which would split the votes for the same ip.
+
+use std::{
+  collections::{HashMap, VecDeque},
+  net::{IpAddr, SocketAddr},
+};
+
+/// Number of recent voters remembered, a node in this window can not vote again.
+const MAX_RECENT_VOTERS: usize = 64;
+
+/// When the total votes reach this number, all of the votes are halved.
+const DECAY_THRESHOLD: u32 = 100;
+
+/// Minimum votes an address needs before we consider it our external address.
+const MIN_VOTES: u32 = 3;
+
+/// Collects the votes of the remote nodes for our external address.
+pub struct ExternalAddrVoter {
+  votes: HashMap<IpAddr, u32>,
+  // The port last seen along with each ip.
+  ports: HashMap<IpAddr, u16>,
+  total_votes: u32,
+  recent_voters: VecDeque<IpAddr>,
+  current: Option<IpAddr>,
+}
+
+impl ExternalAddrVoter {
+  pub fn new() -> ExternalAddrVoter {
+    ExternalAddrVoter {
+      votes: HashMap::new(),
+      ports: HashMap::new(),
+      total_votes: 0,
+      recent_voters: VecDeque::with_capacity(MAX_RECENT_VOTERS),
+      current: None,
+    }
+  }
+
+  /// The current estimation of our external address. Its port is the last
+  /// one seen along with the ip, which is all that is voted on.
+  pub fn external_addr(&self) -> Option<SocketAddr> {
+    let ip = self.current?;
+    Some(SocketAddr::new(
+      ip,
+      self.ports.get(&ip).copied().unwrap_or(0),
+    ))
+  }
+
+  /// Record that the `voter` has seen us on `addr`.
+  ///
+  /// Returns true if the ip of the estimated external address changed.
+  pub fn add_vote(
+    &mut self,
+    voter: IpAddr,
+    addr: SocketAddr,
+    weight: u32,
+  ) -> bool {
+    if self.recent_voters.contains(&voter) {
+      return false;
+    }
+
+    if self.recent_voters.len() == MAX_RECENT_VOTERS {
+      self.recent_voters.pop_front();
+    }
+    self.recent_voters.push_back(voter);
+
+    *self.votes.entry(addr.ip()).or_insert(0) += weight;
+    self.ports.insert(addr.ip(), addr.port());
+    self.total_votes += weight;
+
+    if self.total_votes >= DECAY_THRESHOLD {
+      self.decay_votes();
+    }
+
+    self.update_estimation()
+  }
+
+  /// Halve all the votes, forgetting the addresses without votes left.
+  fn decay_votes(&mut self) {
+    self.votes.retain(|_, votes| {
+      *votes /= 2;
+      *votes > 0
+    });
+    let votes = &self.votes;
+    self.ports.retain(|ip, _| votes.contains_key(ip));
+    self.total_votes = self.votes.values().sum();
+  }
+
+  /// Switch to the ip with the most votes, if it has strictly more votes than
+  /// the current one.
+  fn update_estimation(&mut self) -> bool {
+    let current_votes = self
+      .current
+      .and_then(|ip| self.votes.get(&ip))
+      .copied()
+      .unwrap_or(0);
+
+    let best = self
+      .votes
+      .iter()
+      .max_by_key(|(_, votes)| **votes)
+      .map(|(ip, votes)| (*ip, *votes));
+
+    match best {
+      Some((ip, votes))
+        if votes >= MIN_VOTES
+          && votes > current_votes
+          && Some(ip) != self.current =>
+      {
+        self.current = Some(ip);
+        true
+      }
+      _ => false,
+    }
+  }
+}
+
+impl Default for ExternalAddrVoter {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+  use crate::external_addr::{self, ExternalAddrVoter};
+  use pretty_assertions::assert_eq;
+
+  fn voter(index: u8) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(10, 0, 0, index))
+  }
+
+  fn addr(index: u8) -> SocketAddr {
+    (Ipv4Addr::new(1, 2, 3, index), 6881).into()
+  }
+
+  #[test]
+  fn positive_estimation_after_min_votes() {
+    let mut voter_table = ExternalAddrVoter::new();
+
+    for index in 0..(external_addr::MIN_VOTES as u8 - 1) {
+      assert!(!voter_table.add_vote(voter(index), addr(1), 1));
+    }
+    assert_eq!(voter_table.external_addr(), None);
+
+    assert!(voter_table.add_vote(voter(100), addr(1), 1));
+    assert_eq!(voter_table.external_addr(), Some(addr(1)));
+  }
+
+  #[test]
+  fn negative_same_voter_counted_once() {
+    let mut voter_table = ExternalAddrVoter::new();
+
+    for _ in 0..external_addr::MIN_VOTES {
+      assert!(!voter_table.add_vote(voter(0), addr(1), 1));
+    }
+
+    assert_eq!(voter_table.external_addr(), None);
+  }
+
+  #[test]
+  fn positive_weighted_votes() {
+    let mut voter_table = ExternalAddrVoter::new();
+
+    assert!(voter_table.add_vote(voter(0), addr(1), external_addr::MIN_VOTES));
+    assert_eq!(voter_table.external_addr(), Some(addr(1)));
+  }
+
+  #[test]
+  fn positive_switch_to_new_address() {
+    let mut voter_table = ExternalAddrVoter::new();
+
+    for index in 0..10 {
+      voter_table.add_vote(voter(index), addr(1), 1);
+    }
+    assert_eq!(voter_table.external_addr(), Some(addr(1)));
+
+    // The new address needs to beat the current one.
+    for index in 10..20 {
+      voter_table.add_vote(voter(index), addr(2), 1);
+    }
+    assert_eq!(voter_table.external_addr(), Some(addr(1)));
+
+    assert!(voter_table.add_vote(voter(20), addr(2), 1));
+    assert_eq!(voter_table.external_addr(), Some(addr(2)));
+  }
+
+  #[test]
+  fn positive_votes_counted_per_ip() {
+    let mut voter_table = ExternalAddrVoter::new();
+
+    // A NAT maps us to another port for every node.
+    for index in 0..external_addr::MIN_VOTES as u8 {
+      let addr = SocketAddr::new(addr(1).ip(), 6881 + u16::from(index));
+      voter_table.add_vote(voter(index), addr, 1);
+    }
+
+    let expected = SocketAddr::new(addr(1).ip(), 6881 + 2);
+    assert_eq!(voter_table.external_addr(), Some(expected));
+  }
+
+  #[test]
+  fn positive_decay_votes() {
+    let mut voter_table = ExternalAddrVoter::new();
+
+    for index in 0..(external_addr::DECAY_THRESHOLD - 1) {
+      voter_table.add_vote(voter(index as u8), addr(1), 1);
+    }
+    assert_eq!(voter_table.total_votes, external_addr::DECAY_THRESHOLD - 1);
+
+    // The single vote for the second address is decayed away.
+    voter_table.add_vote(voter(200), addr(2), 1);
+    assert_eq!(
+      voter_table.total_votes,
+      (external_addr::DECAY_THRESHOLD - 1) / 2
+    );
+    assert_eq!(voter_table.votes.len(), 1);
+  }
+}
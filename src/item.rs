@@ -0,0 +1,316 @@
+//! Storing arbitrary data in the DHT.
+//!
+//! Reference link:
+//!   http://bittorrent.org/beps/bep_0044.html
+//!
+//! An immutable item is stored under the SHA-1 hash of its bencoded value,
+//! so anyone retrieving it can verify the value matches the target.
+//!
+//! A mutable item is stored under the SHA-1 hash of the ed25519 public key of
+//! its owner (and an optional salt). Only the owner can sign a new version of
+//! the item, which must carry a higher sequence number than the previous one.
+
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+use thiserror::Error;
+
+use crate::{id::InfoHash, message::Value};
+
+pub use ed25519_dalek::SigningKey;
+
+/// Maximum length of the bencoded value of an item.
+pub const MAX_VALUE_LEN: usize = 1000;
+
+/// Maximum length of the salt of a mutable item.
+pub const MAX_SALT_LEN: usize = 64;
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum ItemError {
+  #[error("item value is larger than {} bytes", MAX_VALUE_LEN)]
+  ValueTooBig,
+  #[error("item salt is larger than {} bytes", MAX_SALT_LEN)]
+  SaltTooBig,
+  #[error("invalid item signature")]
+  InvalidSignature,
+  #[error("the item has been modified since the cas was read")]
+  CasMismatch,
+  #[error("sequence number is less than the current one")]
+  SequenceTooLow,
+  #[error("invalid bencode data")]
+  InvalidBencode(#[source] serde_bencode::Error),
+}
+
+/// Compute the target an immutable item is stored under.
+pub fn immutable_target(value: &Value) -> Result<InfoHash, ItemError> {
+  let encoded = encode_value(value)?;
+
+  Ok(InfoHash::sha1(&encoded))
+}
+
+/// Compute the target a mutable item is stored under.
+pub fn mutable_target(
+  public_key: &[u8; PUBLIC_KEY_LEN],
+  salt: Option<&[u8]>,
+) -> InfoHash {
+  let mut bytes = public_key.to_vec();
+  bytes.extend_from_slice(salt.unwrap_or_default());
+
+  InfoHash::sha1(&bytes)
+}
+
+/// A data item as stored by the nodes.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Item {
+  Immutable(Value),
+  Mutable(MutableItem),
+}
+
+/// A data item signed by its owner, which can be updated by publishing it
+/// again with a higher sequence number.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct MutableItem {
+  pub value: Value,
+  pub public_key: [u8; PUBLIC_KEY_LEN],
+  pub signature: [u8; SIGNATURE_LEN],
+  pub seq: i64,
+  pub salt: Option<Vec<u8>>,
+}
+
+impl MutableItem {
+  /// Create a new version of a mutable item, signed with the key of its owner.
+  pub fn sign(
+    value: Value,
+    signing_key: &SigningKey,
+    seq: i64,
+    salt: Option<Vec<u8>>,
+  ) -> Result<MutableItem, ItemError> {
+    let payload = signature_payload(&value, seq, salt.as_deref())?;
+    let signature = signing_key.sign(&payload);
+
+    Ok(MutableItem {
+      value,
+      public_key: signing_key.verifying_key().to_bytes(),
+      signature: signature.to_bytes(),
+      seq,
+      salt,
+    })
+  }
+
+  /// Build the item from the fields of a message, checking its signature.
+  pub fn from_parts(
+    value: Value,
+    public_key: &[u8],
+    signature: &[u8],
+    seq: i64,
+    salt: Option<Vec<u8>>,
+  ) -> Result<MutableItem, ItemError> {
+    let public_key = public_key
+      .try_into()
+      .map_err(|_| ItemError::InvalidSignature)?;
+    let signature = signature
+      .try_into()
+      .map_err(|_| ItemError::InvalidSignature)?;
+
+    let item = MutableItem {
+      value,
+      public_key,
+      signature,
+      seq,
+      salt,
+    };
+    item.verify()?;
+
+    Ok(item)
+  }
+
+  /// The target this item is stored under.
+  pub fn target(&self) -> InfoHash {
+    mutable_target(&self.public_key, self.salt.as_deref())
+  }
+
+  /// Check the size limits and the signature of the item.
+  pub fn verify(&self) -> Result<(), ItemError> {
+    if self.salt.as_ref().map_or(0, Vec::len) > MAX_SALT_LEN {
+      return Err(ItemError::SaltTooBig);
+    }
+
+    let payload =
+      signature_payload(&self.value, self.seq, self.salt.as_deref())?;
+    let public_key = VerifyingKey::from_bytes(&self.public_key)
+      .map_err(|_| ItemError::InvalidSignature)?;
+
+    public_key
+      .verify(&payload, &Signature::from_bytes(&self.signature))
+      .map_err(|_| ItemError::InvalidSignature)
+  }
+}
+
+/// The signed part of a mutable item, the bencoded salt, seq and v keys
+/// without the surrounding dictionary.
+fn signature_payload(
+  value: &Value,
+  seq: i64,
+  salt: Option<&[u8]>,
+) -> Result<Vec<u8>, ItemError> {
+  let mut payload = Vec::new();
+
+  if let Some(salt) = salt.filter(|salt| !salt.is_empty()) {
+    payload.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+    payload.extend_from_slice(salt);
+  }
+  payload.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+  payload.extend_from_slice(&encode_value(value)?);
+
+  Ok(payload)
+}
+
+/// Bencode the value, making sure it fits in an item.
+fn encode_value(value: &Value) -> Result<Vec<u8>, ItemError> {
+  let encoded =
+    serde_bencode::to_bytes(value).map_err(ItemError::InvalidBencode)?;
+
+  if encoded.len() > MAX_VALUE_LEN {
+    return Err(ItemError::ValueTooBig);
+  }
+
+  Ok(encoded)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::id::InfoHash;
+  use crate::item::{self, ItemError, MutableItem, SigningKey};
+  use crate::message::Value;
+  use pretty_assertions::assert_eq;
+
+  const PUBLIC_KEY: &str =
+    "77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548";
+
+  fn hello_world() -> Value {
+    Value::Bytes(b"Hello World!".to_vec())
+  }
+
+  // Mutable item test vectors from the BEP.
+  fn bep_item(salt: Option<&[u8]>, signature: &str) -> MutableItem {
+    MutableItem {
+      value: hello_world(),
+      public_key: hex::decode(PUBLIC_KEY).unwrap().try_into().unwrap(),
+      signature: hex::decode(signature).unwrap().try_into().unwrap(),
+      seq: 1,
+      salt: salt.map(|salt| salt.to_vec()),
+    }
+  }
+
+  #[test]
+  fn positive_immutable_target_bep_vector() {
+    let value = hello_world();
+
+    assert_eq!(
+      item::immutable_target(&value).unwrap(),
+      InfoHash::sha1(b"12:Hello World!")
+    );
+    assert_eq!(
+      format!("{:x}", item::immutable_target(&value).unwrap()),
+      "e5f96f6f38320f0f33959cb4d3d656452117aadb"
+    );
+  }
+
+  #[test]
+  fn positive_immutable_target_sorted_dict() {
+    let value = Value::Dict(
+      [
+        (b"b".to_vec(), Value::Int(2)),
+        (b"a".to_vec(), Value::Int(1)),
+      ]
+      .into_iter()
+      .collect(),
+    );
+
+    assert_eq!(
+      item::immutable_target(&value).unwrap(),
+      InfoHash::sha1(b"d1:ai1e1:bi2ee")
+    );
+  }
+
+  #[test]
+  fn positive_immutable_target_binary_dict_key() {
+    let value = Value::Dict(
+      [(b"\xff\x00".to_vec(), Value::Int(1))]
+        .into_iter()
+        .collect(),
+    );
+
+    assert_eq!(
+      item::immutable_target(&value).unwrap(),
+      InfoHash::sha1(b"d2:\xff\x00i1ee")
+    );
+  }
+
+  #[test]
+  fn negative_value_too_big() {
+    let value = Value::Bytes(vec![0u8; item::MAX_VALUE_LEN]);
+
+    assert!(matches!(
+      item::immutable_target(&value),
+      Err(ItemError::ValueTooBig)
+    ));
+  }
+
+  #[test]
+  fn positive_mutable_bep_vector() {
+    let item = bep_item(None, "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01");
+
+    item.verify().unwrap();
+    assert_eq!(
+      format!("{:x}", item.target()),
+      "4a533d47ec9c7d95b1ad75f576cffc641853b750"
+    );
+  }
+
+  #[test]
+  fn positive_mutable_with_salt_bep_vector() {
+    let item = bep_item(Some(b"foobar"), "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17ddf9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08");
+
+    item.verify().unwrap();
+    assert_eq!(
+      format!("{:x}", item.target()),
+      "411eba73b6f087ca51a3795d9c8c938d365e32c1"
+    );
+  }
+
+  #[test]
+  fn positive_sign_and_verify() {
+    let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+    let item =
+      MutableItem::sign(hello_world(), &signing_key, 5, Some(b"salt".to_vec()))
+        .unwrap();
+
+    item.verify().unwrap();
+    assert_eq!(
+      item.target(),
+      item::mutable_target(&item.public_key, Some(b"salt"))
+    );
+  }
+
+  #[test]
+  fn negative_modified_item() {
+    let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+    let mut item =
+      MutableItem::sign(hello_world(), &signing_key, 5, None).unwrap();
+    item.seq = 6;
+
+    assert!(matches!(item.verify(), Err(ItemError::InvalidSignature)));
+  }
+
+  #[test]
+  fn negative_salt_too_big() {
+    let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+    let salt = vec![0u8; item::MAX_SALT_LEN + 1];
+    let item =
+      MutableItem::sign(hello_world(), &signing_key, 5, Some(salt)).unwrap();
+
+    assert!(matches!(item.verify(), Err(ItemError::SaltTooBig)));
+  }
+}
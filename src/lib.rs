@@ -4,23 +4,34 @@
 //! other nodes in the DHT to get the location of peers to download from
 //! using the BitTorrent protocol.
 
+pub mod bloom;
 pub mod compact;
+pub mod external_addr;
 pub mod id;
+pub mod item;
 pub mod message;
+pub mod resolver;
 pub mod router;
 pub mod routing;
+pub mod security;
 pub mod storage;
 pub mod token;
 pub mod transaction;
 
 pub mod builder;
+pub mod dual_stack;
 pub mod worker;
 
 pub mod test;
 
 pub use crate::builder::{DhtBuilder, MainlineDht};
+pub use crate::dual_stack::{DualStackDht, DualStackSearchStream};
 pub use crate::id::{InfoHash, LengthError, NodeId, INFO_HASH_LEN};
-pub use crate::worker::State;
+pub use crate::routing::persist::{SavedNode, SavedTable};
+pub use crate::worker::{
+  AnnounceOutcome, AnnounceResult, AnnounceStatus, RpcError, Scrape,
+  SearchEvent, SearchOptions, SearchPriority, SearchStats, ShutdownHook, State,
+};
 
 pub type IpVersion = crate::worker::IpVersion;
 
@@ -8,7 +8,7 @@ use serde::{
 
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct Error {
-  pub code: u8,
+  pub code: u16,
   pub message: String,
 }
 
@@ -42,7 +42,7 @@ impl<'de> Deserialize<'de> for Error {
       where
         A: serde::de::SeqAccess<'de>,
       {
-        let code: u8 = seq
+        let code: u16 = seq
           .next_element()?
           .ok_or_else(|| A::Error::invalid_length(0, &self))?;
 
@@ -67,8 +67,13 @@ pub mod error_code {
   // some of these codes are not used in this crate but we still list them here for completeness.
   #![allow(unused)]
 
-  pub const GENERIC_ERROR: u8 = 201;
-  pub const SERVER_ERROR: u8 = 202;
-  pub const PROTOCOL_ERROR: u8 = 203;
-  pub const METHOD_UNKNOWN: u8 = 204;
+  pub const GENERIC_ERROR: u16 = 201;
+  pub const SERVER_ERROR: u16 = 202;
+  pub const PROTOCOL_ERROR: u16 = 203;
+  pub const METHOD_UNKNOWN: u16 = 204;
+  pub const MESSAGE_TOO_BIG: u16 = 205;
+  pub const INVALID_SIGNATURE: u16 = 206;
+  pub const SALT_TOO_BIG: u16 = 207;
+  pub const CAS_MISMATCH: u16 = 301;
+  pub const SEQUENCE_TOO_LOW: u16 = 302;
 }
@@ -29,6 +29,10 @@
 //!      announce_peer:
 //!        announce that the peer associated with this node is downloading a
 //!        torrent.
+//!      get/put:
+//!        retrieve or store an arbitrary data item (BEP44).
+//!      sample_infohashes:
+//!        sample the info hashes stored by a node (BEP51).
 
 use std::fmt;
 
@@ -39,9 +43,13 @@ pub mod request;
 pub mod response;
 pub mod utils;
 
+#[cfg(test)]
+mod tests;
+
 pub use error::*;
 pub use request::*;
 pub use response::*;
+pub use serde_bencode::value::Value;
 pub use utils::Want;
 use utils::*;
 
@@ -57,6 +65,32 @@ pub struct Message {
   /// `typically 2 characters` are enough as they cover 2^16 outstanding queries.
   #[serde(rename = "t", with = "serde_bytes")]
   pub transaction_id: Vec<u8>,
+  /// Responses carry the "ip" key containing the compact address of the
+  /// querying node, as seen by the responding node (BEP42).
+  #[serde(
+    with = "crate::compact::addr",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub ip: Option<std::net::SocketAddr>,
+  /// Queries of read-only nodes carry the "ro" key, so the queried nodes do
+  /// not add them to their routing table (BEP43).
+  #[serde(
+    rename = "ro",
+    with = "flag",
+    default,
+    skip_serializing_if = "flag::is_false"
+  )]
+  pub read_only: bool,
+  /// The "v" key carries the client version string of the sender: two
+  /// characters identifying the client followed by two bytes of version.
+  #[serde(
+    rename = "v",
+    with = "serde_bytes",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub version: Option<Vec<u8>>,
   #[serde(flatten)]
   pub body: MessageBody,
 }
@@ -67,12 +101,85 @@ impl Message {
   /// `from_bytes_auto`:
   /// The same as from_bytes but deserialize_any will deserialize byte string as str
   /// if input bytes are valid UTF-8, otherwise as bytes.
+  ///
+  /// Queries with a method we do not implement are decoded as
+  /// [`Request::Unknown`], so they can still be answered.
   pub fn decode(input: &[u8]) -> Result<Self, serde_bencoded::DeError> {
-    serde_bencoded::from_bytes_auto(input)
+    serde_bencoded::from_bytes_auto(input).or_else(|error| {
+      match serde_bencoded::from_bytes_auto::<UnknownQuery>(input) {
+        Ok(query)
+          if query.message_type == "q"
+            && !Request::is_known_method(&query.method) =>
+        {
+          Ok(query.into())
+        }
+        _ => Err(error),
+      }
+    })
   }
 
+  /// Encode the message to bencode.
+  ///
+  /// Unlike `serde_bencoded`, `serde_bencode` encodes the dictionary keys of
+  /// the BEP44 values as byte strings, so the keys need not be UTF-8.
   pub fn encode(&self) -> Vec<u8> {
-    serde_bencoded::to_vec(self).expect("failed to serialize message")
+    let encoded = match &self.body {
+      MessageBody::Request(Request::Unknown(request)) => {
+        serde_bencode::to_bytes(&UnknownQuery {
+          transaction_id: self.transaction_id.clone(),
+          read_only: self.read_only,
+          version: self.version.clone(),
+          message_type: "q".to_owned(),
+          method: request.method.clone(),
+          args: request.clone(),
+        })
+      }
+      _ => serde_bencode::to_bytes(self),
+    };
+
+    encoded.expect("failed to serialize message")
+  }
+}
+
+/// A query with a method `Request` does not know about.
+#[derive(Serialize, Deserialize)]
+struct UnknownQuery {
+  #[serde(rename = "t", with = "serde_bytes")]
+  transaction_id: Vec<u8>,
+  #[serde(
+    rename = "ro",
+    with = "flag",
+    default,
+    skip_serializing_if = "flag::is_false"
+  )]
+  read_only: bool,
+  #[serde(
+    rename = "v",
+    with = "serde_bytes",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  version: Option<Vec<u8>>,
+  #[serde(rename = "y")]
+  message_type: String,
+  #[serde(rename = "q")]
+  method: String,
+  #[serde(rename = "a")]
+  args: UnknownRequest,
+}
+
+impl From<UnknownQuery> for Message {
+  fn from(query: UnknownQuery) -> Self {
+    Message {
+      transaction_id: query.transaction_id,
+      ip: None,
+      read_only: query.read_only,
+      version: query.version,
+      body: MessageBody::Request(Request::Unknown(UnknownRequest {
+        method: query.method,
+        ..query.args
+      })),
+    }
   }
 }
 
@@ -80,6 +187,9 @@ impl std::fmt::Debug for Message {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     f.debug_struct("Message")
       .field("transaction_id", &HexFmt(&self.transaction_id))
+      .field("ip", &self.ip)
+      .field("read_only", &self.read_only)
+      .field("version", &self.version.as_deref().map(HexFmt))
       .field("body", &self.body)
       .finish()
   }
@@ -100,7 +210,8 @@ impl std::fmt::Debug for HexFmt<'_> {
 ///
 /// The value of the "y" key is one of "q" for query, "r" for response, or "e" for error.
 ///
-/// A key "v" should be included in every message with a client version string.
+/// A key "v" should be included in every message with a client version string,
+/// see [`Message::version`].
 /// (Not all implementations include a "v" key so clients should not assume its presence.)
 #[derive(Clone, Eq, PartialEq, Serialize, Deserialize, Debug)]
 #[serde(tag = "y")]
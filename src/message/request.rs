@@ -1,15 +1,12 @@
-// TODO:
-// unrecognized requests which contain either
-// an 'info_hash' or 'target' arguments should be
-// interpreted as 'find_node' as per Mainline DHT extensions.
+use std::collections::BTreeMap;
 
 use serde::{Deserialize, Serialize};
 
 use crate::{InfoHash, NodeId};
 
 use super::{
-  utils::{port, want},
-  Want,
+  utils::{flag, port, want},
+  Value, Want,
 };
 
 /// All queries have an "id" key and value containing the node ID of the querying node.
@@ -23,6 +20,31 @@ pub enum Request {
   FindNode(FindNodeRequest),
   GetPeers(GetPeersRequest),
   AnnouncePeer(AnnouncePeerRequest),
+  Get(GetRequest),
+  Put(PutRequest),
+  SampleInfohashes(SampleInfohashesRequest),
+  /// A query with a method we do not implement, see [`Message::decode`].
+  ///
+  /// [`Message::decode`]: super::Message::decode
+  #[serde(skip)]
+  Unknown(UnknownRequest),
+}
+
+impl Request {
+  /// The "q" method names of the queries we implement.
+  const KNOWN_METHODS: &'static [&'static str] = &[
+    "ping",
+    "find_node",
+    "get_peers",
+    "announce_peer",
+    "get",
+    "put",
+    "sample_infohashes",
+  ];
+
+  pub(super) fn is_known_method(method: &str) -> bool {
+    Self::KNOWN_METHODS.contains(&method)
+  }
 }
 
 /// The most basic query is a ping.
@@ -100,11 +122,11 @@ pub struct FindNodeRequest {
 /// "q" = "get_peers" A get_peers query has two arguments("id", "info_hash").
 ///
 /// - If the queried node has peers for the infohash,
-///    they are returned in a key "values" as a list of strings.
-///    Each string containing "compact" format peer information for a single peer.
+///   they are returned in a key "values" as a list of strings.
+///   Each string containing "compact" format peer information for a single peer.
 /// - If the queried node has no peers for the infohash,
-///    a key "nodes" is returned containing the K nodes in the queried nodes routing table
-///    closest to the infohash supplied in the query.
+///   a key "nodes" is returned containing the K nodes in the queried nodes routing table
+///   closest to the infohash supplied in the query.
 ///
 /// In either case a "token" key is also included in the return value.
 ///
@@ -112,6 +134,10 @@ pub struct FindNodeRequest {
 ///
 /// The token value should be a short binary string.
 ///
+/// With "scrape" set, the response also carries the bloom filters "BFsd" and
+/// "BFpe" of the seeds and other peers of the infohash (BEP33). With "noseed"
+/// set, the seeds are left out of the "values".
+///
 /// ## Example Packets:
 /// ```json
 /// get_peers Query = {
@@ -154,6 +180,14 @@ pub struct GetPeersRequest {
 
   #[serde(with = "want", default, skip_serializing_if = "Option::is_none")]
   pub want: Option<Want>,
+
+  /// "scrape" asks for the bloom filters of the seeds and peers (BEP33).
+  #[serde(with = "flag", default, skip_serializing_if = "flag::is_false")]
+  pub scrape: bool,
+
+  /// "noseed" asks to only return the peers which are not seeds (BEP33).
+  #[serde(with = "flag", default, skip_serializing_if = "flag::is_false")]
+  pub noseed: bool,
 }
 
 /// Announce that the peer,
@@ -201,4 +235,204 @@ pub struct AnnouncePeerRequest {
   #[serde(with = "serde_bytes")]
   /// "token" received in response to a previous get_peers query.
   pub token: Vec<u8>,
+  /// "seed" tells the peer has the complete torrent (BEP33).
+  #[serde(with = "flag", default, skip_serializing_if = "flag::is_false")]
+  pub seed: bool,
+}
+
+/// Get a data item stored in the DHT (BEP44).
+///
+/// "q" = "get" A get query has two arguments("id", "target"), and an optional
+/// "seq" argument for mutable items.
+///
+/// The target of an immutable item is the SHA-1 hash of its bencoded value,
+/// the target of a mutable item is the SHA-1 hash of its public key and salt.
+///
+/// The response carries the item in a key "v" if the queried node has it,
+/// along with a write token and the closest nodes to the target. Mutable items
+/// also carry their public key "k", signature "sig" and sequence number "seq",
+/// the value is omitted if the "seq" of the query is not lower than that.
+///
+/// ## Example Packets:
+/// ```json
+/// get Query = {
+///   "t": "aa",
+///   "y": "q",
+///   "q": "get",
+///   "a": {
+///     "id": "abcdefghij0123456789",
+///     "target": "mnopqrstuvwxyz123456"
+///   }
+/// }
+/// Response = {
+///   "t": "aa",
+///   "y": "r",
+///   "r": {
+///     "id": "0123456789abcdefghij",
+///     "token": "aoeusnth",
+///     "nodes": "def456...",
+///     "v": "Hello world!"
+///   }
+/// }
+/// ```
+#[derive(Clone, Eq, PartialEq, Serialize, Deserialize, Debug)]
+pub struct GetRequest {
+  /// "id" containing the node ID of the querying node.
+  pub id: NodeId,
+  /// "target" containing the SHA-1 hash of the item.
+  pub target: NodeId,
+  /// "seq" the sequence number of the mutable item we already have.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub seq: Option<i64>,
+}
+
+/// Store a data item in the DHT (BEP44).
+///
+/// "q" = "put" A put query has three arguments("id", "token", "v"), a mutable
+/// item also has the arguments ("k", "sig", "seq") and optionally ("salt", "cas").
+///
+/// The token must have been received in response to a previous get query,
+/// and the bencoded value must not be larger than 1000 bytes.
+///
+/// A mutable item only replaces the stored one if its "seq" is higher, and if
+/// "cas" is present, only if the stored item has that sequence number.
+///
+/// ## Example Packets:
+/// ```json
+/// put Query = {
+///   "t": "aa",
+///   "y": "q",
+///   "q": "put",
+///   "a": {
+///     "id": "abcdefghij0123456789",
+///     "token": "aoeusnth",
+///     "v": "Hello world!"
+///   }
+/// }
+/// Response = {
+///   "t": "aa",
+///   "y": "r",
+///   "r": {
+///     "id": "mnopqrstuvwxyz123456"
+///   }
+/// }
+/// ```
+#[derive(Clone, Eq, PartialEq, Serialize, Deserialize, Debug)]
+pub struct PutRequest {
+  /// "id" containing the node ID of the querying node.
+  pub id: NodeId,
+  /// "token" received in response to a previous get query.
+  #[serde(with = "serde_bytes")]
+  pub token: Vec<u8>,
+  /// "v" the bencoded value to store.
+  pub v: Value,
+  /// "k" the ed25519 public key of a mutable item.
+  #[serde(
+    with = "serde_bytes",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub k: Option<Vec<u8>>,
+  /// "sig" the ed25519 signature of a mutable item.
+  #[serde(
+    with = "serde_bytes",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub sig: Option<Vec<u8>>,
+  /// "seq" the sequence number of a mutable item.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub seq: Option<i64>,
+  /// "salt" appended to the public key to compute the target.
+  #[serde(
+    with = "serde_bytes",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub salt: Option<Vec<u8>>,
+  /// "cas" the sequence number the stored item is expected to have.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub cas: Option<i64>,
+}
+
+/// Sample the info hashes a node stores (BEP51).
+///
+/// "q" = "sample_infohashes" A sample_infohashes query has two
+/// arguments("id", "target"), the target is used to find the closest nodes
+/// to continue the traversal of the DHT with.
+///
+/// The response carries a random sample of the stored info hashes in a key
+/// "samples", the total number of stored info hashes in "num" and the number of
+/// seconds the requester should wait before querying the node again in "interval".
+///
+/// ## Example Packets:
+/// ```json
+/// sample_infohashes Query = {
+///   "t": "aa",
+///   "y": "q",
+///   "q": "sample_infohashes",
+///   "a": {
+///     "id": "abcdefghij0123456789",
+///     "target": "mnopqrstuvwxyz123456"
+///   }
+/// }
+/// Response = {
+///   "t": "aa",
+///   "y": "r",
+///   "r": {
+///     "id": "0123456789abcdefghij",
+///     "interval": 21600,
+///     "nodes": "def456...",
+///     "num": 2,
+///     "samples": "<20 byte info hash><20 byte info hash>"
+///   }
+/// }
+/// ```
+#[derive(Clone, Eq, PartialEq, Serialize, Deserialize, Debug)]
+pub struct SampleInfohashesRequest {
+  /// "id" containing the node ID of the querying node.
+  pub id: NodeId,
+  /// "target" the id of the closest nodes to return.
+  pub target: NodeId,
+}
+
+/// A query with a method we do not implement.
+///
+/// Such queries are answered with a "Method Unknown" error, unless they
+/// contain either a "target" or an "info_hash" argument, in which case they
+/// are interpreted as find_node queries as per Mainline DHT extensions.
+///
+/// The arguments we do not know about are kept in `args`.
+#[derive(Clone, Eq, PartialEq, Serialize, Deserialize, Debug)]
+pub struct UnknownRequest {
+  /// "q" the name of the method.
+  #[serde(skip)]
+  pub method: String,
+  /// "id" containing the node ID of the querying node.
+  pub id: NodeId,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub target: Option<NodeId>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub info_hash: Option<InfoHash>,
+
+  #[serde(with = "want", default, skip_serializing_if = "Option::is_none")]
+  pub want: Option<Want>,
+
+  /// The other arguments of the query.
+  #[serde(flatten)]
+  pub args: BTreeMap<String, Value>,
+}
+
+impl UnknownRequest {
+  /// The find_node query to answer this query with, if it looks for a
+  /// "target" or an "info_hash".
+  pub fn as_find_node(&self) -> Option<FindNodeRequest> {
+    let target = self.target.or(self.info_hash)?;
+
+    Some(FindNodeRequest {
+      id: self.id,
+      target,
+      want: self.want,
+    })
+  }
 }
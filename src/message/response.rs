@@ -2,7 +2,11 @@ use std::net::SocketAddr;
 
 use serde::{Deserialize, Serialize};
 
-use crate::{compact, routing::node::NodeHandle, NodeId};
+use crate::{
+  bloom::BloomFilter, compact, routing::node::NodeHandle, InfoHash, NodeId,
+};
+
+use super::Value;
 
 #[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
 pub struct Response {
@@ -38,4 +42,70 @@ pub struct Response {
     skip_serializing_if = "Option::is_none"
   )]
   pub token: Option<Vec<u8>>,
+
+  // Only present in response to Get, if the item was found.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub v: Option<Value>,
+
+  // Only present in response to Get, if a mutable item was found.
+  #[serde(
+    with = "serde_bytes",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub k: Option<Vec<u8>>,
+
+  #[serde(
+    with = "serde_bytes",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub sig: Option<Vec<u8>>,
+
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub seq: Option<i64>,
+
+  // Only present in response to SampleInfohashes.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub interval: Option<i64>,
+
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub num: Option<i64>,
+
+  // Always present in response to SampleInfohashes, even if empty.
+  #[serde(
+    with = "compact::info_hashes::option",
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub samples: Option<Vec<InfoHash>>,
+
+  // Only present in response to GetPeers with scrape (BEP33).
+  #[serde(rename = "BFsd", default, skip_serializing_if = "Option::is_none")]
+  pub bf_seeds: Option<BloomFilter>,
+
+  #[serde(rename = "BFpe", default, skip_serializing_if = "Option::is_none")]
+  pub bf_peers: Option<BloomFilter>,
+}
+
+impl Response {
+  /// A response of the node `id` carrying nothing else, to fill in.
+  pub fn new(id: NodeId) -> Response {
+    Response {
+      id,
+      values: Vec::new(),
+      nodes_v4: Vec::new(),
+      nodes_v6: Vec::new(),
+      token: None,
+      v: None,
+      k: None,
+      sig: None,
+      seq: None,
+      interval: None,
+      num: None,
+      samples: None,
+      bf_seeds: None,
+      bf_peers: None,
+    }
+  }
 }
@@ -1,275 +1,665 @@
-#[cfg(test)]
-mod tests {
-  use super::*;
-  use pretty_assertions::assert_eq;
-  use std::net::{Ipv4Addr, Ipv6Addr};
-
-  #[test]
-  fn serialize_ping_request() {
-    let encoded = "d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
-    let decoded = Message {
-      transaction_id: b"aa".to_vec(),
-      body: MessageBody::Request(Request::Ping(PingRequest {
-        id: NodeId::from(*b"abcdefghij0123456789"),
-      })),
-    };
-
-    assert_serialize_deserialize(encoded, &decoded)
-  }
-
-  #[test]
-  fn serialize_find_node_request() {
-    let encoded = "d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q9:find_node1:t2:aa1:y1:qe";
-    let decoded = Message {
-      transaction_id: b"aa".to_vec(),
-      body: MessageBody::Request(Request::FindNode(FindNodeRequest {
-        id: NodeId::from(*b"abcdefghij0123456789"),
-        target: NodeId::from(*b"mnopqrstuvwxyz123456"),
-        want: None,
-      })),
-    };
-
-    assert_serialize_deserialize(encoded, &decoded)
-  }
-
-  #[test]
-  fn serialize_find_node_request_with_want() {
-    let encoded = "d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz1234564:wantl2:n42:n6ee1:q9:find_node1:t2:aa1:y1:qe";
-    let decoded = Message {
-      transaction_id: b"aa".to_vec(),
-      body: MessageBody::Request(Request::FindNode(FindNodeRequest {
+use super::*;
+use crate::{
+  id::{InfoHash, NodeId},
+  routing::node::NodeHandle,
+};
+use pretty_assertions::assert_eq;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+#[test]
+fn serialize_ping_request() {
+  let encoded = "d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Request(Request::Ping(PingRequest {
+      id: NodeId::from(*b"abcdefghij0123456789"),
+    })),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded)
+}
+
+#[test]
+fn serialize_read_only_ping_request() {
+  let encoded =
+    "d1:ad2:id20:abcdefghij0123456789e1:q4:ping2:roi1e1:t2:aa1:y1:qe";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: true,
+    version: None,
+    body: MessageBody::Request(Request::Ping(PingRequest {
+      id: NodeId::from(*b"abcdefghij0123456789"),
+    })),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded)
+}
+
+#[test]
+fn serialize_ping_request_with_version() {
+  let encoded =
+    "d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:v4:RS011:y1:qe";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: Some(b"RS01".to_vec()),
+    body: MessageBody::Request(Request::Ping(PingRequest {
+      id: NodeId::from(*b"abcdefghij0123456789"),
+    })),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded)
+}
+
+#[test]
+fn serialize_find_node_request() {
+  let encoded = "d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q9:find_node1:t2:aa1:y1:qe";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Request(Request::FindNode(FindNodeRequest {
+      id: NodeId::from(*b"abcdefghij0123456789"),
+      target: NodeId::from(*b"mnopqrstuvwxyz123456"),
+      want: None,
+    })),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded)
+}
+
+#[test]
+fn serialize_find_node_request_with_want() {
+  let encoded = "d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz1234564:wantl2:n42:n6ee1:q9:find_node1:t2:aa1:y1:qe";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Request(Request::FindNode(FindNodeRequest {
+      id: NodeId::from(*b"abcdefghij0123456789"),
+      target: NodeId::from(*b"mnopqrstuvwxyz123456"),
+      want: Some(Want::Both),
+    })),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded)
+}
+
+#[test]
+fn serialize_get_peers_request() {
+  let encoded = "d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456e1:q9:get_peers1:t2:aa1:y1:qe";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Request(Request::GetPeers(GetPeersRequest {
+      id: NodeId::from(*b"abcdefghij0123456789"),
+      info_hash: InfoHash::from(*b"mnopqrstuvwxyz123456"),
+      want: None,
+      scrape: false,
+      noseed: false,
+    })),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded)
+}
+
+#[test]
+fn serialize_get_peers_request_with_want() {
+  let encoded = "d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234564:wantl2:n4ee1:q9:get_peers1:t2:aa1:y1:qe";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Request(Request::GetPeers(GetPeersRequest {
+      id: NodeId::from(*b"abcdefghij0123456789"),
+      info_hash: InfoHash::from(*b"mnopqrstuvwxyz123456"),
+      want: Some(Want::V4),
+      scrape: false,
+      noseed: false,
+    })),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded)
+}
+
+#[test]
+fn serialize_get_peers_request_with_scrape() {
+  let encoded = "d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234566:noseedi1e6:scrapei1ee1:q9:get_peers1:t2:aa1:y1:qe";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Request(Request::GetPeers(GetPeersRequest {
+      id: NodeId::from(*b"abcdefghij0123456789"),
+      info_hash: InfoHash::from(*b"mnopqrstuvwxyz123456"),
+      want: None,
+      scrape: true,
+      noseed: true,
+    })),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded)
+}
+
+#[test]
+fn serialize_announce_peer_request_with_implied_port() {
+  let encoded = "d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234565:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Request(Request::AnnouncePeer(AnnouncePeerRequest {
+      id: NodeId::from(*b"abcdefghij0123456789"),
+      port: None,
+      info_hash: InfoHash::from(*b"mnopqrstuvwxyz123456"),
+      token: b"aoeusnth".to_vec(),
+      seed: false,
+    })),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_announce_peer_request_with_explicit_port() {
+  let encoded = "d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Request(Request::AnnouncePeer(AnnouncePeerRequest {
+      id: NodeId::from(*b"abcdefghij0123456789"),
+      port: Some(6881),
+      info_hash: InfoHash::from(*b"mnopqrstuvwxyz123456"),
+      token: b"aoeusnth".to_vec(),
+      seed: false,
+    })),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_announce_peer_request_as_seed() {
+  let encoded = "d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234564:porti6881e4:seedi1e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Request(Request::AnnouncePeer(AnnouncePeerRequest {
+      id: NodeId::from(*b"abcdefghij0123456789"),
+      port: Some(6881),
+      info_hash: InfoHash::from(*b"mnopqrstuvwxyz123456"),
+      token: b"aoeusnth".to_vec(),
+      seed: true,
+    })),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_get_request() {
+  let encoded = "d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q3:get1:t2:aa1:y1:qe";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Request(Request::Get(GetRequest {
+      id: NodeId::from(*b"abcdefghij0123456789"),
+      target: NodeId::from(*b"mnopqrstuvwxyz123456"),
+      seq: None,
+    })),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_put_request() {
+  let encoded = "d1:ad2:id20:abcdefghij01234567895:token8:aoeusnth1:v12:Hello World!e1:q3:put1:t2:aa1:y1:qe";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Request(Request::Put(PutRequest {
+      id: NodeId::from(*b"abcdefghij0123456789"),
+      token: b"aoeusnth".to_vec(),
+      v: Value::Bytes(b"Hello World!".to_vec()),
+      k: None,
+      sig: None,
+      seq: None,
+      salt: None,
+      cas: None,
+    })),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_put_mutable_request() {
+  let encoded = "d1:ad3:casi1e2:id20:abcdefghij01234567891:k4:key14:salt6:foobar3:seqi2e3:sig3:sig5:token8:aoeusnth1:v12:Hello World!e1:q3:put1:t2:aa1:y1:qe";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Request(Request::Put(PutRequest {
+      id: NodeId::from(*b"abcdefghij0123456789"),
+      token: b"aoeusnth".to_vec(),
+      v: Value::Bytes(b"Hello World!".to_vec()),
+      k: Some(b"key1".to_vec()),
+      sig: Some(b"sig".to_vec()),
+      seq: Some(2),
+      salt: Some(b"foobar".to_vec()),
+      cas: Some(1),
+    })),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_put_request_with_binary_dict_key() {
+  let encoded = b"d1:ad2:id20:abcdefghij01234567895:token8:aoeusnth1:vd2:\xff\x00i1eee1:q3:put1:t2:aa1:y1:qe";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Request(Request::Put(PutRequest {
+      id: NodeId::from(*b"abcdefghij0123456789"),
+      token: b"aoeusnth".to_vec(),
+      v: Value::Dict(
+        [(b"\xff\x00".to_vec(), Value::Int(1))]
+          .into_iter()
+          .collect(),
+      ),
+      k: None,
+      sig: None,
+      seq: None,
+      salt: None,
+      cas: None,
+    })),
+  };
+
+  assert_eq!(decoded.encode(), encoded);
+  assert_eq!(Message::decode(encoded).unwrap(), decoded);
+}
+
+#[test]
+fn serialize_sample_infohashes_request() {
+  let encoded = "d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q17:sample_infohashes1:t2:aa1:y1:qe";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Request(Request::SampleInfohashes(
+      SampleInfohashesRequest {
         id: NodeId::from(*b"abcdefghij0123456789"),
         target: NodeId::from(*b"mnopqrstuvwxyz123456"),
-        want: Some(Want::Both),
-      })),
-    };
-
-    assert_serialize_deserialize(encoded, &decoded)
-  }
-
-  #[test]
-  fn serialize_get_peers_request() {
-    let encoded = "d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456e1:q9:get_peers1:t2:aa1:y1:qe";
-    let decoded = Message {
-      transaction_id: b"aa".to_vec(),
-      body: MessageBody::Request(Request::GetPeers(GetPeersRequest {
-        id: NodeId::from(*b"abcdefghij0123456789"),
-        info_hash: InfoHash::from(*b"mnopqrstuvwxyz123456"),
-        want: None,
-      })),
-    };
-
-    assert_serialize_deserialize(encoded, &decoded)
-  }
-
-  #[test]
-  fn serialize_get_peers_request_with_want() {
-    let encoded = "d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234564:wantl2:n4ee1:q9:get_peers1:t2:aa1:y1:qe";
-    let decoded = Message {
-      transaction_id: b"aa".to_vec(),
-      body: MessageBody::Request(Request::GetPeers(GetPeersRequest {
-        id: NodeId::from(*b"abcdefghij0123456789"),
-        info_hash: InfoHash::from(*b"mnopqrstuvwxyz123456"),
-        want: Some(Want::V4),
-      })),
-    };
-
-    assert_serialize_deserialize(encoded, &decoded)
-  }
-
-  #[test]
-  fn serialize_announce_peer_request_with_implied_port() {
-    let encoded = "d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234565:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
-    let decoded = Message {
-      transaction_id: b"aa".to_vec(),
-      body: MessageBody::Request(Request::AnnouncePeer(AnnouncePeerRequest {
-        id: NodeId::from(*b"abcdefghij0123456789"),
-        port: None,
-        info_hash: InfoHash::from(*b"mnopqrstuvwxyz123456"),
-        token: b"aoeusnth".to_vec(),
-      })),
-    };
-
-    assert_serialize_deserialize(encoded, &decoded);
-  }
-
-  #[test]
-  fn serialize_announce_peer_request_with_explicit_port() {
-    let encoded = "d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
-    let decoded = Message {
-      transaction_id: b"aa".to_vec(),
-      body: MessageBody::Request(Request::AnnouncePeer(AnnouncePeerRequest {
-        id: NodeId::from(*b"abcdefghij0123456789"),
-        port: Some(6881),
-        info_hash: InfoHash::from(*b"mnopqrstuvwxyz123456"),
-        token: b"aoeusnth".to_vec(),
-      })),
-    };
-
-    assert_serialize_deserialize(encoded, &decoded);
-  }
-
-  #[test]
-  fn serialize_other_response_none() {
-    let encoded = "d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
-    let decoded = Message {
-      transaction_id: b"aa".to_vec(),
-      body: MessageBody::Response(Response {
-        id: NodeId::from(*b"mnopqrstuvwxyz123456"),
-        values: vec![],
-        nodes_v4: vec![],
-        nodes_v6: vec![],
-        token: None,
-      }),
-    };
-
-    assert_serialize_deserialize(encoded, &decoded);
-  }
-
-  #[test]
-  fn serialize_other_response_v4() {
-    let encoded =
-            "d1:rd2:id20:0123456789abcdefghij5:nodes26:mnopqrstuvwxyz012345axje.ue1:t2:aa1:y1:re";
-    let decoded = Message {
-      transaction_id: b"aa".to_vec(),
-      body: MessageBody::Response(Response {
-        id: NodeId::from(*b"0123456789abcdefghij"),
-        values: vec![],
-        nodes_v4: vec![NodeHandle {
-          id: NodeId::from(*b"mnopqrstuvwxyz012345"),
-          addr: (Ipv4Addr::new(97, 120, 106, 101), 11893).into(),
-        }],
-        nodes_v6: vec![],
-        token: None,
-      }),
-    };
-
-    assert_serialize_deserialize(encoded, &decoded);
-  }
-
-  #[test]
-  fn serialize_other_response_v6() {
-    let encoded =
-            "d1:rd2:id20:0123456789abcdefghij6:nodes638:mnopqrstuvwxyz012345abcdefghijklmnop.ue1:t2:aa1:y1:re";
-    let decoded = Message {
-      transaction_id: b"aa".to_vec(),
-      body: MessageBody::Response(Response {
-        id: NodeId::from(*b"0123456789abcdefghij"),
-        values: vec![],
-        nodes_v4: vec![],
-        nodes_v6: vec![NodeHandle {
-          id: NodeId::from(*b"mnopqrstuvwxyz012345"),
-          addr: (
-            Ipv6Addr::new(
-              0x6162, 0x6364, 0x6566, 0x6768, 0x696a, 0x6b6c, 0x6d6e, 0x6f70,
-            ),
-            11893,
-          )
-            .into(),
-        }],
-        token: None,
-      }),
-    };
-
-    assert_serialize_deserialize(encoded, &decoded);
-  }
-
-  #[test]
-  fn serialize_other_response_both() {
-    let encoded =
-            "d1:rd2:id20:0123456789abcdefghij5:nodes26:mnopqrstuvwxyz012345axje.u6:nodes638:6789abcdefghijklmnopabcdefghijklmnop.ue1:t2:aa1:y1:re";
-    let decoded = Message {
-      transaction_id: b"aa".to_vec(),
-      body: MessageBody::Response(Response {
-        id: NodeId::from(*b"0123456789abcdefghij"),
-        values: vec![],
-        nodes_v4: vec![NodeHandle {
-          id: NodeId::from(*b"mnopqrstuvwxyz012345"),
+      },
+    )),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_sample_infohashes_response() {
+  let encoded = "d1:rd2:id20:0123456789abcdefghij8:intervali21600e5:nodes26:mnopqrstuvwxyz012345axje.u3:numi2e7:samples20:abcdefghij0123456789e1:t2:aa1:y1:re";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Response(Response {
+      nodes_v4: vec![NodeHandle {
+        id: NodeId::from(*b"mnopqrstuvwxyz012345"),
+        addr: (Ipv4Addr::new(97, 120, 106, 101), 11893).into(),
+      }],
+      interval: Some(21600),
+      num: Some(2),
+      samples: Some(vec![InfoHash::from(*b"abcdefghij0123456789")]),
+      ..Response::new(NodeId::from(*b"0123456789abcdefghij"))
+    }),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_empty_sample_infohashes_response() {
+  // The "samples" key is required even if we store no info hash.
+  let encoded = "d1:rd2:id20:0123456789abcdefghij8:intervali21600e3:numi0e7:samples0:e1:t2:aa1:y1:re";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Response(Response {
+      interval: Some(21600),
+      num: Some(0),
+      samples: Some(vec![]),
+      ..Response::new(NodeId::from(*b"0123456789abcdefghij"))
+    }),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_get_peers_response_with_scrape() {
+  let seeds = [0x01u8; 256];
+  let peers = [0x80u8; 256];
+  let mut encoded = b"d1:rd4:BFpe256:".to_vec();
+  encoded.extend_from_slice(&peers);
+  encoded.extend_from_slice(b"4:BFsd256:");
+  encoded.extend_from_slice(&seeds);
+  encoded.extend_from_slice(
+    b"2:id20:0123456789abcdefghij5:token8:aoeusnthe1:t2:aa1:y1:re",
+  );
+
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Response(Response {
+      token: Some(b"aoeusnth".to_vec()),
+      bf_seeds: Some(seeds.into()),
+      bf_peers: Some(peers.into()),
+      ..Response::new(NodeId::from(*b"0123456789abcdefghij"))
+    }),
+  };
+
+  assert_eq!(decoded.encode(), encoded);
+  assert_eq!(Message::decode(&encoded).unwrap(), decoded);
+}
+
+#[test]
+fn serialize_other_response_none() {
+  let encoded = "d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Response(Response::new(NodeId::from(
+      *b"mnopqrstuvwxyz123456",
+    ))),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_other_response_v4() {
+  let encoded =
+          "d1:rd2:id20:0123456789abcdefghij5:nodes26:mnopqrstuvwxyz012345axje.ue1:t2:aa1:y1:re";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Response(Response {
+      nodes_v4: vec![NodeHandle {
+        id: NodeId::from(*b"mnopqrstuvwxyz012345"),
+        addr: (Ipv4Addr::new(97, 120, 106, 101), 11893).into(),
+      }],
+      ..Response::new(NodeId::from(*b"0123456789abcdefghij"))
+    }),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_other_response_v6() {
+  let encoded =
+          "d1:rd2:id20:0123456789abcdefghij6:nodes638:mnopqrstuvwxyz012345abcdefghijklmnop.ue1:t2:aa1:y1:re";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Response(Response {
+      nodes_v6: vec![NodeHandle {
+        id: NodeId::from(*b"mnopqrstuvwxyz012345"),
+        addr: (
+          Ipv6Addr::new(
+            0x6162, 0x6364, 0x6566, 0x6768, 0x696a, 0x6b6c, 0x6d6e, 0x6f70,
+          ),
+          11893,
+        )
+          .into(),
+      }],
+      ..Response::new(NodeId::from(*b"0123456789abcdefghij"))
+    }),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_other_response_both() {
+  let encoded =
+          "d1:rd2:id20:0123456789abcdefghij5:nodes26:mnopqrstuvwxyz012345axje.u6:nodes638:6789abcdefghijklmnopabcdefghijklmnop.ue1:t2:aa1:y1:re";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Response(Response {
+      nodes_v4: vec![NodeHandle {
+        id: NodeId::from(*b"mnopqrstuvwxyz012345"),
+        addr: (Ipv4Addr::new(97, 120, 106, 101), 11893).into(),
+      }],
+      nodes_v6: vec![NodeHandle {
+        id: NodeId::from(*b"6789abcdefghijklmnop"),
+        addr: (
+          Ipv6Addr::new(
+            0x6162, 0x6364, 0x6566, 0x6768, 0x696a, 0x6b6c, 0x6d6e, 0x6f70,
+          ),
+          11893,
+        )
+          .into(),
+      }],
+      ..Response::new(NodeId::from(*b"0123456789abcdefghij"))
+    }),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_get_peers_response_with_values() {
+  let encoded = "d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Response(Response {
+      values: vec![
+        (Ipv4Addr::new(97, 120, 106, 101), 11893).into(),
+        (Ipv4Addr::new(105, 100, 104, 116), 28269).into(),
+      ],
+      token: Some(b"aoeusnth".to_vec()),
+      ..Response::new(NodeId::from(*b"abcdefghij0123456789"))
+    }),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_get_peers_response_with_nodes_v4() {
+  let encoded =
+          "d1:rd2:id20:abcdefghij01234567895:nodes52:mnopqrstuvwxyz123456axje.u789abcdefghijklmnopqidhtnm5:token8:aoeusnthe1:t2:aa1:y1:re";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Response(Response {
+      nodes_v4: vec![
+        NodeHandle {
+          id: NodeId::from(*b"mnopqrstuvwxyz123456"),
           addr: (Ipv4Addr::new(97, 120, 106, 101), 11893).into(),
-        }],
-        nodes_v6: vec![NodeHandle {
-          id: NodeId::from(*b"6789abcdefghijklmnop"),
-          addr: (
-            Ipv6Addr::new(
-              0x6162, 0x6364, 0x6566, 0x6768, 0x696a, 0x6b6c, 0x6d6e, 0x6f70,
-            ),
-            11893,
-          )
-            .into(),
-        }],
-        token: None,
-      }),
-    };
-
-    assert_serialize_deserialize(encoded, &decoded);
-  }
-
-  #[test]
-  fn serialize_get_peers_response_with_values() {
-    let encoded = "d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
-    let decoded = Message {
-      transaction_id: b"aa".to_vec(),
-      body: MessageBody::Response(Response {
-        id: NodeId::from(*b"abcdefghij0123456789"),
-        values: vec![
-          (Ipv4Addr::new(97, 120, 106, 101), 11893).into(),
-          (Ipv4Addr::new(105, 100, 104, 116), 28269).into(),
-        ],
-        nodes_v4: vec![],
-        nodes_v6: vec![],
-        token: Some(b"aoeusnth".to_vec()),
-      }),
-    };
-
-    assert_serialize_deserialize(encoded, &decoded);
-  }
-
-  #[test]
-  fn serialize_get_peers_response_with_nodes_v4() {
-    let encoded =
-            "d1:rd2:id20:abcdefghij01234567895:nodes52:mnopqrstuvwxyz123456axje.u789abcdefghijklmnopqidhtnm5:token8:aoeusnthe1:t2:aa1:y1:re";
-    let decoded = Message {
-      transaction_id: b"aa".to_vec(),
-      body: MessageBody::Response(Response {
-        id: NodeId::from(*b"abcdefghij0123456789"),
-        values: vec![],
-        nodes_v4: vec![
-          NodeHandle {
-            id: NodeId::from(*b"mnopqrstuvwxyz123456"),
-            addr: (Ipv4Addr::new(97, 120, 106, 101), 11893).into(),
-          },
-          NodeHandle {
-            id: NodeId::from(*b"789abcdefghijklmnopq"),
-            addr: (Ipv4Addr::new(105, 100, 104, 116), 28269).into(),
-          },
-        ],
-        nodes_v6: vec![],
-        token: Some(b"aoeusnth".to_vec()),
-      }),
-    };
-
-    assert_serialize_deserialize(encoded, &decoded);
-  }
-
-  #[test]
-  fn serialize_error() {
-    let encoded = "d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
-    let decoded = Message {
-      transaction_id: b"aa".to_vec(),
-      body: MessageBody::Error(Error {
-        code: error_code::GENERIC_ERROR,
-        message: "A Generic Error Ocurred".to_owned(),
-      }),
-    };
-
-    assert_serialize_deserialize(encoded, &decoded);
-  }
-
-  #[track_caller]
-  fn assert_serialize_deserialize(encoded: &str, decoded: &Message) {
-    let l_encoded = serde_bencoded::to_string(decoded).unwrap();
-    assert_eq!(l_encoded, encoded);
-    let r_decoded = Message::decode(encoded.as_bytes()).unwrap();
-    assert_eq!(r_decoded, *decoded);
-  }
+        },
+        NodeHandle {
+          id: NodeId::from(*b"789abcdefghijklmnopq"),
+          addr: (Ipv4Addr::new(105, 100, 104, 116), 28269).into(),
+        },
+      ],
+      token: Some(b"aoeusnth".to_vec()),
+      ..Response::new(NodeId::from(*b"abcdefghij0123456789"))
+    }),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_get_response_with_value() {
+  let encoded = "d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth1:vd4:listli1ei2eeee1:t2:aa1:y1:re";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Response(Response {
+      token: Some(b"aoeusnth".to_vec()),
+      v: Some(Value::Dict(
+        [(
+          b"list".to_vec(),
+          Value::List(vec![Value::Int(1), Value::Int(2)]),
+        )]
+        .into_iter()
+        .collect(),
+      )),
+      ..Response::new(NodeId::from(*b"abcdefghij0123456789"))
+    }),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_response_with_ip() {
+  let encoded = "d2:ip6:axje.u1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: Some((Ipv4Addr::new(97, 120, 106, 101), 11893).into()),
+    read_only: false,
+    version: None,
+    body: MessageBody::Response(Response::new(NodeId::from(
+      *b"mnopqrstuvwxyz123456",
+    ))),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_error() {
+  let encoded = "d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Error(Error {
+      code: error_code::GENERIC_ERROR,
+      message: "A Generic Error Ocurred".to_owned(),
+    }),
+  };
+
+  assert_serialize_deserialize(encoded, &decoded);
+}
+
+#[test]
+fn serialize_unknown_request() {
+  let encoded = "d1:ad3:fooi42e2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q7:vote_me1:t2:aa1:y1:qe";
+  let decoded = Message {
+    transaction_id: b"aa".to_vec(),
+    ip: None,
+    read_only: false,
+    version: None,
+    body: MessageBody::Request(Request::Unknown(UnknownRequest {
+      method: "vote_me".to_owned(),
+      id: NodeId::from(*b"abcdefghij0123456789"),
+      target: Some(NodeId::from(*b"mnopqrstuvwxyz123456")),
+      info_hash: None,
+      want: None,
+      args: [("foo".to_owned(), Value::Int(42))].into(),
+    })),
+  };
+
+  assert_eq!(decoded.encode(), encoded.as_bytes());
+  assert_eq!(Message::decode(encoded.as_bytes()).unwrap(), decoded);
+}
+
+#[test]
+fn positive_unknown_request_as_find_node() {
+  let encoded = "d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456e1:q7:vote_me1:t2:aa1:y1:qe";
+  let message = Message::decode(encoded.as_bytes()).unwrap();
+
+  let MessageBody::Request(Request::Unknown(request)) = message.body else {
+    panic!("not an unknown request: {:?}", message.body);
+  };
+  assert_eq!(
+    request.as_find_node(),
+    Some(FindNodeRequest {
+      id: NodeId::from(*b"abcdefghij0123456789"),
+      target: NodeId::from(*b"mnopqrstuvwxyz123456"),
+      want: None,
+    })
+  );
+}
+
+#[test]
+fn negative_unknown_request_without_target() {
+  let encoded = "d1:ad2:id20:abcdefghij0123456789e1:q7:vote_me1:t2:aa1:y1:qe";
+  let message = Message::decode(encoded.as_bytes()).unwrap();
+
+  let MessageBody::Request(Request::Unknown(request)) = message.body else {
+    panic!("not an unknown request: {:?}", message.body);
+  };
+  assert_eq!(request.method, "vote_me");
+  assert_eq!(request.as_find_node(), None);
+}
+
+#[test]
+fn negative_malformed_known_request() {
+  // A ping without an id is not treated as an unknown query.
+  let encoded = "d1:ade1:q4:ping1:t2:aa1:y1:qe";
+
+  assert!(Message::decode(encoded.as_bytes()).is_err());
+}
+
+#[track_caller]
+fn assert_serialize_deserialize(encoded: &str, decoded: &Message) {
+  assert_eq!(decoded.encode(), encoded.as_bytes());
+  let r_decoded = Message::decode(encoded.as_bytes()).unwrap();
+  assert_eq!(r_decoded, *decoded);
 }
@@ -166,3 +166,27 @@ pub(super) mod port {
     Ok(num > 0)
   }
 }
+
+/// Helper to serialize or deserialize the boolean flags sent as integers,
+/// such as `seed`, `scrape` and `noseed` (BEP33).
+pub(super) mod flag {
+  use serde::{Deserialize, Deserializer, Serializer};
+
+  pub fn serialize<S: Serializer>(
+    flag: &bool,
+    s: S,
+  ) -> Result<S::Ok, S::Error> {
+    s.serialize_u8(*flag as u8)
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(
+    d: D,
+  ) -> Result<bool, D::Error> {
+    let num = i64::deserialize(d)?;
+    Ok(num > 0)
+  }
+
+  pub fn is_false(flag: &bool) -> bool {
+    !*flag
+  }
+}
@@ -0,0 +1,247 @@
+//! Updating torrents via DHT mutable items.
+//!
+//! Reference link:
+//!   http://bittorrent.org/beps/bep_0046.html
+//!
+//! The publisher stores the info hash of the latest version of a torrent in a
+//! mutable item, whose value is a dictionary with the info hash under the key
+//! "ih". Followers poll the item by the public key of the publisher and search
+//! for the peers of the info hash whenever a newer version points to another
+//! one, and again on the next polls once the search is over.
+
+use std::{net::SocketAddr, pin::Pin, time::Duration};
+
+use futures_util::{Stream, StreamExt};
+use tokio::{
+  select,
+  sync::mpsc,
+  time::{self, MissedTickBehavior},
+};
+
+use crate::{
+  builder::SearchStream,
+  id::InfoHash,
+  item::{MutableItem, PUBLIC_KEY_LEN},
+  message::Value,
+  worker::{OneShotTask, SearchOptions, StartLookup, StartMutableItemLookup},
+};
+
+const INFO_HASH_KEY: &[u8] = b"ih";
+
+/// Build the value of a mutable item pointing to the given info hash.
+pub fn info_hash_value(info_hash: InfoHash) -> Value {
+  Value::Dict(
+    [(
+      INFO_HASH_KEY.to_vec(),
+      Value::Bytes(info_hash.as_ref().to_vec()),
+    )]
+    .into_iter()
+    .collect(),
+  )
+}
+
+/// Extract the info hash from the value of a mutable item.
+pub fn parse_info_hash(value: &Value) -> Option<InfoHash> {
+  match value {
+    Value::Dict(dict) => match dict.get(INFO_HASH_KEY) {
+      Some(Value::Bytes(bytes)) => InfoHash::try_from(&bytes[..]).ok(),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+/// Stream returned from [`MainlineDht::resolve()`](crate::MainlineDht::resolve)
+///
+/// Yields the peers of the latest info hash published under the public key.
+#[must_use = "streams do nothing unless polled"]
+pub struct ResolveStream(mpsc::UnboundedReceiver<(InfoHash, SocketAddr)>);
+
+impl Stream for ResolveStream {
+  type Item = (InfoHash, SocketAddr);
+
+  fn poll_next(
+    mut self: std::pin::Pin<&mut Self>,
+    cx: &mut std::task::Context<'_>,
+  ) -> std::task::Poll<Option<Self::Item>> {
+    Pin::new(&mut self.0).poll_recv(cx)
+  }
+}
+
+/// Start following the mutable item, until the returned stream is dropped.
+pub(crate) fn start(
+  send: mpsc::UnboundedSender<OneShotTask>,
+  public_key: [u8; PUBLIC_KEY_LEN],
+  salt: Option<Vec<u8>>,
+  poll_interval: Duration,
+) -> ResolveStream {
+  let (tx, rx) = mpsc::unbounded_channel();
+
+  tokio::spawn(run(send, public_key, salt, poll_interval, tx));
+
+  ResolveStream(rx)
+}
+
+async fn run(
+  send: mpsc::UnboundedSender<OneShotTask>,
+  public_key: [u8; PUBLIC_KEY_LEN],
+  salt: Option<Vec<u8>>,
+  poll_interval: Duration,
+  tx: mpsc::UnboundedSender<(InfoHash, SocketAddr)>,
+) {
+  let mut interval = time::interval(poll_interval);
+  // A poll runs a whole lookup, which may take longer than the interval.
+  interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+  let mut current_seq = None;
+  let mut current_info_hash = None;
+  // The lookup of the mutable item in progress, if any.
+  let mut poll: Option<mpsc::UnboundedReceiver<MutableItem>> = None;
+  let mut search: Option<(InfoHash, SearchStream)> = None;
+
+  loop {
+    select! {
+      _ = interval.tick(), if poll.is_none() => {
+        match get_mutable(&send, public_key, salt.clone()) {
+          Some(rx) => poll = Some(rx),
+          // The DhtHandler has shut down.
+          None => return,
+        }
+      }
+      item = next_item(&mut poll) => {
+        poll = None;
+
+        if let Some(item) = item.filter(|item| Some(item.seq) > current_seq) {
+          current_seq = Some(item.seq);
+
+          match parse_info_hash(&item.value) {
+            Some(info_hash) if Some(info_hash) != current_info_hash => {
+              log::debug!(
+                "following mutable item seq {} to info hash {:?}",
+                item.seq,
+                info_hash
+              );
+              current_info_hash = Some(info_hash);
+              search = None;
+            }
+            Some(_) => (),
+            None => log::warn!(
+              "mutable item seq {} does not contain an info hash",
+              item.seq
+            ),
+          }
+        }
+
+        // Search the peers of the current info hash again once the previous
+        // search is over, to find the peers which joined since.
+        if let (None, Some(info_hash)) = (&search, current_info_hash) {
+          match start_search(&send, info_hash) {
+            Some(stream) => search = Some((info_hash, stream)),
+            None => return,
+          }
+        }
+      }
+      peer = next_peer(&mut search) => match peer {
+        Some(peer) => {
+          if tx.send(peer).is_err() {
+            return;
+          }
+        }
+        // The search has finished, wait for the next poll.
+        None => search = None,
+      },
+      _ = tx.closed() => return,
+    }
+  }
+}
+
+async fn next_item(
+  poll: &mut Option<mpsc::UnboundedReceiver<MutableItem>>,
+) -> Option<MutableItem> {
+  match poll {
+    Some(rx) => rx.recv().await,
+    None => std::future::pending().await,
+  }
+}
+
+async fn next_peer(
+  search: &mut Option<(InfoHash, SearchStream)>,
+) -> Option<(InfoHash, SocketAddr)> {
+  match search {
+    Some((info_hash, stream)) => {
+      stream.next().await.map(|peer| (*info_hash, peer))
+    }
+    None => std::future::pending().await,
+  }
+}
+
+/// Start a lookup of the mutable item, which sends the newest item found, if
+/// any, through the returned channel.
+fn get_mutable(
+  send: &mpsc::UnboundedSender<OneShotTask>,
+  public_key: [u8; PUBLIC_KEY_LEN],
+  salt: Option<Vec<u8>>,
+) -> Option<mpsc::UnboundedReceiver<MutableItem>> {
+  let (tx, rx) = mpsc::unbounded_channel();
+
+  send
+    .send(OneShotTask::StartMutableItemLookup(
+      StartMutableItemLookup {
+        public_key,
+        salt,
+        put: None,
+        cas: None,
+        tx,
+        outcome_tx: None,
+      },
+    ))
+    .ok()?;
+
+  Some(rx)
+}
+
+fn start_search(
+  send: &mpsc::UnboundedSender<OneShotTask>,
+  info_hash: InfoHash,
+) -> Option<SearchStream> {
+  let (tx, rx) = mpsc::unbounded_channel();
+
+  send
+    .send(OneShotTask::StartLookup(StartLookup {
+      info_hash,
+      options: SearchOptions::new(),
+      tx,
+      outcome_tx: None,
+      events_tx: None,
+    }))
+    .ok()?;
+
+  Some(SearchStream(rx))
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::id::InfoHash;
+  use crate::message::Value;
+  use crate::resolver;
+  use pretty_assertions::assert_eq;
+
+  #[test]
+  fn positive_parse_info_hash() {
+    let info_hash = InfoHash::sha1(b"release");
+    let value = resolver::info_hash_value(info_hash);
+
+    assert_eq!(resolver::parse_info_hash(&value), Some(info_hash));
+  }
+
+  #[test]
+  fn negative_parse_info_hash() {
+    let short = Value::Dict(
+      [(b"ih".to_vec(), Value::Bytes(vec![0u8; 19]))]
+        .into_iter()
+        .collect(),
+    );
+
+    assert_eq!(resolver::parse_info_hash(&short), None);
+    assert_eq!(resolver::parse_info_hash(&Value::Int(1)), None);
+  }
+}
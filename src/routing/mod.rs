@@ -1,3 +1,4 @@
 pub mod bucket;
 pub mod node;
+pub mod persist;
 pub mod table;
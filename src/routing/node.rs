@@ -39,6 +39,10 @@ pub struct Node {
   last_local_request: Option<Instant>,
   /// Record the requests which should refreshing.
   refresh_requests: usize,
+  /// Number of KRPC errors the node answered our requests with.
+  errors: usize,
+  /// The "v" client version of the last message received from the node.
+  client_version: Option<Vec<u8>>,
 }
 
 impl Node {
@@ -51,6 +55,8 @@ impl Node {
       last_request: None,
       last_local_request: None,
       refresh_requests: 0,
+      errors: 0,
+      client_version: None,
     }
   }
 
@@ -67,6 +73,8 @@ impl Node {
       last_request: None,
       last_local_request: None,
       refresh_requests: 0,
+      errors: 0,
+      client_version: None,
     }
   }
 
@@ -79,6 +87,8 @@ impl Node {
       last_request: None,
       last_local_request: None,
       refresh_requests: 0,
+      errors: 0,
+      client_version: None,
     }
   }
 
@@ -96,6 +106,8 @@ impl Node {
           last_request: self.last_request,
           last_local_request: self.last_local_request,
           refresh_requests: 0,
+          errors: self.errors,
+          client_version: self.client_version.take(),
         };
       }
       (NodeStatus::Good, NodeStatus::Questionable) => {}
@@ -129,6 +141,31 @@ impl Node {
     self.last_request = Some(Instant::now());
   }
 
+  /// Record that the node answered one of our requests with an error.
+  pub fn remote_error(&mut self) {
+    self.errors = self.errors.saturating_add(1);
+  }
+
+  /// Number of KRPC errors the node answered our requests with.
+  pub fn error_count(&self) -> usize {
+    self.errors
+  }
+
+  /// Record the "v" client version the node sent us.
+  pub fn set_client_version(&mut self, version: Vec<u8>) {
+    self.client_version = Some(version);
+  }
+
+  /// The "v" client version of the node, if it sent us one.
+  pub fn client_version(&self) -> Option<&[u8]> {
+    self.client_version.as_deref()
+  }
+
+  /// When the node last answered one of our requests.
+  pub fn last_response(&self) -> Option<Instant> {
+    self.last_response
+  }
+
   /// Return true if we have sent this node a request recently.
   pub fn recently_requested_from(&self) -> bool {
     if let Some(time) = self.last_local_request {
@@ -225,6 +262,8 @@ impl std::fmt::Debug for Node {
       .field("last_request", &self.last_request)
       .field("last_response", &self.last_response)
       .field("refresh_requests", &self.refresh_requests)
+      .field("errors", &self.errors)
+      .field("client_version", &self.client_version)
       .finish()
   }
 }
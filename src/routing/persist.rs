@@ -0,0 +1,194 @@
+//! Save the routing table across restarts, so that we do not need to
+//! bootstrap through the routers again.
+
+use std::{
+  cmp::Reverse,
+  fs, io,
+  net::SocketAddr,
+  path::Path,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::id::NodeId;
+
+use super::{node::NodeHandle, table::RoutingTable};
+
+/// Our node id and the nodes of our routing table, as saved in a bencoded
+/// file.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedTable {
+  pub id: NodeId,
+  pub nodes: Vec<SavedNode>,
+}
+
+/// A good or questionable node of a saved routing table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedNode {
+  pub id: NodeId,
+  #[serde(with = "crate::compact::socket_addr")]
+  pub addr: SocketAddr,
+  /// When the node last answered us, in seconds since the unix epoch.
+  pub last_seen: u64,
+}
+
+impl SavedTable {
+  /// Take the good and questionable nodes of the routing table, the most
+  /// recently seen first.
+  pub fn from_table(table: &RoutingTable) -> Self {
+    let now = SystemTime::now();
+
+    let mut nodes: Vec<_> = table
+      .closest_nodes(table.node_id())
+      .map(|node| SavedNode {
+        id: node.id(),
+        addr: node.addr(),
+        last_seen: node
+          .last_response()
+          .and_then(|last_response| now.checked_sub(last_response.elapsed()))
+          .map_or(0, unix_time),
+      })
+      .collect();
+    nodes.sort_by_key(|node| Reverse(node.last_seen));
+
+    SavedTable {
+      id: table.node_id(),
+      nodes,
+    }
+  }
+
+  /// The saved nodes, the most recently seen first.
+  pub fn node_handles(&self) -> Vec<NodeHandle> {
+    let mut nodes = self.nodes.clone();
+    nodes.sort_by_key(|node| Reverse(node.last_seen));
+    nodes
+      .into_iter()
+      .map(|node| NodeHandle::new(node.id, node.addr))
+      .collect()
+  }
+
+  pub fn encode(&self) -> Result<Vec<u8>, serde_bencoded::SerError> {
+    serde_bencoded::to_vec(self)
+  }
+
+  pub fn decode(input: &[u8]) -> Result<Self, serde_bencoded::DeError> {
+    serde_bencoded::from_bytes_auto(input)
+  }
+
+  /// Load the table saved in the given file.
+  pub fn load(path: &Path) -> io::Result<Self> {
+    let bytes = fs::read(path)?;
+    Self::decode(&bytes)
+      .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+  }
+
+  /// Save the table in the given file. The file is replaced at once, so that
+  /// it is never left half written.
+  pub fn save(&self, path: &Path) -> io::Result<()> {
+    let bytes = self
+      .encode()
+      .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, bytes)?;
+    fs::rename(tmp_path, path)
+  }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+  time
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::Ipv4Addr;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::{
+    id::NodeId,
+    routing::{node::Node, table::RoutingTable},
+  };
+
+  use super::{SavedNode, SavedTable};
+
+  #[test]
+  fn encode_decode_saved_table() {
+    let table = SavedTable {
+      id: NodeId::from(*b"0123456789abcdefghij"),
+      nodes: vec![SavedNode {
+        id: NodeId::from(*b"klmnopqrstuvwxyz0123"),
+        addr: (Ipv4Addr::new(127, 0, 0, 1), 6789).into(),
+        last_seen: 1234,
+      }],
+    };
+
+    let encoded = table.encode().unwrap();
+    assert_eq!(
+      encoded,
+      b"d2:id20:0123456789abcdefghij5:nodesld4:addr6:\x7f\x00\x00\x01\x1a\x852:id20:klmnopqrstuvwxyz01239:last_seeni1234eeee"
+    );
+    assert_eq!(SavedTable::decode(&encoded).unwrap(), table);
+  }
+
+  #[test]
+  fn saved_table_from_table() {
+    let mut table = RoutingTable::new(NodeId::from([0u8; 20]));
+    let good = NodeId::from([1u8; 20]);
+    let questionable = NodeId::from([2u8; 20]);
+    let bad = NodeId::from([3u8; 20]);
+    table
+      .add_node(Node::as_good(good, (Ipv4Addr::new(127, 0, 0, 1), 1).into()));
+    table.add_node(Node::as_questionable(
+      questionable,
+      (Ipv4Addr::new(127, 0, 0, 2), 2).into(),
+    ));
+    table.add_node(Node::as_bad(bad, (Ipv4Addr::new(127, 0, 0, 3), 3).into()));
+
+    let saved = SavedTable::from_table(&table);
+    assert_eq!(saved.id, table.node_id());
+    assert_eq!(
+      saved
+        .node_handles()
+        .iter()
+        .map(|node| node.id)
+        .collect::<Vec<_>>(),
+      vec![good, questionable]
+    );
+  }
+
+  #[test]
+  fn save_load_saved_table() {
+    let dir = std::env::temp_dir()
+      .join(format!("bt-rust-dht-persist-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // The files of the two instances of a dual-stack DHT, which must not
+    // share their temporary file.
+    let v4 = SavedTable {
+      id: NodeId::from([1u8; 20]),
+      nodes: Vec::new(),
+    };
+    let v6 = SavedTable {
+      id: NodeId::from([6u8; 20]),
+      nodes: Vec::new(),
+    };
+    v4.save(&dir.join("state")).unwrap();
+    v6.save(&dir.join("state.v6")).unwrap();
+
+    assert_eq!(SavedTable::load(&dir.join("state")).unwrap(), v4);
+    assert_eq!(SavedTable::load(&dir.join("state.v6")).unwrap(), v6);
+    let mut files = std::fs::read_dir(&dir)
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name())
+      .collect::<Vec<_>>();
+    files.sort();
+    assert_eq!(files, vec!["state", "state.v6"]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -1,4 +1,4 @@
-use std::{iter::Filter, net::SocketAddr, slice::Iter};
+use std::{collections::HashMap, iter::Filter, net::SocketAddr, slice::Iter};
 
 use crate::id::{NodeId, ID_LEN};
 
@@ -43,7 +43,7 @@ impl RoutingTable {
   /// The closeness of nodes has a maximum granularity of a bucket.
   /// For most use cases this is fine since we will usually be performing
   /// lookups and aggregating a number of results equal to the size of a bucket.
-  pub fn closest_nodes(&self, node_id: NodeId) -> ClosestNodes {
+  pub fn closest_nodes(&self, node_id: NodeId) -> ClosestNodes<'_> {
     ClosestNodes::new(&self.buckets, self.node_id, node_id)
   }
 
@@ -65,6 +65,17 @@ impl RoutingTable {
       .count()
   }
 
+  /// Number of nodes in the RoutingTable per "v" client version.
+  pub fn client_versions(&self) -> HashMap<Vec<u8>, usize> {
+    let mut versions = HashMap::new();
+    for node in self.closest_nodes(self.node_id()) {
+      if let Some(version) = node.client_version() {
+        *versions.entry(version.to_vec()).or_insert(0) += 1;
+      }
+    }
+    versions
+  }
+
   /// Number of questionable nodes in the RoutingTable.
   pub fn num_questionable_node(&self) -> usize {
     self
@@ -74,7 +85,7 @@ impl RoutingTable {
   }
 
   /// Iterator over all buckets in the routing table.
-  pub fn buckets(&self) -> impl Iterator<Item = &Bucket> + ExactSizeIterator {
+  pub fn buckets(&self) -> impl ExactSizeIterator<Item = &Bucket> {
     self.buckets.iter()
   }
 
@@ -97,6 +108,19 @@ impl RoutingTable {
     bucket.ping_able_nodes_mut().find(|n| n.handle() == node)
   }
 
+  /// Find a mutable reference to the node with the given address, for when we
+  /// do not know its id.
+  pub fn find_node_by_addr_mut(
+    &mut self,
+    addr: SocketAddr,
+  ) -> Option<&mut Node> {
+    self
+      .buckets
+      .iter_mut()
+      .flat_map(|bucket| bucket.ping_able_nodes_mut())
+      .find(|n| n.addr() == addr)
+  }
+
   fn bucket_index_for_node(&self, node_id: NodeId) -> usize {
     let bucket_index = leading_bit_count(self.node_id, node_id);
 
@@ -349,7 +373,7 @@ fn precomputed_assorted_nodes(
 ///
 /// Filter the good nodes(not including the last bucket(unsorted bucket table) because
 /// that may contain assorted nodes(different ideal bucket index)).
-fn bucket_iterator(buckets: &[Bucket], index: usize) -> Option<GoodNodes> {
+fn bucket_iterator(buckets: &[Bucket], index: usize) -> Option<GoodNodes<'_>> {
   if buckets.len() == MAX_BUCKETS {
     buckets
   } else {
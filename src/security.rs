@@ -0,0 +1,231 @@
+//! DHT security extension.
+//!
+//! Reference link:
+//!   http://bittorrent.org/beps/bep_0042.html
+//!
+//! The node id of a node is tied to its external ip address, so an attacker
+//! can not freely choose where in the keyspace its nodes are placed.
+//!
+//! The first 21 bits of the node id are derived from the `crc32c` of the
+//! masked ip address (with 3 random bits `r` mixed in), and the last byte of
+//! the node id stores `r` so that other nodes can verify the id.
+//!
+//! ```text
+//!  byte:    0      1      2       3 .. 18       19
+//! +------+------+-------+---------------+------+
+//! |  crc32c(ip) | crc | |    random     |  r   |
+//! +------+------+-------+---------------+------+
+//!    8 bits 8 bits 5 bits
+//! ```
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::id::{NodeId, NODE_ID_LEN};
+
+const IPV4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const IPV6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+/// Reversed polynomial of the crc32c (Castagnoli) checksum.
+const CRC32C_POLY: u32 = 0x82f6_3b78;
+
+/// Generate a node id which is valid for the given external ip address.
+pub fn generate_secure_id(ip: IpAddr) -> NodeId {
+  let mut random = [0u8; NODE_ID_LEN];
+  rand::Rng::fill(&mut rand::thread_rng(), &mut random);
+
+  secure_id_from_parts(ip, random)
+}
+
+/// Build a secure node id from the ip address and the random bytes.
+///
+/// Only the low 3 bits of the last random byte are used as `r`, the
+/// remaining random bytes fill the part of the id not covered by the crc.
+fn secure_id_from_parts(ip: IpAddr, random: [u8; NODE_ID_LEN]) -> NodeId {
+  let r = random[NODE_ID_LEN - 1] & 0x07;
+  let crc = ip_crc(ip, r);
+
+  let mut id = random;
+  id[0] = (crc >> 24) as u8;
+  id[1] = (crc >> 16) as u8;
+  id[2] = ((crc >> 8) as u8 & 0xf8) | (random[2] & 0x07);
+
+  id.into()
+}
+
+/// Return true if the node id conforms to the ip address it was seen on.
+///
+/// Local network addresses are exempt from the check, any id is valid for them.
+pub fn is_secure_id(id: &NodeId, ip: IpAddr) -> bool {
+  if is_exempt(ip) {
+    return true;
+  }
+
+  let id = id.as_ref();
+  let crc = ip_crc(ip, id[NODE_ID_LEN - 1] & 0x07);
+
+  id[0] == (crc >> 24) as u8
+    && id[1] == (crc >> 16) as u8
+    && id[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+}
+
+/// Return true if the ip address is not subject to the node id restriction,
+/// which is the case for loopback and local network addresses.
+pub fn is_exempt(ip: IpAddr) -> bool {
+  match ip {
+    IpAddr::V4(v4) => is_exempt_v4(v4),
+    IpAddr::V6(v6) => is_exempt_v6(v6),
+  }
+}
+
+fn is_exempt_v4(ip: Ipv4Addr) -> bool {
+  ip.is_unspecified()
+    || ip.is_loopback()
+    || ip.is_private()
+    || ip.is_link_local()
+}
+
+fn is_exempt_v6(ip: Ipv6Addr) -> bool {
+  let first = ip.segments()[0];
+
+  ip.is_unspecified()
+    || ip.is_loopback()
+    // unique local (fc00::/7)
+    || first & 0xfe00 == 0xfc00
+    // link local (fe80::/10)
+    || first & 0xffc0 == 0xfe80
+}
+
+/// Checksum of the masked ip address with `r` mixed into the first byte.
+fn ip_crc(ip: IpAddr, r: u8) -> u32 {
+  match ip {
+    IpAddr::V4(v4) => {
+      let mut bytes = v4.octets();
+      mask_ip(&mut bytes, &IPV4_MASK, r);
+      crc32c(&bytes)
+    }
+    IpAddr::V6(v6) => {
+      let mut bytes = [0u8; 8];
+      bytes.copy_from_slice(&v6.octets()[..8]);
+      mask_ip(&mut bytes, &IPV6_MASK, r);
+      crc32c(&bytes)
+    }
+  }
+}
+
+fn mask_ip(bytes: &mut [u8], mask: &[u8], r: u8) {
+  for (byte, mask) in bytes.iter_mut().zip(mask) {
+    *byte &= mask;
+  }
+  bytes[0] |= r << 5;
+}
+
+fn crc32c(bytes: &[u8]) -> u32 {
+  let mut crc = !0u32;
+
+  for byte in bytes {
+    crc ^= u32::from(*byte);
+
+    for _ in 0..8 {
+      crc = if crc & 1 == 1 {
+        (crc >> 1) ^ CRC32C_POLY
+      } else {
+        crc >> 1
+      };
+    }
+  }
+
+  !crc
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+  use crate::id::{NodeId, NODE_ID_LEN};
+  use crate::security;
+  use crate::test;
+  use pretty_assertions::assert_eq;
+
+  // (ip, rand, expected first 3 bytes of the node id), from the BEP.
+  const VECTORS: [([u8; 4], u8, [u8; 3]); 5] = [
+    ([124, 31, 75, 21], 1, [0x5f, 0xbf, 0xbf]),
+    ([21, 75, 31, 124], 86, [0x5a, 0x3c, 0xe9]),
+    ([65, 23, 51, 170], 22, [0xa5, 0xd4, 0x32]),
+    ([84, 124, 73, 14], 65, [0x1b, 0x03, 0x21]),
+    ([43, 213, 53, 83], 90, [0xe5, 0x6f, 0x6c]),
+  ];
+
+  #[test]
+  fn positive_crc32c_check_value() {
+    assert_eq!(security::crc32c(b"123456789"), 0xe306_9283);
+  }
+
+  #[test]
+  fn positive_generate_bep_vectors() {
+    for (ip, rand, prefix) in VECTORS {
+      let mut random = [0u8; NODE_ID_LEN];
+      random[2] = prefix[2] & 0x07;
+      random[NODE_ID_LEN - 1] = rand;
+
+      let id =
+        security::secure_id_from_parts(Ipv4Addr::from(ip).into(), random);
+
+      assert_eq!(&id.as_ref()[..3], &prefix[..]);
+      assert_eq!(id.as_ref()[NODE_ID_LEN - 1], rand);
+    }
+  }
+
+  #[test]
+  fn positive_validate_bep_vectors() {
+    for (ip, rand, prefix) in VECTORS {
+      let mut bytes = [0u8; NODE_ID_LEN];
+      bytes[..3].copy_from_slice(&prefix);
+      bytes[NODE_ID_LEN - 1] = rand;
+
+      assert!(security::is_secure_id(
+        &NodeId::from(bytes),
+        Ipv4Addr::from(ip).into()
+      ));
+    }
+  }
+
+  #[test]
+  fn positive_generated_ids_are_secure() {
+    let v4 = IpAddr::V4(Ipv4Addr::new(124, 31, 75, 21));
+    let v6 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 3, 4, 5, 6));
+
+    for _ in 0..16 {
+      assert!(security::is_secure_id(
+        &security::generate_secure_id(v4),
+        v4
+      ));
+      assert!(security::is_secure_id(
+        &security::generate_secure_id(v6),
+        v6
+      ));
+    }
+  }
+
+  #[test]
+  fn negative_random_id_for_public_ip() {
+    let ip = IpAddr::V4(Ipv4Addr::new(124, 31, 75, 21));
+
+    assert!(!security::is_secure_id(&test::dummy_node_id(), ip));
+  }
+
+  #[test]
+  fn positive_local_ip_is_exempt() {
+    assert!(security::is_secure_id(
+      &test::dummy_node_id(),
+      test::dummy_ipv4_addr()
+    ));
+    assert!(security::is_secure_id(
+      &test::dummy_node_id(),
+      IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))
+    ));
+    assert!(security::is_secure_id(
+      &test::dummy_node_id(),
+      IpAddr::V6(Ipv6Addr::LOCALHOST)
+    ));
+  }
+}
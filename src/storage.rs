@@ -4,9 +4,17 @@ use std::{
   time::{Duration, Instant},
 };
 
-use crate::id::InfoHash;
+use rand::seq::IteratorRandom;
+
+use crate::{
+  bloom::BloomFilter,
+  id::{Id, InfoHash},
+  item::{Item, ItemError, MutableItem},
+  message::Value,
+};
 
 const MAX_ITEMS_STORED: usize = 500;
+const MAX_DATA_ITEMS_STORED: usize = 500;
 
 /// Storing the Announce Item mapping with its InfoHash.
 pub struct AnnounceStorage {
@@ -23,8 +31,15 @@ impl AnnounceStorage {
   }
 
   /// Returns true if the item was added or it's existing expiration updated, false otherwise.
-  pub fn add_item(&mut self, info_hash: InfoHash, address: SocketAddr) -> bool {
-    self.add(info_hash, address, Instant::now())
+  ///
+  /// `seed` tells whether the peer has the complete torrent (BEP33).
+  pub fn add_item(
+    &mut self,
+    info_hash: InfoHash,
+    address: SocketAddr,
+    seed: bool,
+  ) -> bool {
+    self.add(info_hash, address, seed, Instant::now())
   }
 
   /// Add the contact.
@@ -34,12 +49,13 @@ impl AnnounceStorage {
     &mut self,
     info_hash: InfoHash,
     address: SocketAddr,
+    seed: bool,
     current_time: Instant,
   ) -> bool {
     // Clear out any old contacts that we have sorted
     self.remove_expired_items(current_time);
 
-    let item = AnnounceItem::new(info_hash, address);
+    let item = AnnounceItem::new(info_hash, address, seed);
     let item_expiration = item.expiration();
 
     // Check if we already have the item and want to update
@@ -89,6 +105,67 @@ impl AnnounceStorage {
       .map(|item| item.address())
   }
 
+  /// Same as `find_items`, leaving out the peers which are seeds (BEP33).
+  pub fn find_non_seeds<'a>(
+    &'a mut self,
+    info_hash: &'_ InfoHash,
+  ) -> impl Iterator<Item = SocketAddr> + 'a {
+    self.remove_expired_items(Instant::now());
+
+    self
+      .storage
+      .get(info_hash)
+      .into_iter()
+      .flatten()
+      .filter(|item| !item.is_seed())
+      .map(|item| item.address())
+  }
+
+  /// Build the bloom filters of the seeds and of the other peers of this
+  /// info_hash (BEP33).
+  pub fn scrape(&mut self, info_hash: &InfoHash) -> (BloomFilter, BloomFilter) {
+    self.remove_expired_items(Instant::now());
+
+    let mut seeds = BloomFilter::new();
+    let mut peers = BloomFilter::new();
+
+    for item in self.storage.get(info_hash).into_iter().flatten() {
+      if item.is_seed() {
+        seeds.insert(item.address().ip());
+      } else {
+        peers.insert(item.address().ip());
+      }
+    }
+
+    (seeds, peers)
+  }
+
+  /// Pick a random sample of the info hashes which have announce items (BEP51).
+  ///
+  /// Returns the sample along with the total number of info hashes.
+  pub fn sample_info_hashes(
+    &mut self,
+    max_samples: usize,
+  ) -> (Vec<InfoHash>, usize) {
+    self.sample(max_samples, Instant::now())
+  }
+
+  fn sample(
+    &mut self,
+    max_samples: usize,
+    current_time: Instant,
+  ) -> (Vec<InfoHash>, usize) {
+    self.remove_expired_items(current_time);
+
+    let samples = self
+      .storage
+      .keys()
+      .copied()
+      .choose_multiple(&mut rand::thread_rng(), max_samples);
+
+    (samples, self.storage.len())
+  }
+
   /// Accepting a announce item, meaning this the life time of this contact.
   ///
   /// Check the existence of this announce item and whether here will overflow the capacity if inserting it.
@@ -97,7 +174,14 @@ impl AnnounceStorage {
 
     let already_in_list =
       if let Some(items) = self.storage.get_mut(&item_info_hash) {
-        items.iter().any(|a| a == &item)
+        match items.iter_mut().find(|a| *a == &item) {
+          Some(existing) => {
+            // The peer may have completed the torrent since it last announced.
+            existing.seed = item.seed;
+            true
+          }
+          None => false,
+        }
       } else {
         false
       };
@@ -164,15 +248,21 @@ impl Default for AnnounceStorage {
 // -------------------------- //
 
 /// Warping a expiration item.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Eq)]
 struct AnnounceItem {
   expiration: ItemExpiration,
+  seed: bool,
 }
 
 impl AnnounceItem {
-  pub fn new(info_hash: InfoHash, address: SocketAddr) -> AnnounceItem {
+  pub fn new(
+    info_hash: InfoHash,
+    address: SocketAddr,
+    seed: bool,
+  ) -> AnnounceItem {
     AnnounceItem {
       expiration: ItemExpiration::new(info_hash, address),
+      seed,
     }
   }
 
@@ -187,6 +277,17 @@ impl AnnounceItem {
   pub fn info_hash(&self) -> InfoHash {
     self.expiration.info_hash()
   }
+
+  pub fn is_seed(&self) -> bool {
+    self.seed
+  }
+}
+
+impl PartialEq for AnnounceItem {
+  // The same peer announcing again is the same item, whether it is a seed or not.
+  fn eq(&self, other: &Self) -> bool {
+    self.expiration == other.expiration
+  }
 }
 
 // -------------------------- //
@@ -228,12 +329,139 @@ impl PartialEq for ItemExpiration {
   }
 }
 
+// -------------------------- //
+
+const DATA_ITEM_EXPIRATION_TIME: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// Storing the data items (BEP44) with their target.
+pub struct ItemStorage {
+  storage: HashMap<Id, DataItem>,
+}
+
+impl ItemStorage {
+  pub fn new() -> ItemStorage {
+    ItemStorage {
+      storage: HashMap::new(),
+    }
+  }
+
+  /// Returns true if the item was added or it's existing expiration updated, false otherwise.
+  pub fn add_item(&mut self, target: Id, value: Value) -> bool {
+    self.add(target, value, Instant::now())
+  }
+
+  fn add(&mut self, target: Id, value: Value, current_time: Instant) -> bool {
+    // Clear out any old items that we have stored
+    self.remove_expired_items(current_time);
+
+    self.insert(target, Item::Immutable(value), current_time)
+  }
+
+  /// Add or replace the mutable item, the signature must already be verified.
+  ///
+  /// An item only replaces a stored one with a lower sequence number, and if
+  /// `cas` is given, only if the stored one has that sequence number.
+  ///
+  /// Returns Ok(false) if the item can not be stored because the storage is full.
+  pub fn add_mutable_item(
+    &mut self,
+    item: MutableItem,
+    cas: Option<i64>,
+  ) -> Result<bool, ItemError> {
+    self.add_mutable(item, cas, Instant::now())
+  }
+
+  fn add_mutable(
+    &mut self,
+    item: MutableItem,
+    cas: Option<i64>,
+    current_time: Instant,
+  ) -> Result<bool, ItemError> {
+    self.remove_expired_items(current_time);
+
+    let target = item.target();
+
+    if let Some(Item::Mutable(stored)) =
+      self.storage.get(&target).map(|data| &data.item)
+    {
+      if cas.is_some_and(|cas| cas != stored.seq) {
+        return Err(ItemError::CasMismatch);
+      }
+
+      // The same version can be stored again to renew it.
+      if item.seq < stored.seq
+        || (item.seq == stored.seq && item.value != stored.value)
+      {
+        return Err(ItemError::SequenceTooLow);
+      }
+    }
+
+    Ok(self.insert(target, Item::Mutable(item), current_time))
+  }
+
+  fn insert(&mut self, target: Id, item: Item, current_time: Instant) -> bool {
+    if !self.storage.contains_key(&target)
+      && self.storage.len() >= MAX_DATA_ITEMS_STORED
+    {
+      return false;
+    }
+
+    self.storage.insert(
+      target,
+      DataItem {
+        item,
+        inserted: current_time,
+      },
+    );
+
+    true
+  }
+
+  /// Find the item stored under the target, if it has not expired.
+  pub fn find_item(&mut self, target: &Id) -> Option<&Item> {
+    self.find(target, Instant::now())
+  }
+
+  fn find(&mut self, target: &Id, current_time: Instant) -> Option<&Item> {
+    self.remove_expired_items(current_time);
+
+    self.storage.get(target).map(|data| &data.item)
+  }
+
+  /// Prunes all expired items.
+  fn remove_expired_items(&mut self, current_time: Instant) {
+    self
+      .storage
+      .retain(|_, item| !item.is_expired(current_time));
+  }
+}
+
+impl Default for ItemStorage {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+struct DataItem {
+  item: Item,
+  inserted: Instant,
+}
+
+impl DataItem {
+  fn is_expired(&self, now: Instant) -> bool {
+    now - self.inserted >= DATA_ITEM_EXPIRATION_TIME
+  }
+}
+
 #[cfg(test)]
 mod tests {
+  use std::net::{Ipv4Addr, SocketAddr};
   use std::time::Instant;
 
-  use crate::id::INFO_HASH_LEN;
-  use crate::storage::{self, AnnounceStorage};
+  use crate::id::{Id, INFO_HASH_LEN};
+  use crate::item::{Item, ItemError, MutableItem, SigningKey};
+  use crate::message::Value;
+  use crate::storage::{self, AnnounceStorage, ItemStorage};
   use crate::test;
   use pretty_assertions::assert_eq;
 
@@ -243,7 +471,7 @@ mod tests {
     let info_hash = [0u8; INFO_HASH_LEN].into();
     let sock_addr = test::dummy_socket_addr_v4();
 
-    assert!(announce_store.add_item(info_hash, sock_addr));
+    assert!(announce_store.add_item(info_hash, sock_addr, false));
 
     let items: Vec<_> = announce_store.find_items(&info_hash).collect();
     assert_eq!(items.len(), 1);
@@ -259,7 +487,7 @@ mod tests {
       test::dummy_block_socket_address(storage::MAX_ITEMS_STORED as u16);
 
     for sock_addr in sock_address.iter() {
-      assert!(announce_store.add_item(info_hash, *sock_addr));
+      assert!(announce_store.add_item(info_hash, *sock_addr, false));
     }
 
     let items: Vec<_> = announce_store.find_items(&info_hash).collect();
@@ -278,22 +506,25 @@ mod tests {
       test::dummy_block_socket_address((storage::MAX_ITEMS_STORED + 1) as u16);
 
     for sock_addr in sock_address.iter().take(storage::MAX_ITEMS_STORED) {
-      assert!(announce_store.add_item(info_hash, *sock_addr));
+      assert!(announce_store.add_item(info_hash, *sock_addr, false));
     }
 
     // Try to add a new item
     let other_info_hash = [1u8; INFO_HASH_LEN].into();
 
     // Returns false because it wasn't added
-    assert!(!announce_store
-      .add_item(other_info_hash, sock_address[sock_address.len() - 1]));
+    assert!(!announce_store.add_item(
+      other_info_hash,
+      sock_address[sock_address.len() - 1],
+      false
+    ));
     // Iterator is empty because it wasn't added
     let count = announce_store.find_items(&other_info_hash).count();
     assert_eq!(count, 0);
 
     // Try to add all of the initial nodes again (renew)
     for sock_addr in sock_address.iter().take(storage::MAX_ITEMS_STORED) {
-      assert!(announce_store.add_item(info_hash, *sock_addr));
+      assert!(announce_store.add_item(info_hash, *sock_addr, false));
     }
   }
 
@@ -306,15 +537,18 @@ mod tests {
 
     // Fill up the announce storage completely
     for sock_addr in sock_address.iter().take(storage::MAX_ITEMS_STORED) {
-      assert!(announce_store.add_item(info_hash, *sock_addr));
+      assert!(announce_store.add_item(info_hash, *sock_addr, false));
     }
 
     // Try to add a new item into the storage (under a different info hash)
     let other_info_hash = [1u8; INFO_HASH_LEN].into();
 
     // Returned false because it wasn't added
-    assert!(!announce_store
-      .add_item(other_info_hash, sock_address[sock_address.len() - 1]));
+    assert!(!announce_store.add_item(
+      other_info_hash,
+      sock_address[sock_address.len() - 1],
+      false
+    ));
     // Iterator is empty because it wasn't added
     let count = announce_store.find_items(&other_info_hash).count();
     assert_eq!(count, 0);
@@ -324,6 +558,7 @@ mod tests {
     assert!(announce_store.add(
       other_info_hash,
       sock_address[sock_address.len() - 1],
+      false,
       mock_current_time
     ));
     // Iterator is not empty because it was added
@@ -342,7 +577,7 @@ mod tests {
     // Fill up first info hash
     let num_contacts_first = storage::MAX_ITEMS_STORED / 2;
     for sock_addr in sock_address.iter().take(num_contacts_first) {
-      assert!(announce_store.add_item(info_hash_one, *sock_addr));
+      assert!(announce_store.add_item(info_hash_one, *sock_addr, false));
     }
 
     // Fill up second info hash
@@ -352,13 +587,16 @@ mod tests {
       .skip(num_contacts_first)
       .take(num_contacts_second)
     {
-      assert!(announce_store.add_item(info_hash_two, *sock_addr));
+      assert!(announce_store.add_item(info_hash_two, *sock_addr, false));
     }
 
     // Try to add a third info hash with a contact
     let info_hash_three = [2u8; INFO_HASH_LEN].into();
-    assert!(!announce_store
-      .add_item(info_hash_three, sock_address[sock_address.len() - 1]));
+    assert!(!announce_store.add_item(
+      info_hash_three,
+      sock_address[sock_address.len() - 1],
+      false
+    ));
     // Iterator is empty because it was not added
     let count = announce_store.find_items(&info_hash_three).count();
     assert_eq!(count, 0);
@@ -368,10 +606,165 @@ mod tests {
     assert!(announce_store.add(
       info_hash_three,
       sock_address[sock_address.len() - 1],
+      false,
       mock_current_time
     ));
     // Iterator is not empty because it was added
     let count = announce_store.find_items(&info_hash_three).count();
     assert_eq!(count, 1);
   }
+
+  #[test]
+  fn positive_scrape_seeds_and_peers() {
+    let mut announce_store = AnnounceStorage::new();
+    let info_hash = [0u8; INFO_HASH_LEN].into();
+    // The filters count distinct IP addresses.
+    let sock_address: Vec<SocketAddr> = (1..=3)
+      .map(|i| (Ipv4Addr::new(10, 0, 0, i), 6881).into())
+      .collect();
+
+    assert!(announce_store.add_item(info_hash, sock_address[0], true));
+    assert!(announce_store.add_item(info_hash, sock_address[1], false));
+    assert!(announce_store.add_item(info_hash, sock_address[2], false));
+
+    let (seeds, peers) = announce_store.scrape(&info_hash);
+    assert_eq!(seeds.estimate(), 1);
+    assert_eq!(peers.estimate(), 2);
+
+    let non_seeds: Vec<_> = announce_store.find_non_seeds(&info_hash).collect();
+    assert_eq!(non_seeds, vec![sock_address[1], sock_address[2]]);
+  }
+
+  #[test]
+  fn positive_announce_again_as_seed() {
+    let mut announce_store = AnnounceStorage::new();
+    let info_hash = [0u8; INFO_HASH_LEN].into();
+    let sock_addr = test::dummy_socket_addr_v4();
+
+    assert!(announce_store.add_item(info_hash, sock_addr, false));
+    assert!(announce_store.add_item(info_hash, sock_addr, true));
+
+    assert_eq!(announce_store.find_items(&info_hash).count(), 1);
+    assert_eq!(announce_store.find_non_seeds(&info_hash).count(), 0);
+  }
+
+  #[test]
+  fn positive_add_and_retrieve_data_item() {
+    let mut item_store = ItemStorage::new();
+    let target = Id::sha1(b"12:Hello World!");
+    let value = Value::Bytes(b"Hello World!".to_vec());
+
+    assert!(item_store.add_item(target, value.clone()));
+    assert_eq!(item_store.find_item(&target), Some(&Item::Immutable(value)));
+
+    let other_target = [1u8; INFO_HASH_LEN].into();
+    assert_eq!(item_store.find_item(&other_target), None);
+  }
+
+  #[test]
+  fn positive_full_data_storage_expire_items() {
+    let mut item_store = ItemStorage::new();
+    let value = Value::Int(0);
+
+    for index in 0..storage::MAX_DATA_ITEMS_STORED {
+      let target = Id::sha1(&index.to_be_bytes());
+      assert!(item_store.add_item(target, value.clone()));
+    }
+
+    // Returns false because the storage is full
+    let other_target = [1u8; INFO_HASH_LEN].into();
+    assert!(!item_store.add_item(other_target, value.clone()));
+    assert_eq!(item_store.find_item(&other_target), None);
+
+    // Existing items can still be renewed
+    let target = Id::sha1(&0usize.to_be_bytes());
+    assert!(item_store.add_item(target, value.clone()));
+
+    // Add it mocking the current time, all the other items have expired
+    let mock_current_time = Instant::now() + storage::DATA_ITEM_EXPIRATION_TIME;
+    assert!(item_store.add(other_target, value.clone(), mock_current_time));
+    assert_eq!(item_store.storage.len(), 1);
+    assert_eq!(
+      item_store.find_item(&other_target),
+      Some(&Item::Immutable(value))
+    );
+  }
+
+  #[test]
+  fn positive_sample_info_hashes() {
+    let mut announce_store = AnnounceStorage::new();
+    let sock_addr = test::dummy_socket_addr_v4();
+
+    for index in 0..10u8 {
+      let info_hash = [index; INFO_HASH_LEN].into();
+      assert!(announce_store.add_item(info_hash, sock_addr, false));
+    }
+
+    let (samples, num) = announce_store.sample_info_hashes(4);
+    assert_eq!(num, 10);
+    assert_eq!(samples.len(), 4);
+
+    let (samples, num) = announce_store.sample_info_hashes(20);
+    assert_eq!(num, 10);
+    assert_eq!(samples.len(), 10);
+
+    // Expired items are not sampled
+    let mock_current_time = Instant::now() + storage::EXPIRATION_TIME;
+    assert_eq!(announce_store.sample(20, mock_current_time), (vec![], 0));
+  }
+
+  fn mutable_item(seq: i64, value: &[u8]) -> MutableItem {
+    let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+
+    MutableItem::sign(Value::Bytes(value.to_vec()), &signing_key, seq, None)
+      .unwrap()
+  }
+
+  #[test]
+  fn positive_replace_mutable_item() {
+    let mut item_store = ItemStorage::new();
+    let item = mutable_item(1, b"one");
+    let target = item.target();
+
+    assert!(item_store.add_mutable_item(item.clone(), None).unwrap());
+    // Storing the same version again renews it
+    assert!(item_store.add_mutable_item(item, None).unwrap());
+
+    let item = mutable_item(2, b"two");
+    assert!(item_store.add_mutable_item(item.clone(), Some(1)).unwrap());
+    assert_eq!(item_store.find_item(&target), Some(&Item::Mutable(item)));
+  }
+
+  #[test]
+  fn negative_mutable_item_sequence_too_low() {
+    let mut item_store = ItemStorage::new();
+    let item = mutable_item(2, b"two");
+    let target = item.target();
+
+    assert!(item_store.add_mutable_item(item.clone(), None).unwrap());
+
+    assert!(matches!(
+      item_store.add_mutable_item(mutable_item(1, b"one"), None),
+      Err(ItemError::SequenceTooLow)
+    ));
+    assert!(matches!(
+      item_store.add_mutable_item(mutable_item(2, b"other"), None),
+      Err(ItemError::SequenceTooLow)
+    ));
+    assert_eq!(item_store.find_item(&target), Some(&Item::Mutable(item)));
+  }
+
+  #[test]
+  fn negative_mutable_item_cas_mismatch() {
+    let mut item_store = ItemStorage::new();
+
+    assert!(item_store
+      .add_mutable_item(mutable_item(2, b"two"), None)
+      .unwrap());
+
+    assert!(matches!(
+      item_store.add_mutable_item(mutable_item(3, b"three"), Some(1)),
+      Err(ItemError::CasMismatch)
+    ));
+  }
 }
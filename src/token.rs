@@ -177,7 +177,7 @@ fn generate_token_from_addr(addr: IpAddr, secret: u32) -> Token {
 /// Generate a token from an ipv4 and a secret.
 ///
 /// The token for addr-v4 format:
-/// ```
+/// ```text
 ///        8 bytes
 /// |  ip_v4  | secret |
 ///   4 bytes   4 bytes
@@ -199,7 +199,7 @@ fn generate_token_from_addr_v4(v4_addr: Ipv4Addr, secret: u32) -> Token {
 /// Generate a token from an ipv6 and a secret.
 ///
 /// The token for addr-v6
-/// ```
+/// ```text
 ///     20 bytes
 /// |   ip_v6   | secret |
 ///   16 bytes   4 bytes
@@ -30,7 +30,7 @@
 //! The pre-allocation strategy is used both on the `action id` level as
 //! well as the `message id` level.
 //!
-//! ```
+//! ```text
 //! +---------------+----------+
 //! |   action id   |message id|
 //! +---------------+----------+
@@ -228,7 +228,7 @@ fn generate_mids(next_alloc: u64) -> (u64, [u64; MESSAGE_ID_PRE_ALLOC_LEN]) {
 
 /// The transaction id format would like:
 ///
-/// ```
+/// ```text
 /// +---------------+----------+
 /// |   action id   |message id|
 /// +---------------+----------+
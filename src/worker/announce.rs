@@ -0,0 +1,248 @@
+use std::{
+  collections::HashMap,
+  time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::{id::InfoHash, routing::node::NodeHandle};
+
+use super::{timer::Timeout, AnnounceOutcome, AnnounceResult, AnnounceStatus};
+
+/// How long to wait before announcing again, the nodes forget the peers they
+/// did not hear from for about 30 minutes.
+const REANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// How long to wait before trying again when no node accepted the announce.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// How long we use the tokens the nodes gave us, BitTorrent nodes accept
+/// tokens up to ten minutes old but only guarantee five.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(5 * 60);
+/// How long we ask the closest nodes of a search for new tokens instead of
+/// searching again, the nodes which joined closer to the info hash since then
+/// only get our announces after the next search.
+const NODE_LIFETIME: Duration = Duration::from_secs(30 * 60);
+
+/// An info hash we announce ourselves for periodically.
+pub struct PeriodicAnnounce {
+  port: u16,
+  // The id of the announce in progress, if any.
+  round: Option<u64>,
+  // When the next announce starts, if none is in progress.
+  next: Option<(Instant, Timeout)>,
+  last_success: Option<Instant>,
+  last_results: Vec<AnnounceResult>,
+}
+
+impl PeriodicAnnounce {
+  pub fn new(port: u16) -> PeriodicAnnounce {
+    PeriodicAnnounce {
+      port,
+      round: None,
+      next: None,
+      last_success: None,
+      last_results: Vec::new(),
+    }
+  }
+
+  pub fn port(&self) -> u16 {
+    self.port
+  }
+
+  /// Use another port, starting with the next announce.
+  pub fn set_port(&mut self, port: u16) {
+    self.port = port;
+  }
+
+  pub fn start_round(&mut self, round: u64) {
+    self.round = Some(round);
+    self.next = None;
+  }
+
+  /// Record what the nodes answered to the announce `round`. Returns how long
+  /// to wait before the next one, or `None` if the announce is not the one in
+  /// progress.
+  pub fn finish_round(
+    &mut self,
+    round: u64,
+    results: Vec<AnnounceResult>,
+  ) -> Option<Duration> {
+    if self.round != Some(round) {
+      return None;
+    }
+    self.round = None;
+
+    let accepted = results
+      .iter()
+      .any(|result| result.outcome == AnnounceOutcome::Accepted);
+    if accepted {
+      self.last_success = Some(Instant::now());
+    }
+    self.last_results = results;
+
+    if accepted {
+      Some(jittered(REANNOUNCE_INTERVAL))
+    } else {
+      Some(jittered(RETRY_INTERVAL))
+    }
+  }
+
+  pub fn schedule(&mut self, delay: Duration, timeout: Timeout) {
+    self.next = Some((Instant::now() + delay, timeout));
+  }
+
+  /// The timeout of the next announce, to cancel it.
+  pub fn take_timeout(&mut self) -> Option<Timeout> {
+    self.next.take().map(|(_, timeout)| timeout)
+  }
+
+  pub fn status(&self) -> AnnounceStatus {
+    AnnounceStatus {
+      port: self.port,
+      announcing: self.round.is_some(),
+      last_success: self.last_success,
+      last_results: self.last_results.clone(),
+      next_announce: self.next.map(|(at, _)| at),
+    }
+  }
+}
+
+/// The closest nodes which gave us an announce token with their token, the
+/// closest first.
+pub type ClosestTokens = Vec<(NodeHandle, Vec<u8>)>;
+
+/// What we know of the closest nodes of an info hash searched recently.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum CachedNodes {
+  /// Their tokens are fresh, announce with them right away.
+  Tokens(ClosestTokens),
+  /// Their tokens are too old, ask them for new ones first.
+  Nodes(Vec<NodeHandle>),
+}
+
+struct CacheEntry {
+  searched_at: Instant,
+  received_at: Instant,
+  tokens: ClosestTokens,
+}
+
+/// The announce tokens of the nodes closest to the info hashes searched
+/// recently, so the next announces can skip the search.
+#[derive(Default)]
+pub struct TokenCache {
+  entries: HashMap<InfoHash, CacheEntry>,
+}
+
+impl TokenCache {
+  /// Keep the tokens of the closest nodes found by a search.
+  pub fn insert(&mut self, info_hash: InfoHash, tokens: ClosestTokens) {
+    self.put(info_hash, Instant::now(), tokens);
+  }
+
+  /// Keep the new tokens the closest nodes of a previous search gave us,
+  /// still searching again once the nodes are too old.
+  pub fn refresh(&mut self, info_hash: InfoHash, tokens: ClosestTokens) {
+    if let Some(searched_at) =
+      self.entries.get(&info_hash).map(|entry| entry.searched_at)
+    {
+      self.put(info_hash, searched_at, tokens);
+    }
+  }
+
+  fn put(
+    &mut self,
+    info_hash: InfoHash,
+    searched_at: Instant,
+    tokens: ClosestTokens,
+  ) {
+    self
+      .entries
+      .retain(|_, entry| entry.searched_at.elapsed() < NODE_LIFETIME);
+
+    if tokens.is_empty() {
+      self.entries.remove(&info_hash);
+    } else {
+      let entry = CacheEntry {
+        searched_at,
+        received_at: Instant::now(),
+        tokens,
+      };
+      self.entries.insert(info_hash, entry);
+    }
+  }
+
+  /// The closest nodes of the info hash with their tokens if they are fresh,
+  /// unless the nodes themselves are too old.
+  pub fn get(&self, info_hash: InfoHash) -> Option<CachedNodes> {
+    self.get_at(info_hash, Instant::now())
+  }
+
+  fn get_at(&self, info_hash: InfoHash, now: Instant) -> Option<CachedNodes> {
+    let entry = self.entries.get(&info_hash).filter(|entry| {
+      now.saturating_duration_since(entry.searched_at) < NODE_LIFETIME
+    })?;
+
+    if now.saturating_duration_since(entry.received_at) < TOKEN_LIFETIME {
+      Some(CachedNodes::Tokens(entry.tokens.clone()))
+    } else {
+      let nodes = entry.tokens.iter().map(|(node, _)| *node).collect();
+      Some(CachedNodes::Nodes(nodes))
+    }
+  }
+
+  pub fn remove(&mut self, info_hash: InfoHash) {
+    self.entries.remove(&info_hash);
+  }
+}
+
+/// Spread the announces around the interval, so those started together do
+/// not keep running at the same time.
+fn jittered(interval: Duration) -> Duration {
+  interval.mul_f64(rand::thread_rng().gen_range(0.8..1.2))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Instant,
+  };
+
+  use pretty_assertions::assert_eq;
+
+  use crate::{id::InfoHash, routing::node::NodeHandle};
+
+  use super::{CachedNodes, TokenCache, NODE_LIFETIME, REANNOUNCE_INTERVAL};
+
+  #[test]
+  fn token_cache_serves_the_periodic_announces() {
+    let info_hash = InfoHash::sha1(b"torrent");
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 6881));
+    let node = NodeHandle::new(rand::random(), addr);
+    let tokens = vec![(node, b"token".to_vec())];
+
+    let mut cache = TokenCache::default();
+    cache.insert(info_hash, tokens.clone());
+    assert_eq!(
+      cache.get(info_hash),
+      Some(CachedNodes::Tokens(tokens.clone()))
+    );
+
+    // By the next periodic announce the tokens are too old, but the nodes
+    // can still be asked for new ones.
+    let reannounce_at = Instant::now() + REANNOUNCE_INTERVAL.mul_f64(1.2);
+    assert_eq!(
+      cache.get_at(info_hash, reannounce_at),
+      Some(CachedNodes::Nodes(vec![node]))
+    );
+
+    // New tokens do not make the nodes younger, they are searched again.
+    cache.refresh(info_hash, tokens.clone());
+    assert_eq!(cache.get(info_hash), Some(CachedNodes::Tokens(tokens)));
+    let expired_at = Instant::now() + NODE_LIFETIME;
+    assert_eq!(cache.get_at(info_hash, expired_at), None);
+
+    // Without tokens there is nothing to go back to.
+    cache.refresh(info_hash, Vec::new());
+    assert_eq!(cache.get(info_hash), None);
+  }
+}
@@ -47,6 +47,9 @@ pub struct TableBootstrap {
   id_generator: MIDGenerator,
   /// Representing the initial nodes to contact for bootstrap.
   starting_nodes: HashSet<SocketAddr>,
+  /// The nodes restored from a saved routing table, the most recently seen
+  /// first. The first bootstrap contacts them instead of the routers.
+  restored_nodes: Vec<SocketAddr>,
   /// Used for tracking active messages in the network.
   active_message: HashMap<TransactionID, Timeout>,
   /// An integer representing the current bucket being used for bootstrap.
@@ -80,6 +83,7 @@ impl TableBootstrap {
     id_generator: MIDGenerator,
     routers: HashSet<String>,
     nodes: HashSet<SocketAddr>,
+    restored_nodes: Vec<SocketAddr>,
   ) -> Self {
     TableBootstrap {
       name,
@@ -89,6 +93,7 @@ impl TableBootstrap {
       router_addresses: HashSet::new(),
       id_generator,
       starting_nodes: nodes,
+      restored_nodes,
       active_message: HashMap::new(),
       current_bootstrap_bucket: 0,
       initial_responses: HashSet::new(),
@@ -99,6 +104,11 @@ impl TableBootstrap {
     }
   }
 
+  /// Update the node id we bootstrap against, after it was regenerated.
+  pub fn set_table_id(&mut self, table_id: NodeId) {
+    self.table_id = table_id;
+  }
+
   pub fn router_addresses(&self) -> &HashSet<SocketAddr> {
     &self.router_addresses
   }
@@ -144,7 +154,10 @@ impl TableBootstrap {
 
     // If we have no bootstrap contacts it means we are the first node in the network and
     // other would bootstrap against us. We consider this node as already bootstrapped.
-    if self.routers.is_empty() && self.starting_nodes.is_empty() {
+    if self.routers.is_empty()
+      && self.starting_nodes.is_empty()
+      && self.restored_nodes.is_empty()
+    {
       log::debug!(
         "[{}] Router is empty, I may be the first node in this network.",
         self.name
@@ -190,23 +203,31 @@ impl TableBootstrap {
 
     let find_node_msg = Message {
       transaction_id: trans_id.as_ref().to_vec(),
+      ip: None,
+      read_only: socket.read_only(),
+      version: socket.client_version().map(Vec::from),
       body: MessageBody::Request(Request::FindNode(FindNodeRequest {
         id: self.table_id,
         target: self.table_id,
-        want: None, // we want only contacts of the same address family we have.
+        want: socket.want(),
       })),
     }
     .encode();
 
-    // Ping all initial routers and nodes.
+    // Ping all initial routers and nodes. The first time, the most recently
+    // seen of the restored nodes are pinged instead of the routers, which are
+    // only needed if they turn out to be gone.
     self.initial_responses_expected = 0;
     self.initial_responses.clear();
 
-    for addr in self
-      .router_addresses
-      .iter()
-      .chain(self.starting_nodes.iter())
-    {
+    let restored_nodes = std::mem::take(&mut self.restored_nodes);
+    let initial_contacts: Vec<SocketAddr> = if restored_nodes.is_empty() {
+      self.router_addresses.iter().copied().collect()
+    } else {
+      restored_nodes.into_iter().take(PINGS_PER_BUCKET).collect()
+    };
+
+    for addr in initial_contacts.iter().chain(self.starting_nodes.iter()) {
       log::trace!(
         "[{}] sending initial request to {}",
         &self.name,
@@ -496,10 +517,13 @@ impl TableBootstrap {
 
       let find_node_msg = Message {
         transaction_id: trans_id.as_ref().to_vec(),
+        ip: None,
+        read_only: socket.read_only(),
+        version: socket.client_version().map(Vec::from),
         body: MessageBody::Request(Request::FindNode(FindNodeRequest {
           id: table.node_id(),
           target: target_id,
-          want: None,
+          want: socket.want(),
         })),
       }
       .encode();
@@ -0,0 +1,362 @@
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  net::SocketAddr,
+  time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+  id::{InfoHash, NodeId},
+  message::{
+    FindNodeRequest, Message, MessageBody, Request, Response,
+    SampleInfohashesRequest,
+  },
+  routing::{
+    bucket,
+    node::{NodeHandle, NodeStatus},
+    table::RoutingTable,
+  },
+  transaction::{MIDGenerator, TransactionID},
+  IpVersion,
+};
+
+use super::{
+  socket::Socket,
+  timer::{Timeout, Timer},
+  ScheduledTaskCheck,
+};
+
+const CRAWL_TIMEOUT: Duration = Duration::from_millis(1500);
+const CRAWL_IDLE_WAKEUP: Duration = Duration::from_secs(5);
+const CRAWL_CONCURRENCY: usize = 8;
+const MAX_QUEUED_NODES: usize = 1000;
+// Number of info hashes remembered to yield each once, the oldest are
+// forgotten first so the memory of a long crawl stays bounded.
+const MAX_SEEN_INFO_HASHES: usize = 100_000;
+// Upper bound of the interval a node can ask us to wait, also used for the
+// nodes which do not tell us.
+const MAX_SAMPLE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+// Number of sampled nodes remembered to honour their interval, the nodes
+// answering once it is reached are forgotten until older intervals run out.
+const MAX_SAMPLED_NODES: usize = 100_000;
+
+/// Walks the keyspace sampling the info hashes stored by the nodes (BEP51).
+///
+/// Every round starts from the nodes of the routing table closest to a random
+/// target, the nodes returned in the responses are sampled next.
+pub struct TableCrawl {
+  name: String,
+  ip_version: IpVersion,
+  id_generator: MIDGenerator,
+  active_requests: HashMap<TransactionID, ActiveRequest>,
+  queued_nodes: VecDeque<NodeHandle>,
+  // The target of the current round, the nodes sampled recently are asked
+  // for the nodes closest to it instead.
+  round_target: NodeId,
+  // When the nodes which answered our samples allow us to sample them again.
+  next_sample: HashMap<SocketAddr, Instant>,
+  // The latest info hashes passed on, so each is yielded once, and the order
+  // they were seen in.
+  seen_info_hashes: HashSet<InfoHash>,
+  seen_order: VecDeque<InfoHash>,
+  // Send the sampled info hashes through this channel.
+  tx: mpsc::UnboundedSender<InfoHash>,
+}
+
+struct ActiveRequest {
+  addr: SocketAddr,
+  // Whether we asked the node for samples, not only for nodes.
+  sample: bool,
+  timeout: Timeout,
+}
+
+impl TableCrawl {
+  pub fn new(
+    name: String,
+    ip_version: IpVersion,
+    id_generator: MIDGenerator,
+    tx: mpsc::UnboundedSender<InfoHash>,
+  ) -> TableCrawl {
+    TableCrawl {
+      name,
+      ip_version,
+      id_generator,
+      active_requests: HashMap::new(),
+      queued_nodes: VecDeque::new(),
+      round_target: rand::random(),
+      next_sample: HashMap::new(),
+      seen_info_hashes: HashSet::new(),
+      seen_order: VecDeque::new(),
+      tx,
+    }
+  }
+
+  /// Whether the receiver of the info hashes is gone.
+  pub fn is_closed(&self) -> bool {
+    self.tx.is_closed()
+  }
+
+  /// Start a new round from a random place in the keyspace.
+  pub async fn start_round(
+    &mut self,
+    table: &mut RoutingTable,
+    socket: &Socket,
+    timer: &mut Timer<ScheduledTaskCheck>,
+  ) {
+    let now = Instant::now();
+    self.next_sample.retain(|_, next| *next > now);
+
+    self.round_target = rand::random();
+    let nodes = table
+      .closest_nodes(self.round_target)
+      .filter(|n| n.status() == NodeStatus::Good)
+      .take(bucket::MAX_BUCKET_SIZE)
+      .map(|n| *n.handle())
+      .collect::<Vec<_>>();
+
+    log::debug!(
+      "[{}] {}: Starting a crawl round from {} nodes",
+      self.name,
+      self.ip_version,
+      nodes.len()
+    );
+
+    // Even the nodes sampled recently can lead us to other nodes.
+    for node in nodes {
+      self.enqueue_node(node);
+    }
+
+    self.continue_crawl(table, socket, timer).await;
+  }
+
+  pub async fn recv_response(
+    &mut self,
+    trans_id: &TransactionID,
+    rsp: Response,
+    table: &mut RoutingTable,
+    socket: &Socket,
+    timer: &mut Timer<ScheduledTaskCheck>,
+  ) {
+    let request = if let Some(request) = self.active_requests.remove(trans_id) {
+      request
+    } else {
+      log::debug!(
+        "[{}] {}: Received expired/unsolicited node response for an active table crawl",
+        self.name,
+        self.ip_version
+      );
+      return;
+    };
+    timer.cancel(request.timeout);
+
+    // Honour the interval the node asked us to wait before sampling it again.
+    let interval = rsp
+      .interval
+      .map(|interval| Duration::from_secs(interval.max(0) as u64));
+    if request.sample || interval.is_some() {
+      self.record_sample(request.addr, interval);
+    }
+
+    for info_hash in rsp.samples.unwrap_or_default() {
+      if self.seen_info_hashes.insert(info_hash) {
+        self.seen_order.push_back(info_hash);
+        self.tx.send(info_hash).unwrap_or(())
+      }
+    }
+    while self.seen_order.len() > MAX_SEEN_INFO_HASHES {
+      if let Some(oldest) = self.seen_order.pop_front() {
+        self.seen_info_hashes.remove(&oldest);
+      }
+    }
+
+    let nodes = match self.ip_version {
+      IpVersion::V4 => rsp.nodes_v4,
+      IpVersion::V6 => rsp.nodes_v6,
+    };
+
+    let now = Instant::now();
+    for node in nodes {
+      if self.can_sample(&node, now) {
+        self.enqueue_node(node);
+      }
+    }
+
+    self.continue_crawl(table, socket, timer).await;
+  }
+
+  pub async fn recv_timeout(
+    &mut self,
+    trans_id: &TransactionID,
+    table: &mut RoutingTable,
+    socket: &Socket,
+    timer: &mut Timer<ScheduledTaskCheck>,
+  ) {
+    if self.active_requests.remove(trans_id).is_none() {
+      log::warn!(
+        "[{}] {}: Received expired/unsolicited node timeout for an active table crawl",
+        self.name,
+        self.ip_version
+      );
+      return;
+    }
+
+    self.continue_crawl(table, socket, timer).await;
+  }
+
+  /// The node answered with a KRPC error, move on to the next nodes.
+  pub async fn recv_error(
+    &mut self,
+    trans_id: &TransactionID,
+    table: &mut RoutingTable,
+    socket: &Socket,
+    timer: &mut Timer<ScheduledTaskCheck>,
+  ) {
+    let request = match self.active_requests.remove(trans_id) {
+      Some(request) => request,
+      None => return,
+    };
+    timer.cancel(request.timeout);
+
+    // It answered, but does not let us sample it, e.g. it does not support
+    // BEP51.
+    if request.sample {
+      self.record_sample(request.addr, None);
+    }
+
+    self.continue_crawl(table, socket, timer).await;
+  }
+
+  /// Sample the queued nodes, or ask those sampled recently for more nodes,
+  /// then wait a bit before starting a new round if we ran out of nodes.
+  async fn continue_crawl(
+    &mut self,
+    table: &mut RoutingTable,
+    socket: &Socket,
+    timer: &mut Timer<ScheduledTaskCheck>,
+  ) {
+    let now = Instant::now();
+
+    while self.active_requests.len() < CRAWL_CONCURRENCY {
+      let node = match self.queued_nodes.pop_front() {
+        Some(node) => node,
+        None => break,
+      };
+
+      if self.is_requested(&node) {
+        continue;
+      }
+
+      if self.can_sample(&node, now) {
+        let sample_req = Request::SampleInfohashes(SampleInfohashesRequest {
+          id: table.node_id(),
+          target: rand::random(),
+        });
+        self
+          .send_request(sample_req, true, node, table, socket, timer)
+          .await;
+      } else {
+        let find_node_req = Request::FindNode(FindNodeRequest {
+          id: table.node_id(),
+          target: self.round_target,
+          want: None,
+        });
+        self
+          .send_request(find_node_req, false, node, table, socket, timer)
+          .await;
+      }
+    }
+
+    if self.active_requests.is_empty() {
+      timer.schedule_in(
+        CRAWL_IDLE_WAKEUP,
+        ScheduledTaskCheck::CrawlWakeUp(self.id_generator.generate()),
+      );
+    }
+  }
+
+  async fn send_request(
+    &mut self,
+    request: Request,
+    sample: bool,
+    node: NodeHandle,
+    table: &mut RoutingTable,
+    socket: &Socket,
+    timer: &mut Timer<ScheduledTaskCheck>,
+  ) {
+    let trans_id = self.id_generator.generate();
+    let msg = Message {
+      transaction_id: trans_id.as_ref().to_vec(),
+      ip: None,
+      read_only: socket.read_only(),
+      version: socket.client_version().map(Vec::from),
+      body: MessageBody::Request(request),
+    }
+    .encode();
+
+    if let Err(error) = socket.send(&msg, node.addr).await {
+      log::error!(
+        "[{}] {}: Could not send a crawl message: {}",
+        self.name,
+        self.ip_version,
+        error
+      );
+      return;
+    }
+
+    let timeout = timer
+      .schedule_in(CRAWL_TIMEOUT, ScheduledTaskCheck::CrawlTimeout(trans_id));
+    let request = ActiveRequest {
+      addr: node.addr,
+      sample,
+      timeout,
+    };
+    self.active_requests.insert(trans_id, request);
+
+    // We requested from the node, mark it down if the node is in our routing table
+    if let Some(n) = table.find_node_mut(&node) {
+      n.local_request()
+    }
+  }
+
+  /// Do not sample the node again before the interval it asked for, or the
+  /// longest one if it did not tell us.
+  fn record_sample(&mut self, addr: SocketAddr, interval: Option<Duration>) {
+    let now = Instant::now();
+    if self.next_sample.len() >= MAX_SAMPLED_NODES
+      && !self.next_sample.contains_key(&addr)
+    {
+      self.next_sample.retain(|_, next| *next > now);
+      if self.next_sample.len() >= MAX_SAMPLED_NODES {
+        return;
+      }
+    }
+
+    let interval = interval.map_or(MAX_SAMPLE_INTERVAL, |interval| {
+      interval.min(MAX_SAMPLE_INTERVAL)
+    });
+    self.next_sample.insert(addr, now + interval);
+  }
+
+  fn is_requested(&self, node: &NodeHandle) -> bool {
+    self
+      .active_requests
+      .values()
+      .any(|request| request.addr == node.addr)
+  }
+
+  fn can_sample(&self, node: &NodeHandle, now: Instant) -> bool {
+    self
+      .next_sample
+      .get(&node.addr)
+      .is_none_or(|next| *next <= now)
+  }
+
+  fn enqueue_node(&mut self, node: NodeHandle) {
+    if self.queued_nodes.len() < MAX_QUEUED_NODES
+      && !self.queued_nodes.contains(&node)
+    {
+      self.queued_nodes.push_back(node);
+    }
+  }
+}
@@ -1,48 +1,80 @@
 use std::{
   collections::{HashMap, HashSet},
-  net::SocketAddr,
+  net::{IpAddr, SocketAddr},
   time::Duration,
 };
 
 use futures_util::StreamExt;
 use tokio::{
   select,
-  sync::{mpsc, oneshot},
+  sync::{mpsc, oneshot, watch},
+  task,
 };
 
 use crate::{
+  external_addr::ExternalAddrVoter,
   id::InfoHash,
-  message::{error_code, Error, Message, MessageBody, Request, Response, Want},
+  item::{self, Item, ItemError, MutableItem},
+  message::{
+    error_code, AnnouncePeerRequest, Error, FindNodeRequest, GetPeersRequest,
+    Message, MessageBody, PingRequest, PutRequest, Request, Response, Want,
+  },
   routing::{
     node::{Node, NodeHandle},
     table::RoutingTable,
   },
-  storage::AnnounceStorage,
+  security,
+  storage::{AnnounceStorage, ItemStorage},
   token::{Token, TokenStore},
   transaction::{AIDGenerator, ActionID, TransactionID},
   IpVersion,
 };
 
 use super::{
-  bootstrap::TableBootstrap, lookup::TableLookup, refresh::TableRefresh,
-  socket::Socket, timer::Timer, ActionStatus, BootstrapTimeout, OneShotTask,
-  ScheduledTaskCheck, StartLookup, State, WorkerError,
+  announce::{CachedNodes, PeriodicAnnounce, TokenCache},
+  bootstrap::TableBootstrap,
+  crawl::TableCrawl,
+  lookup::{LookupKind, LookupQueue, PeersSubscriber, TableLookup},
+  refresh::TableRefresh,
+  rpc::NodeRpcs,
+  sibling::Sibling,
+  socket::Socket,
+  timer::Timer,
+  ActionStatus, AnnounceResult, AnnounceStatus, BootstrapTimeout, OneShotTask,
+  RpcRequest, ScheduledTaskCheck, SearchOptions, SearchPriority, ShutdownHook,
+  StartItemLookup, StartLookup, StartMutableItemLookup, StartNodesLookup,
+  StartRpc, StartScrape, State, WorkerError,
 };
 
+/// Maximum number of info hashes in a sample_infohashes response (BEP51).
+const MAX_SAMPLES: usize = 20;
+/// Seconds the requester of a sample should wait before asking us again.
+const SAMPLE_INTERVAL: i64 = 6 * 60 * 60;
+
 pub struct DhtHandler {
   name: String,
 
   running: bool,
+  // Whether we stopped accepting commands and only wait for the announces.
+  stopping: bool,
   command_rx: mpsc::UnboundedReceiver<OneShotTask>,
   timer: Timer<ScheduledTaskCheck>,
-  read_only: bool,
+  // Reject nodes whose id does not conform to their ip (BEP42).
+  enforce_node_id: bool,
   announce_port: Option<u16>,
   socket: Socket,
   token_store: TokenStore,
   aid_generator: AIDGenerator,
   routing_table: RoutingTable,
+  // Whether the node id was given by the user, so we must not regenerate it.
+  node_id_fixed: bool,
   active_stores: AnnounceStorage,
+  item_stores: ItemStorage,
   bootstrap: TableBootstrap,
+  external_addr: ExternalAddrVoter,
+  external_addr_tx: watch::Sender<Option<SocketAddr>>,
+  // Number of KRPC errors we received.
+  error_count: usize,
 
   next_bootstrap_txs_id: u64,
   bootstrap_txs: HashMap<u64, oneshot::Sender<bool>>,
@@ -51,19 +83,63 @@ pub struct DhtHandler {
   refresh: TableRefresh,
   // Ongoing TableLookups.
   lookups: HashMap<ActionID, TableLookup>,
+  max_concurrent_lookups: usize,
+  // The lookups waiting for a slot among the ongoing ones.
+  pending_lookups: LookupQueue,
+  // Ongoing TableCrawls.
+  crawls: HashMap<ActionID, TableCrawl>,
+  // Requests sent to a single node.
+  rpcs: NodeRpcs,
+  // The info hashes announced periodically.
+  announces: HashMap<InfoHash, PeriodicAnnounce>,
+  // The tokens of the closest nodes of the recent lookups.
+  token_cache: TokenCache,
+  next_announce_round: u64,
+  // What the nodes answered to the periodic announces, per round.
+  announce_results_tx: mpsc::UnboundedSender<AnnounceRound>,
+  announce_results_rx: mpsc::UnboundedReceiver<AnnounceRound>,
+  // The instance of the other address family, in a dual-stack DHT.
+  sibling: Option<Sibling>,
+  // Called with the routing table once we stopped.
+  shutdown_hooks: Vec<ShutdownHook>,
 }
 
+type AnnounceRound = (u64, InfoHash, Vec<AnnounceResult>);
+
 impl DhtHandler {
   pub fn new(
     name: String,
-    table: RoutingTable,
+    mut table: RoutingTable,
+    node_id_fixed: bool,
     socket: Socket,
-    read_only: bool,
+    enforce_node_id: bool,
     routers: HashSet<String>,
     nodes: HashSet<SocketAddr>,
+    restored_nodes: Vec<NodeHandle>,
     announce_port: Option<u16>,
+    max_concurrent_lookups: usize,
+    max_queued_lookups: usize,
     command_rx: mpsc::UnboundedReceiver<OneShotTask>,
+    external_addr_tx: watch::Sender<Option<SocketAddr>>,
+    sibling: Option<Sibling>,
+    shutdown_hooks: Vec<ShutdownHook>,
   ) -> Self {
+    let ip_version = socket.ip_version();
+
+    // The restored nodes are questionable until they answer the bootstrap.
+    let restored_nodes: Vec<_> = restored_nodes
+      .into_iter()
+      .filter(|node| match ip_version {
+        IpVersion::V4 => node.addr.is_ipv4(),
+        IpVersion::V6 => node.addr.is_ipv6(),
+      })
+      .filter(|node| accept_node(enforce_node_id, node))
+      .map(|node| {
+        table.add_node(Node::as_questionable(node.id, node.addr));
+        node.addr
+      })
+      .collect();
+
     let mut aid_generator = AIDGenerator::default();
 
     // The refresh task to execute after the bootstrap
@@ -78,27 +154,53 @@ impl DhtHandler {
       mid_generator,
       routers,
       nodes,
+      restored_nodes,
     );
 
-    let timer = Timer::new();
+    let mid_generator = aid_generator.generate();
+    let rpcs = NodeRpcs::new(name.clone(), socket.ip_version(), mid_generator);
+
+    let mut timer = Timer::new();
+    if let Some(sibling) = &sibling {
+      sibling.publish_nodes(&table, &mut timer);
+    }
+
+    let (announce_results_tx, announce_results_rx) = mpsc::unbounded_channel();
 
     DhtHandler {
       name,
       running: true,
+      stopping: false,
       command_rx,
       timer,
-      read_only,
+      enforce_node_id,
       announce_port,
       socket,
       token_store: TokenStore::default(),
       aid_generator,
       routing_table: table,
+      node_id_fixed,
       active_stores: AnnounceStorage::new(),
+      item_stores: ItemStorage::new(),
       bootstrap,
+      external_addr: ExternalAddrVoter::new(),
+      external_addr_tx,
+      error_count: 0,
       next_bootstrap_txs_id: 0,
       bootstrap_txs: HashMap::new(),
       refresh: table_refresh,
       lookups: HashMap::new(),
+      max_concurrent_lookups,
+      pending_lookups: LookupQueue::new(max_queued_lookups),
+      crawls: HashMap::new(),
+      rpcs,
+      announces: HashMap::new(),
+      token_cache: TokenCache::default(),
+      next_announce_round: 0,
+      announce_results_tx,
+      announce_results_rx,
+      sibling,
+      shutdown_hooks,
     }
   }
 
@@ -110,6 +212,29 @@ impl DhtHandler {
     while self.running {
       self.run_once().await
     }
+
+    // The hooks may block, to save the routing table in a file for instance.
+    // The lookups left and the socket are dropped once we return.
+    let hooks = std::mem::take(&mut self.shutdown_hooks);
+    let node_id = self.routing_table.node_id();
+    let table =
+      std::mem::replace(&mut self.routing_table, RoutingTable::new(node_id));
+    let called = task::spawn_blocking(move || {
+      for hook in &hooks {
+        hook.call(&table);
+      }
+    })
+    .await;
+    if let Err(error) = called {
+      log::error!(
+        "[{}] {}: Shutdown hook failed: {}",
+        self.name,
+        self.ip_version(),
+        error
+      );
+    }
+
+    log::info!("[{}] {}: Stopped", self.name, self.ip_version());
   }
 
   async fn run_once(&mut self) {
@@ -121,7 +246,7 @@ impl DhtHandler {
         log::debug!("[{}] handle ScheduledTaskCheck::{}.", self.name, &token);
         self.handle_timeout(token).await
       }
-      command = self.command_rx.recv() => {
+      command = self.command_rx.recv(), if !self.stopping => {
         if let Some(command) = command {
           log::debug!("[{}] handle OneShotTask::{}.", self.name, &command);
           self.handle_command(command).await
@@ -129,6 +254,12 @@ impl DhtHandler {
           self.shutdown()
         }
       }
+      round = self.announce_results_rx.recv() => {
+        // `unwrap` is OK because we keep a sender of the channel, so it is
+        // never closed.
+        let (round, info_hash, results) = round.unwrap();
+        self.handle_announce_round_finished(round, info_hash, results)
+      }
       message = self.socket.recv() => {
         // log::debug!("[{}] handle socket receive.", self.name);
         match message {
@@ -149,6 +280,16 @@ impl DhtHandler {
         }
       }
     }
+
+    // Make use of the lookups which completed in the meantime.
+    self.start_pending_lookups().await;
+
+    if self.stopping
+      && self.lookups.is_empty()
+      && self.pending_lookups.is_empty()
+    {
+      self.shutdown()
+    }
   }
 
   async fn handle_command(&mut self, task: OneShotTask) {
@@ -162,6 +303,31 @@ impl DhtHandler {
       OneShotTask::StartLookup(lookup) => {
         self.handle_start_lookup(lookup).await;
       }
+      OneShotTask::StartItemLookup(lookup) => {
+        self.handle_start_item_lookup(lookup).await;
+      }
+      OneShotTask::StartMutableItemLookup(lookup) => {
+        self.handle_start_mutable_item_lookup(lookup).await;
+      }
+      OneShotTask::StartScrape(scrape) => {
+        self.handle_start_scrape(scrape).await;
+      }
+      OneShotTask::StartNodesLookup(lookup) => {
+        self.handle_start_nodes_lookup(lookup).await;
+      }
+      OneShotTask::StartCrawl(tx) => self.handle_start_crawl(tx).await,
+      OneShotTask::StartRpc(rpc) => self.handle_start_rpc(rpc).await,
+      OneShotTask::StartAnnouncing(info_hash, port) => {
+        self.handle_start_announcing(info_hash, port).await
+      }
+      OneShotTask::StopAnnouncing(info_hash) => {
+        self.handle_stop_announcing(info_hash)
+      }
+      OneShotTask::GetAnnounceStatus(info_hash, tx) => {
+        self.handle_get_announce_status(info_hash, tx)
+      }
+      OneShotTask::AddNodes(nodes) => self.handle_add_nodes(nodes).await,
+      OneShotTask::Shutdown(timeout) => self.handle_shutdown(timeout),
       OneShotTask::GetLocalAddr(tx) => self.handle_get_local_addr(tx),
       OneShotTask::GetState(tx) => self.handle_get_state(tx),
       OneShotTask::GetNodes(tx) => self.handler_check_nodes(tx),
@@ -185,6 +351,23 @@ impl DhtHandler {
       ScheduledTaskCheck::LookupEndGame(trans_id) => {
         self.handle_check_lookup_endgame(trans_id).await;
       }
+      ScheduledTaskCheck::LookupDeadline(trans_id) => {
+        self.handle_check_lookup_deadline(trans_id).await;
+      }
+      ScheduledTaskCheck::CrawlTimeout(trans_id) => {
+        self.handle_check_crawl_timeout(trans_id).await;
+      }
+      ScheduledTaskCheck::CrawlWakeUp(trans_id) => {
+        self.handle_check_crawl_wakeup(trans_id).await;
+      }
+      ScheduledTaskCheck::RpcTimeout(trans_id) => {
+        self.rpcs.recv_timeout(&trans_id);
+      }
+      ScheduledTaskCheck::Reannounce(info_hash) => {
+        self.start_announce_round(info_hash).await;
+      }
+      ScheduledTaskCheck::PublishNodes => self.handle_publish_nodes(),
+      ScheduledTaskCheck::ShutdownTimeout => self.shutdown(),
     }
   }
 
@@ -193,14 +376,13 @@ impl DhtHandler {
     buffer: &[u8],
     addr: SocketAddr,
   ) -> Result<(), WorkerError> {
-    let message =
+    let mut message =
       Message::decode(buffer).map_err(WorkerError::InvalidBencodeDe)?;
 
     // Do not process requests if we are read only
-    // TODO: Add read only flags to messages we send it we are read only!
-    // Also, check for read only flags on responses we get before adding nodes
-    // to our RoutingTable.
-    if self.read_only && matches!(message.body, MessageBody::Request(_)) {
+    if self.socket.read_only()
+      && matches!(message.body, MessageBody::Request(_))
+    {
       return Ok(());
     }
 
@@ -211,6 +393,15 @@ impl DhtHandler {
       message
     );
 
+    let client_version = message.version.take();
+
+    // Unknown queries looking for a target are answered as find_node queries.
+    if let MessageBody::Request(Request::Unknown(u)) = &message.body {
+      if let Some(f) = u.as_find_node() {
+        message.body = MessageBody::Request(Request::FindNode(f));
+      }
+    }
+
     // Process the given message
     match message.body {
       MessageBody::Request(Request::Ping(p)) => {
@@ -221,15 +412,12 @@ impl DhtHandler {
           n.remote_request()
         }
 
-        let ping_rsp = Response {
-          id: self.routing_table.node_id(),
-          values: vec![],
-          nodes_v4: vec![],
-          nodes_v6: vec![],
-          token: None,
-        };
+        let ping_rsp = Response::new(self.routing_table.node_id());
         let ping_msg = Message {
           transaction_id: message.transaction_id,
+          ip: Some(addr),
+          read_only: false,
+          version: self.socket.client_version().map(Vec::from),
           body: MessageBody::Response(ping_rsp),
         };
         let ping_msg = ping_msg.encode();
@@ -243,9 +431,11 @@ impl DhtHandler {
         log::trace!("[{}] find node request", self.name);
         if let Some(n) = self.routing_table.find_node_mut(&node) {
           n.remote_request()
-        } else {
+        } else if !message.read_only && accept_node(self.enforce_node_id, &node)
+        {
           // if routing table doesn't contain this node,
-          // we add it as a good node.
+          // we add it as a good node, unless it is read-only and so will not
+          // answer our queries (BEP43).
           self.routing_table.add_node(Node::as_good(f.id, addr));
         }
 
@@ -253,14 +443,15 @@ impl DhtHandler {
         log::debug!("[{}] found v4 nodes: {:#?}", self.name, nodes_v4);
 
         let find_node_rsp = Response {
-          id: self.routing_table.node_id(),
-          values: vec![],
           nodes_v4,
           nodes_v6,
-          token: None,
+          ..Response::new(self.routing_table.node_id())
         };
         let find_node_msg = Message {
           transaction_id: message.transaction_id,
+          ip: Some(addr),
+          read_only: false,
+          version: self.socket.client_version().map(Vec::from),
           body: MessageBody::Response(find_node_rsp),
         };
         let find_node_msg = find_node_msg.encode();
@@ -275,9 +466,15 @@ impl DhtHandler {
           n.remote_request()
         }
 
-        let values: Vec<_> = self
-          .active_stores
-          .find_items(&g.info_hash)
+        // The requester may only be interested in the peers to download from.
+        let peers: Vec<_> = if g.noseed {
+          self.active_stores.find_non_seeds(&g.info_hash).collect()
+        } else {
+          self.active_stores.find_items(&g.info_hash).collect()
+        };
+
+        let values: Vec<_> = peers
+          .into_iter()
           .filter(|value_addr| match (addr, value_addr) {
             (SocketAddr::V4(_), SocketAddr::V4(_)) => true,
             (SocketAddr::V6(_), SocketAddr::V6(_)) => true,
@@ -291,16 +488,29 @@ impl DhtHandler {
           self.find_closest_nodes(g.info_hash, g.want)?;
         let token = self.token_store.check_out(addr.ip());
 
+        // Swarm size estimation (BEP33).
+        let (bf_seeds, bf_peers) = if g.scrape {
+          let (seeds, peers) = self.active_stores.scrape(&g.info_hash);
+          (Some(seeds), Some(peers))
+        } else {
+          (None, None)
+        };
+
         let get_peers_rsp = Response {
-          id: self.routing_table.node_id(),
           values,
           nodes_v4,
           nodes_v6,
           token: Some(token.as_ref().to_vec()),
+          bf_seeds,
+          bf_peers,
+          ..Response::new(self.routing_table.node_id())
         };
 
         let get_peers_msg = Message {
           transaction_id: message.transaction_id,
+          ip: Some(addr),
+          read_only: false,
+          version: self.socket.client_version().map(Vec::from),
           body: MessageBody::Response(get_peers_rsp),
         };
         let get_peers_msg = get_peers_msg.encode();
@@ -341,23 +551,28 @@ impl DhtHandler {
           );
           Message {
             transaction_id: message.transaction_id,
+            ip: None,
+            read_only: false,
+            version: self.socket.client_version().map(Vec::from),
             body: MessageBody::Error(Error {
               code: error_code::PROTOCOL_ERROR,
               message: "received an invalid token".to_owned(),
             }),
           }
           .encode()
-        } else if self.active_stores.add_item(a.info_hash, connect_addr) {
+        } else if self
+          .active_stores
+          .add_item(a.info_hash, connect_addr, a.seed)
+        {
           // Node successfully stored the value with us, send an announce response
           Message {
             transaction_id: message.transaction_id,
-            body: MessageBody::Response(Response {
-              id: self.routing_table.node_id(),
-              values: vec![],
-              nodes_v4: vec![],
-              nodes_v6: vec![],
-              token: None,
-            }),
+            ip: Some(addr),
+            read_only: false,
+            version: self.socket.client_version().map(Vec::from),
+            body: MessageBody::Response(Response::new(
+              self.routing_table.node_id(),
+            )),
           }
           .encode()
         } else {
@@ -369,6 +584,9 @@ impl DhtHandler {
 
           Message {
             transaction_id: message.transaction_id,
+            ip: None,
+            read_only: false,
+            version: self.socket.client_version().map(Vec::from),
             body: MessageBody::Error(Error {
               code: error_code::SERVER_ERROR,
               message: "announce storage is full".to_owned(),
@@ -379,14 +597,266 @@ impl DhtHandler {
 
         self.socket.send(&response_msg, addr).await?
       }
+      MessageBody::Request(Request::Get(g)) => {
+        let node = NodeHandle::new(g.id, addr);
+
+        // Node requested from us, mark it in the RoutingTable
+        if let Some(n) = self.routing_table.find_node_mut(&node) {
+          n.remote_request()
+        }
+
+        // Grab the closest nodes
+        let (nodes_v4, nodes_v6) = self.find_closest_nodes(g.target, None)?;
+        let token = self.token_store.check_out(addr.ip());
+
+        // Leave out the value of a mutable item the node already has.
+        let (v, k, sig, seq) = match self.item_stores.find_item(&g.target) {
+          Some(Item::Immutable(value)) => {
+            (Some(value.clone()), None, None, None)
+          }
+          Some(Item::Mutable(item))
+            if g.seq.is_none_or(|seq| item.seq > seq) =>
+          {
+            (
+              Some(item.value.clone()),
+              Some(item.public_key.to_vec()),
+              Some(item.signature.to_vec()),
+              Some(item.seq),
+            )
+          }
+          Some(Item::Mutable(item)) => (None, None, None, Some(item.seq)),
+          None => (None, None, None, None),
+        };
+
+        let get_rsp = Response {
+          nodes_v4,
+          nodes_v6,
+          token: Some(token.as_ref().to_vec()),
+          v,
+          k,
+          sig,
+          seq,
+          ..Response::new(self.routing_table.node_id())
+        };
+
+        let get_msg = Message {
+          transaction_id: message.transaction_id,
+          ip: Some(addr),
+          read_only: false,
+          version: self.socket.client_version().map(Vec::from),
+          body: MessageBody::Response(get_rsp),
+        };
+        let get_msg = get_msg.encode();
+
+        self.socket.send(&get_msg, addr).await?;
+      }
+      MessageBody::Request(Request::Put(p)) => {
+        let node = NodeHandle::new(p.id, addr);
+
+        // Node requested from us, mark it in the RoutingTable
+        if let Some(n) = self.routing_table.find_node_mut(&node) {
+          n.remote_request()
+        }
+
+        // Validate the token
+        let is_valid = match Token::new(&p.token) {
+          Ok(t) => self.token_store.check_in(addr.ip(), t),
+          Err(_) => false,
+        };
+
+        // Resolve type of response we are going to send
+        let error = if !is_valid {
+          log::debug!(
+            "[{}] {}: Remote node sent us an invalid token for a PutRequest",
+            self.name,
+            self.ip_version()
+          );
+          Some((error_code::PROTOCOL_ERROR, "received an invalid token"))
+        } else {
+          self.store_item(p)
+        };
+
+        let response_msg = match error {
+          Some((code, message_text)) => Message {
+            transaction_id: message.transaction_id,
+            ip: None,
+            read_only: false,
+            version: self.socket.client_version().map(Vec::from),
+            body: MessageBody::Error(Error {
+              code,
+              message: message_text.to_owned(),
+            }),
+          },
+          None => Message {
+            transaction_id: message.transaction_id,
+            ip: Some(addr),
+            read_only: false,
+            version: self.socket.client_version().map(Vec::from),
+            body: MessageBody::Response(Response::new(
+              self.routing_table.node_id(),
+            )),
+          },
+        }
+        .encode();
+
+        self.socket.send(&response_msg, addr).await?
+      }
+      MessageBody::Request(Request::SampleInfohashes(s)) => {
+        let node = NodeHandle::new(s.id, addr);
+
+        // Node requested from us, mark it in the RoutingTable
+        if let Some(n) = self.routing_table.find_node_mut(&node) {
+          n.remote_request()
+        }
+
+        let (samples, num) = self.active_stores.sample_info_hashes(MAX_SAMPLES);
+        let (nodes_v4, nodes_v6) = self.find_closest_nodes(s.target, None)?;
+
+        let sample_rsp = Response {
+          nodes_v4,
+          nodes_v6,
+          interval: Some(SAMPLE_INTERVAL),
+          num: Some(num as i64),
+          samples: Some(samples),
+          ..Response::new(self.routing_table.node_id())
+        };
+
+        let sample_msg = Message {
+          transaction_id: message.transaction_id,
+          ip: Some(addr),
+          read_only: false,
+          version: self.socket.client_version().map(Vec::from),
+          body: MessageBody::Response(sample_rsp),
+        };
+        let sample_msg = sample_msg.encode();
+
+        self.socket.send(&sample_msg, addr).await?;
+      }
+      MessageBody::Request(Request::Unknown(u)) => {
+        log::debug!(
+          "[{}] {}: Received unknown query {:?} from {}",
+          self.name,
+          self.ip_version(),
+          u.method,
+          addr
+        );
+
+        let error_msg = Message {
+          transaction_id: message.transaction_id,
+          ip: None,
+          read_only: false,
+          version: self.socket.client_version().map(Vec::from),
+          body: MessageBody::Error(Error {
+            code: error_code::METHOD_UNKNOWN,
+            message: "Method Unknown".to_owned(),
+          }),
+        }
+        .encode();
+
+        self.socket.send(&error_msg, addr).await?
+      }
       MessageBody::Response(rsp) => {
         let trans_id = TransactionID::from_bytes(&message.transaction_id)
           .ok_or(WorkerError::InvalidTransactionId)?;
+        let node = NodeHandle::new(rsp.id, addr);
 
         self.handle_incoming_response(trans_id, addr, rsp).await?;
+
+        // Only solicited responses get to vote for our external address.
+        if let Some(external_addr) = message.ip {
+          self.handle_external_addr_vote(node, external_addr);
+        }
+      }
+      MessageBody::Error(e) => {
+        let trans_id = TransactionID::from_bytes(&message.transaction_id)
+          .ok_or(WorkerError::InvalidTransactionId)?;
+
+        self.handle_incoming_error(trans_id, addr, e).await?;
       }
-      MessageBody::Error(_) => (),
     }
+
+    // Record the client of the node, once it made it to the routing table.
+    if let Some(version) = client_version {
+      if let Some(node) = self.routing_table.find_node_by_addr_mut(addr) {
+        node.set_client_version(version)
+      }
+    }
+
+    Ok(())
+  }
+
+  async fn handle_incoming_error(
+    &mut self,
+    trans_id: TransactionID,
+    addr: SocketAddr,
+    error: Error,
+  ) -> Result<(), WorkerError> {
+    log::debug!(
+      "[{}] {}: Received error {} \"{}\" from {}",
+      self.name,
+      self.ip_version(),
+      error.code,
+      error.message,
+      addr
+    );
+
+    self.error_count += 1;
+    if let Some(node) = self.routing_table.find_node_by_addr_mut(addr) {
+      node.remote_error()
+    }
+
+    if self.bootstrap.action_id() == trans_id.action_id() {
+      // The node answered, even if it did not give us any nodes.
+      let state_changed = self
+        .bootstrap
+        .recv_response(
+          addr,
+          &trans_id,
+          &mut self.routing_table,
+          &self.socket,
+          &mut self.timer,
+        )
+        .await;
+
+      if state_changed {
+        self
+          .handle_bootstrap_change(self.bootstrap.is_bootstrapped())
+          .await;
+      }
+    } else if self.drop_abandoned_lookup(trans_id.action_id()) {
+      // Nobody waits for the results of the lookup anymore.
+    } else if let Some(lookup) = self.lookups.get_mut(&trans_id.action_id()) {
+      match lookup
+        .recv_error(
+          &trans_id,
+          error,
+          &mut self.routing_table,
+          &self.socket,
+          &mut self.timer,
+        )
+        .await
+      {
+        ActionStatus::Ongoing => (),
+        ActionStatus::Completed => self.handle_lookup_completed(trans_id).await,
+      }
+    } else if let Some(crawl) = self.crawls.get_mut(&trans_id.action_id()) {
+      crawl
+        .recv_error(
+          &trans_id,
+          &mut self.routing_table,
+          &self.socket,
+          &mut self.timer,
+        )
+        .await;
+    } else if self.rpcs.action_id() == trans_id.action_id() {
+      self.rpcs.recv_error(&trans_id, error, &mut self.timer);
+    } else if self.refresh.action_id() == trans_id.action_id() {
+      // The refresh does not wait for the answers, counting the error is all
+      // there is to do.
+    } else {
+      return Err(WorkerError::UnsolicitedResponse);
+    }
+
     Ok(())
   }
 
@@ -402,14 +872,23 @@ impl DhtHandler {
       IpVersion::V4 => &rsp.nodes_v4,
       IpVersion::V6 => &rsp.nodes_v6,
     };
+    // The nodes of the other address family are for the sibling instance,
+    // when they answer our own find_node and get_peers queries.
+    let other_nodes = match self.socket.ip_version() {
+      IpVersion::V4 => rsp.nodes_v6.clone(),
+      IpVersion::V6 => rsp.nodes_v4.clone(),
+    };
+    let mut for_sibling = false;
 
     if self.bootstrap.action_id() == trans_id.action_id() {
+      for_sibling = true;
       // log::debug!("[{}] handle bootstrap action response", self.name);
       add_nodes(
         &mut self.routing_table,
         &node,
         nodes,
         self.bootstrap.router_addresses(),
+        self.enforce_node_id,
       );
 
       let state_changed = self
@@ -428,12 +907,16 @@ impl DhtHandler {
           .handle_bootstrap_change(self.bootstrap.is_bootstrapped())
           .await;
       }
+    } else if self.drop_abandoned_lookup(trans_id.action_id()) {
+      // Nobody waits for the results of the lookup anymore.
     } else if let Some(lookup) = self.lookups.get_mut(&trans_id.action_id()) {
+      for_sibling = lookup.finds_nodes();
       add_nodes(
         &mut self.routing_table,
         &node,
         nodes,
         self.bootstrap.router_addresses(),
+        self.enforce_node_id,
       );
 
       match lookup
@@ -450,63 +933,216 @@ impl DhtHandler {
         ActionStatus::Ongoing => (),
         ActionStatus::Completed => self.handle_lookup_completed(trans_id).await,
       }
+    } else if let Some(crawl) = self.crawls.get_mut(&trans_id.action_id()) {
+      add_nodes(
+        &mut self.routing_table,
+        &node,
+        nodes,
+        self.bootstrap.router_addresses(),
+        self.enforce_node_id,
+      );
+
+      crawl
+        .recv_response(
+          &trans_id,
+          rsp,
+          &mut self.routing_table,
+          &self.socket,
+          &mut self.timer,
+        )
+        .await;
+    } else if self.rpcs.action_id() == trans_id.action_id() {
+      add_nodes(
+        &mut self.routing_table,
+        &node,
+        nodes,
+        self.bootstrap.router_addresses(),
+        self.enforce_node_id,
+      );
+
+      self.rpcs.recv_response(&trans_id, rsp, &mut self.timer);
     } else if self.refresh.action_id() == trans_id.action_id() {
+      for_sibling = true;
       add_nodes(
         &mut self.routing_table,
         &node,
         nodes,
         self.bootstrap.router_addresses(),
+        self.enforce_node_id,
       );
     } else {
       return Err(WorkerError::UnsolicitedResponse);
     }
 
-    Ok(())
-  }
-
-  async fn handle_start_bootstrap(&mut self) {
-    if self.bootstrap.start(&self.socket, &mut self.timer).await {
-      self
-        .handle_bootstrap_change(self.bootstrap.is_bootstrapped())
-        .await;
+    if let Some(sibling) = self.sibling.as_ref().filter(|_| for_sibling) {
+      sibling.add_nodes(&other_nodes);
     }
+
+    Ok(())
   }
 
-  async fn handle_check_bootstrap(
+  fn handle_external_addr_vote(
     &mut self,
-    tx: oneshot::Sender<bool>,
-    timeout: Option<Duration>,
+    node: NodeHandle,
+    external_addr: SocketAddr,
   ) {
-    if self.bootstrap.is_bootstrapped() {
-      log::trace!("[{}] is bootstrapped.", self.name);
-      tx.send(true).unwrap_or(())
+    if external_addr.is_ipv4() != self.socket.local_addr().is_ipv4() {
+      return;
+    }
+
+    // Nodes which conform to the security extension are more trustworthy.
+    let weight = if security::is_secure_id(&node.id, node.addr.ip()) {
+      2
     } else {
-      log::trace!("[{}] is not bootstrapped.", self.name);
-      let id = self.next_bootstrap_txs_id;
-      self.next_bootstrap_txs_id += 1;
-      self.bootstrap_txs.insert(id, tx);
+      1
+    };
 
-      if let Some(timeout) = timeout {
-        self.timer.schedule_in(
-          timeout,
-          ScheduledTaskCheck::UserBootstrappedTimeout(id),
-        );
-      } else {
-        // Here the remove action must not be None.
-        let tx = self.bootstrap_txs.remove(&id).unwrap();
-        // Here maybe be panic in unwrap(), if the channel was closed.
-        tx.send(true).unwrap();
-      }
+    if !self
+      .external_addr
+      .add_vote(node.addr.ip(), external_addr, weight)
+    {
+      return;
     }
-  }
 
-  async fn handle_check_user_bootstrapped_timeout(&mut self, id: u64) {
-    if let Some(tx) = self.bootstrap_txs.remove(&id) {
-      tx.send(false).unwrap_or(())
+    log::info!(
+      "[{}] {}: External address changed to {}",
+      self.name,
+      self.ip_version(),
+      external_addr
+    );
+    self.external_addr_tx.send_replace(Some(external_addr));
+
+    if !self.node_id_fixed
+      && !security::is_secure_id(
+        &self.routing_table.node_id(),
+        external_addr.ip(),
+      )
+    {
+      self.regenerate_node_id(external_addr.ip());
     }
   }
 
-  async fn handle_check_bootstrap_timeout(
+  /// Generate a new node id conforming to our external ip (BEP42),
+  /// the nodes of the current routing table are moved to the new one.
+  fn regenerate_node_id(&mut self, external_ip: IpAddr) {
+    let node_id = security::generate_secure_id(external_ip);
+    let old_table =
+      std::mem::replace(&mut self.routing_table, RoutingTable::new(node_id));
+
+    for node in old_table.buckets().flat_map(|b| b.ping_able_nodes()) {
+      self.routing_table.add_node(node.clone());
+    }
+    self.bootstrap.set_table_id(node_id);
+
+    log::info!(
+      "[{}] {}: Regenerated node id {:?} for external ip {}",
+      self.name,
+      self.ip_version(),
+      node_id,
+      external_ip
+    );
+  }
+
+  /// Store the item of a put request.
+  ///
+  /// Returns the error code and message to respond with if it was rejected.
+  fn store_item(&mut self, put: PutRequest) -> Option<(u16, &'static str)> {
+    let stored = match (put.k, put.sig, put.seq) {
+      (Some(k), Some(sig), Some(seq)) => {
+        MutableItem::from_parts(put.v, &k, &sig, seq, put.salt)
+          .and_then(|item| self.item_stores.add_mutable_item(item, put.cas))
+      }
+      _ => item::immutable_target(&put.v)
+        .map(|target| self.item_stores.add_item(target, put.v)),
+    };
+
+    match stored {
+      Ok(true) => None,
+      Ok(false) => {
+        log::warn!(
+          "[{}] {}: ItemStorage failed to store the item because it is full",
+          self.name,
+          self.ip_version()
+        );
+        Some((error_code::SERVER_ERROR, "item storage is full"))
+      }
+      Err(error) => {
+        log::debug!(
+          "[{}] {}: Rejected a PutRequest: {}",
+          self.name,
+          self.ip_version(),
+          error
+        );
+
+        Some(match error {
+          ItemError::ValueTooBig => {
+            (error_code::MESSAGE_TOO_BIG, "message (v field) too big")
+          }
+          ItemError::SaltTooBig => {
+            (error_code::SALT_TOO_BIG, "salt (salt field) too big")
+          }
+          ItemError::InvalidSignature => {
+            (error_code::INVALID_SIGNATURE, "invalid signature")
+          }
+          ItemError::CasMismatch => (
+            error_code::CAS_MISMATCH,
+            "the CAS hash mismatched, re-read value and try again",
+          ),
+          ItemError::SequenceTooLow => (
+            error_code::SEQUENCE_TOO_LOW,
+            "sequence number less than current",
+          ),
+          ItemError::InvalidBencode(_) => {
+            (error_code::PROTOCOL_ERROR, "invalid item value")
+          }
+        })
+      }
+    }
+  }
+
+  async fn handle_start_bootstrap(&mut self) {
+    if self.bootstrap.start(&self.socket, &mut self.timer).await {
+      self
+        .handle_bootstrap_change(self.bootstrap.is_bootstrapped())
+        .await;
+    }
+  }
+
+  async fn handle_check_bootstrap(
+    &mut self,
+    tx: oneshot::Sender<bool>,
+    timeout: Option<Duration>,
+  ) {
+    if self.bootstrap.is_bootstrapped() {
+      log::trace!("[{}] is bootstrapped.", self.name);
+      tx.send(true).unwrap_or(())
+    } else {
+      log::trace!("[{}] is not bootstrapped.", self.name);
+      let id = self.next_bootstrap_txs_id;
+      self.next_bootstrap_txs_id += 1;
+      self.bootstrap_txs.insert(id, tx);
+
+      if let Some(timeout) = timeout {
+        self.timer.schedule_in(
+          timeout,
+          ScheduledTaskCheck::UserBootstrappedTimeout(id),
+        );
+      } else {
+        // Here the remove action must not be None.
+        let tx = self.bootstrap_txs.remove(&id).unwrap();
+        // Here maybe be panic in unwrap(), if the channel was closed.
+        tx.send(true).unwrap();
+      }
+    }
+  }
+
+  async fn handle_check_user_bootstrapped_timeout(&mut self, id: u64) {
+    if let Some(tx) = self.bootstrap_txs.remove(&id) {
+      tx.send(false).unwrap_or(())
+    }
+  }
+
+  async fn handle_check_bootstrap_timeout(
     &mut self,
     timeout: BootstrapTimeout,
   ) {
@@ -554,32 +1190,329 @@ impl DhtHandler {
   }
 
   async fn handle_start_lookup(&mut self, lookup: StartLookup) {
-    // Start the lookup right now if not bootstrapping
+    let subscriber = PeersSubscriber::new(
+      &lookup.options,
+      lookup.tx,
+      lookup.outcome_tx,
+      lookup.events_tx,
+    );
+
+    // Share the lookup already searching the peers of this info hash, if any.
+    if let Some(shared) = self
+      .lookups
+      .values_mut()
+      .find(|shared| shared.accepts_search(lookup.info_hash, &lookup.options))
+    {
+      shared.add_subscriber(lookup.options, subscriber, &mut self.timer);
+      return;
+    }
+    if let Some(queued) = self
+      .pending_lookups
+      .find_search(lookup.info_hash, &lookup.options)
+    {
+      queued.join_search(lookup.options, subscriber, Duration::ZERO);
+      return;
+    }
+
+    let priority = lookup.options.priority;
+    let kind = LookupKind::Peers {
+      options: lookup.options,
+      subscribers: vec![subscriber],
+    };
+    self
+      .start_table_lookup(priority, lookup.info_hash, kind)
+      .await;
+  }
+
+  async fn handle_start_scrape(&mut self, scrape: StartScrape) {
+    let kind = LookupKind::Scrape { tx: scrape.tx };
+    self
+      .start_table_lookup(SearchPriority::User, scrape.info_hash, kind)
+      .await;
+  }
+
+  async fn handle_start_nodes_lookup(&mut self, lookup: StartNodesLookup) {
+    let kind = LookupKind::Nodes { tx: lookup.tx };
+    self
+      .start_table_lookup(SearchPriority::User, lookup.target, kind)
+      .await;
+  }
+
+  async fn handle_start_item_lookup(&mut self, lookup: StartItemLookup) {
+    let kind = LookupKind::Item {
+      put: lookup.put,
+      tx: lookup.tx,
+      outcome_tx: lookup.outcome_tx,
+    };
+    self
+      .start_table_lookup(SearchPriority::User, lookup.target, kind)
+      .await;
+  }
+
+  async fn handle_start_mutable_item_lookup(
+    &mut self,
+    lookup: StartMutableItemLookup,
+  ) {
+    let target =
+      item::mutable_target(&lookup.public_key, lookup.salt.as_deref());
+    let kind = LookupKind::MutableItem {
+      salt: lookup.salt,
+      put: lookup.put,
+      cas: lookup.cas,
+      tx: lookup.tx,
+      outcome_tx: lookup.outcome_tx,
+    };
+    self
+      .start_table_lookup(SearchPriority::User, target, kind)
+      .await;
+  }
+
+  /// Start the lookup right now, unless running as many lookups as allowed
+  /// already, then queue it.
+  async fn start_table_lookup(
+    &mut self,
+    priority: SearchPriority,
+    target: InfoHash,
+    kind: LookupKind,
+  ) {
+    if self.lookups.len() >= self.max_concurrent_lookups {
+      self.queue_lookup(priority, target, kind);
+      return;
+    }
+
     let mid_generator = self.aid_generator.generate();
     let action_id = mid_generator.action_id();
 
-    let mut lookup = TableLookup::new(
-      self.name.clone(),
-      lookup.info_hash,
-      lookup.announce,
-      lookup.tx,
-      mid_generator,
-      &mut self.routing_table,
-      &self.socket,
-      &mut self.timer,
-    )
-    .await;
+    // An announce nobody waits the peers for goes straight to the closest
+    // nodes of a recent lookup.
+    let cached = match &kind {
+      LookupKind::Peers { options, .. } if options.announce_only => {
+        self.token_cache.get(target)
+      }
+      _ => None,
+    };
 
-    if lookup.completed() {
-      lookup
-        .recv_finished(
-          self.announce_port,
+    let lookup = match cached {
+      Some(CachedNodes::Tokens(tokens)) => TableLookup::from_tokens(
+        self.name.clone(),
+        target,
+        kind,
+        mid_generator,
+        tokens,
+        &self.socket,
+      ),
+      Some(CachedNodes::Nodes(nodes)) => {
+        TableLookup::from_nodes(
+          self.name.clone(),
+          target,
+          kind,
+          mid_generator,
+          nodes,
           &mut self.routing_table,
           &self.socket,
+          &mut self.timer,
         )
-        .await;
+        .await
+      }
+      None => {
+        TableLookup::new(
+          self.name.clone(),
+          target,
+          kind,
+          mid_generator,
+          &mut self.routing_table,
+          &self.socket,
+          &mut self.timer,
+        )
+        .await
+      }
+    };
+
+    self.track_lookup(action_id, lookup).await;
+  }
+
+  fn queue_lookup(
+    &mut self,
+    priority: SearchPriority,
+    target: InfoHash,
+    kind: LookupKind,
+  ) {
+    if self.pending_lookups.push(priority, target, kind) {
+      log::warn!(
+        "[{}] {}: Too many lookups queued, dropped one",
+        self.name,
+        self.ip_version()
+      );
+    }
+  }
+
+  /// Start the queued lookups while there is room for them.
+  async fn start_pending_lookups(&mut self) {
+    while self.lookups.len() < self.max_concurrent_lookups {
+      let Some((target, kind)) = self.pending_lookups.pop() else {
+        break;
+      };
+      let priority = match &kind {
+        LookupKind::Peers { options, .. } => options.priority,
+        _ => SearchPriority::User,
+      };
+      self.start_table_lookup(priority, target, kind).await;
+    }
+  }
+
+  async fn handle_start_announcing(&mut self, info_hash: InfoHash, port: u16) {
+    if let Some(announce) = self.announces.get_mut(&info_hash) {
+      announce.set_port(port);
+      return;
+    }
+
+    self
+      .announces
+      .insert(info_hash, PeriodicAnnounce::new(port));
+    self.start_announce_round(info_hash).await;
+  }
+
+  fn handle_stop_announcing(&mut self, info_hash: InfoHash) {
+    // The announce in progress, if any, runs to its end.
+    if let Some(mut announce) = self.announces.remove(&info_hash) {
+      if let Some(timeout) = announce.take_timeout() {
+        self.timer.cancel(timeout);
+      }
+    }
+  }
+
+  fn handle_get_announce_status(
+    &self,
+    info_hash: InfoHash,
+    tx: oneshot::Sender<Option<AnnounceStatus>>,
+  ) {
+    let status = self.announces.get(&info_hash).map(PeriodicAnnounce::status);
+    tx.send(status).unwrap_or(())
+  }
+
+  /// Run an announcing search in the background, its results come back
+  /// through `announce_results_rx` once it completes.
+  async fn start_announce_round(&mut self, info_hash: InfoHash) {
+    let Some(announce) = self.announces.get_mut(&info_hash) else {
+      return;
+    };
+    let round = self.next_announce_round;
+    self.next_announce_round += 1;
+    announce.start_round(round);
+
+    let options = SearchOptions::new()
+      .set_announce(true)
+      .set_announce_only(true)
+      .set_announce_port(announce.port())
+      .set_priority(SearchPriority::Background);
+    let (tx, _peers) = mpsc::unbounded_channel();
+    let (outcome_tx, mut outcome_rx) = mpsc::unbounded_channel();
+
+    let results_tx = self.announce_results_tx.clone();
+    task::spawn(async move {
+      // The channel is closed once the lookup is completed.
+      let mut results = Vec::new();
+      while let Some(result) = outcome_rx.recv().await {
+        results.push(result);
+      }
+      results_tx.send((round, info_hash, results)).unwrap_or(())
+    });
+
+    self
+      .handle_start_lookup(StartLookup {
+        info_hash,
+        options,
+        tx,
+        outcome_tx: Some(outcome_tx),
+        events_tx: None,
+      })
+      .await;
+  }
+
+  fn handle_announce_round_finished(
+    &mut self,
+    round: u64,
+    info_hash: InfoHash,
+    results: Vec<AnnounceResult>,
+  ) {
+    let Some(announce) = self.announces.get_mut(&info_hash) else {
+      return;
+    };
+    let Some(delay) = announce.finish_round(round, results) else {
+      return;
+    };
+
+    log::debug!(
+      "[{}] {}: Announce {:?} again in {:?}",
+      self.name,
+      self.socket.ip_version(),
+      info_hash,
+      delay
+    );
+    let timeout = self
+      .timer
+      .schedule_in(delay, ScheduledTaskCheck::Reannounce(info_hash));
+    announce.schedule(delay, timeout);
+  }
+
+  /// Keep the lookup around until it completes, or finish it right away.
+  async fn track_lookup(
+    &mut self,
+    action_id: ActionID,
+    mut lookup: TableLookup,
+  ) {
+    let status = if lookup.completed() {
+      self.finish_lookup(&mut lookup).await
     } else {
+      ActionStatus::Ongoing
+    };
+
+    self.keep_lookup(action_id, lookup, status);
+  }
+
+  /// Announce/store once the search is over, keeping the tokens of the
+  /// closest nodes for the next announces.
+  async fn finish_lookup(&mut self, lookup: &mut TableLookup) -> ActionStatus {
+    let status = lookup
+      .recv_finished(
+        self.announce_port,
+        &mut self.routing_table,
+        &self.socket,
+        &mut self.timer,
+      )
+      .await;
+
+    if let Some((info_hash, tokens)) = lookup.fresh_tokens() {
+      if lookup.searched() {
+        self.token_cache.insert(info_hash, tokens);
+      } else {
+        self.token_cache.refresh(info_hash, tokens);
+      }
+    }
+
+    status
+  }
+
+  fn keep_lookup(
+    &mut self,
+    action_id: ActionID,
+    mut lookup: TableLookup,
+    status: ActionStatus,
+  ) {
+    if status == ActionStatus::Ongoing {
       self.lookups.insert(action_id, lookup);
+    } else if lookup.needs_full_lookup() {
+      // The tokens were not accepted anymore, search the closest nodes again.
+      log::debug!(
+        "[{}] {}: Announce with the cached tokens failed",
+        self.name,
+        self.ip_version()
+      );
+      let (priority, target, kind) = lookup.take_search();
+      self.token_cache.remove(target);
+      self.queue_lookup(priority, target, kind);
+    } else {
+      lookup.send_finished();
     }
   }
 
@@ -590,6 +1523,10 @@ impl DhtHandler {
       good_node_count: self.routing_table.num_good_nodes(),
       questionable_node_count: self.routing_table.num_questionable_node(),
       bucket_count: self.routing_table.buckets().count(),
+      error_count: self.error_count,
+      client_versions: self.routing_table.client_versions(),
+      running_lookups: self.lookups.len(),
+      pending_lookups: self.pending_lookups.len(),
     })
     .unwrap_or(())
   }
@@ -598,11 +1535,126 @@ impl DhtHandler {
     tx.send(self.routing_table.get_nodes()).unwrap_or(())
   }
 
+  /// Add the nodes the sibling instance learned of our address family, as
+  /// questionable until they answer the find_node query we send them.
+  async fn handle_add_nodes(&mut self, nodes: Vec<NodeHandle>) {
+    let ip_version = self.ip_version();
+    let mut new_nodes = Vec::new();
+
+    for node in nodes {
+      let same_family = match ip_version {
+        IpVersion::V4 => node.addr.is_ipv4(),
+        IpVersion::V6 => node.addr.is_ipv6(),
+      };
+
+      if !same_family
+        || self.routing_table.find_node(&node).is_some()
+        || self.bootstrap.router_addresses().contains(&node.addr)
+        || !accept_node(self.enforce_node_id, &node)
+      {
+        continue;
+      }
+
+      self
+        .routing_table
+        .add_node(Node::as_questionable(node.id, node.addr));
+
+      // The node may not have found room in its bucket.
+      if self.routing_table.find_node(&node).is_some() {
+        new_nodes.push(node);
+      }
+    }
+
+    let node_id = self.routing_table.node_id();
+    self
+      .refresh
+      .query_nodes(&new_nodes, node_id, &mut self.routing_table, &self.socket)
+      .await;
+  }
+
+  fn handle_publish_nodes(&mut self) {
+    if let Some(sibling) = &self.sibling {
+      sibling.publish_nodes(&self.routing_table, &mut self.timer);
+    }
+  }
+
+  /// Stop accepting commands, and stop once the lookups which announce or
+  /// store are over if we may wait for them, right away otherwise.
+  fn handle_shutdown(&mut self, timeout: Option<Duration>) {
+    log::info!("[{}] {}: Shutting down", self.name, self.ip_version());
+
+    self.stopping = true;
+    self.command_rx.close();
+
+    for (_, mut announce) in self.announces.drain() {
+      if let Some(timeout) = announce.take_timeout() {
+        self.timer.cancel(timeout);
+      }
+    }
+    self.crawls.clear();
+
+    // The commands sent before the channel was closed, e.g. by a resolver or
+    // the sibling, are still waiting.
+    while let Ok(command) = self.command_rx.try_recv() {
+      self.handle_command_when_stopping(command);
+    }
+
+    let Some(timeout) = timeout else {
+      self.shutdown();
+      return;
+    };
+
+    let aborted: Vec<_> = self
+      .lookups
+      .iter()
+      .filter(|(_, lookup)| !lookup.stores())
+      .map(|(action_id, _)| *action_id)
+      .collect();
+    for action_id in aborted {
+      if let Some(mut lookup) = self.lookups.remove(&action_id) {
+        lookup.cancel(&mut self.timer);
+      }
+    }
+    self.pending_lookups.retain(LookupKind::stores);
+
+    self
+      .timer
+      .schedule_in(timeout, ScheduledTaskCheck::ShutdownTimeout);
+  }
+
+  /// Answer the queries about the state of the DHT, and drop the commands
+  /// starting anything, which ends their lookups and requests.
+  fn handle_command_when_stopping(&self, task: OneShotTask) {
+    log::debug!(
+      "[{}] {}: handle OneShotTask::{} while stopping.",
+      self.name,
+      self.ip_version(),
+      &task
+    );
+
+    match task {
+      OneShotTask::CheckBootstrap(tx, _) => {
+        tx.send(self.bootstrap.is_bootstrapped()).unwrap_or(())
+      }
+      OneShotTask::GetAnnounceStatus(info_hash, tx) => {
+        self.handle_get_announce_status(info_hash, tx)
+      }
+      OneShotTask::GetLocalAddr(tx) => self.handle_get_local_addr(tx),
+      OneShotTask::GetState(tx) => self.handle_get_state(tx),
+      OneShotTask::GetNodes(tx) => self.handler_check_nodes(tx),
+      _ => (),
+    }
+  }
+
   fn handle_get_local_addr(&self, tx: oneshot::Sender<SocketAddr>) {
     tx.send(self.socket.local_addr()).unwrap_or(())
   }
 
   async fn handle_check_lookup_timeout(&mut self, trans_id: TransactionID) {
+    if self.drop_abandoned_lookup(trans_id.action_id()) {
+      return;
+    }
+
     let lookup = if let Some(lookup) =
       self.lookups.get_mut(&trans_id.action_id())
     {
@@ -632,6 +1684,23 @@ impl DhtHandler {
   }
 
   async fn handle_check_lookup_endgame(&mut self, trans_id: TransactionID) {
+    if self.drop_abandoned_lookup(trans_id.action_id()) {
+      return;
+    }
+
+    self.handle_lookup_completed(trans_id).await
+  }
+
+  async fn handle_check_lookup_deadline(&mut self, trans_id: TransactionID) {
+    if self.drop_abandoned_lookup(trans_id.action_id()) {
+      return;
+    }
+
+    log::debug!(
+      "[{}] {}: Lookup deadline reached",
+      self.name,
+      self.ip_version()
+    );
     self.handle_lookup_completed(trans_id).await
   }
 
@@ -644,9 +1713,127 @@ impl DhtHandler {
         return;
       };
 
-    lookup
-      .recv_finished(self.announce_port, &mut self.routing_table, &self.socket)
+    // Once the search is over, the lookup is only completed when all the
+    // announce/put requests were answered.
+    let status = if lookup.is_finished() {
+      lookup.current_lookup_status()
+    } else {
+      self.finish_lookup(&mut lookup).await
+    };
+
+    self.keep_lookup(trans_id.action_id(), lookup, status);
+  }
+
+  async fn handle_start_crawl(&mut self, tx: mpsc::UnboundedSender<InfoHash>) {
+    let mid_generator = self.aid_generator.generate();
+    let action_id = mid_generator.action_id();
+
+    let mut crawl =
+      TableCrawl::new(self.name.clone(), self.ip_version(), mid_generator, tx);
+    crawl
+      .start_round(&mut self.routing_table, &self.socket, &mut self.timer)
       .await;
+
+    self.crawls.insert(action_id, crawl);
+  }
+
+  async fn handle_start_rpc(&mut self, rpc: StartRpc) {
+    let id = self.routing_table.node_id();
+    let request = match rpc.request {
+      RpcRequest::Ping => Request::Ping(PingRequest { id }),
+      RpcRequest::FindNode { target } => Request::FindNode(FindNodeRequest {
+        id,
+        target,
+        want: None,
+      }),
+      RpcRequest::GetPeers { info_hash } => {
+        Request::GetPeers(GetPeersRequest {
+          id,
+          info_hash,
+          want: None,
+          scrape: false,
+          noseed: false,
+        })
+      }
+      RpcRequest::AnnouncePeer {
+        info_hash,
+        token,
+        port,
+      } => Request::AnnouncePeer(AnnouncePeerRequest {
+        id,
+        info_hash,
+        port,
+        token,
+        seed: false,
+      }),
+    };
+
+    self
+      .rpcs
+      .send(
+        rpc.addr,
+        request,
+        rpc.tx,
+        &mut self.routing_table,
+        &self.socket,
+        &mut self.timer,
+      )
+      .await;
+  }
+
+  async fn handle_check_crawl_timeout(&mut self, trans_id: TransactionID) {
+    if self.drop_stopped_crawl(trans_id.action_id()) {
+      return;
+    }
+
+    if let Some(crawl) = self.crawls.get_mut(&trans_id.action_id()) {
+      crawl
+        .recv_timeout(
+          &trans_id,
+          &mut self.routing_table,
+          &self.socket,
+          &mut self.timer,
+        )
+        .await;
+    }
+  }
+
+  async fn handle_check_crawl_wakeup(&mut self, trans_id: TransactionID) {
+    if self.drop_stopped_crawl(trans_id.action_id()) {
+      return;
+    }
+
+    if let Some(crawl) = self.crawls.get_mut(&trans_id.action_id()) {
+      crawl
+        .start_round(&mut self.routing_table, &self.socket, &mut self.timer)
+        .await;
+    }
+  }
+
+  /// Cancel the lookup if nobody listens to it anymore.
+  fn drop_abandoned_lookup(&mut self, action_id: ActionID) -> bool {
+    match self.lookups.get(&action_id) {
+      Some(lookup) if lookup.is_abandoned() => {
+        log::debug!("[{}] {}: Lookup cancelled", self.name, self.ip_version());
+        if let Some(mut lookup) = self.lookups.remove(&action_id) {
+          lookup.cancel(&mut self.timer);
+        }
+        true
+      }
+      _ => false,
+    }
+  }
+
+  /// Drop the crawl if nobody listens to it anymore.
+  fn drop_stopped_crawl(&mut self, action_id: ActionID) -> bool {
+    match self.crawls.get(&action_id) {
+      Some(crawl) if crawl.is_closed() => {
+        log::debug!("[{}] {}: Crawl stopped", self.name, self.ip_version());
+        self.crawls.remove(&action_id);
+        true
+      }
+      _ => false,
+    }
   }
 
   async fn handle_check_table_refresh(&mut self) {
@@ -686,30 +1873,43 @@ impl DhtHandler {
     );
 
     let node_v4 = if matches!(want, Want::V4 | Want::Both) {
-      self
-        .routing_table
-        .closest_nodes(target)
-        .filter(|node| node.addr().is_ipv4())
-        .take(8)
-        .map(|node| *node.handle())
-        .collect()
+      self.closest_nodes_of(IpVersion::V4, target)
     } else {
       vec![]
     };
 
     let node_v6 = if matches!(want, Want::V6 | Want::Both) {
+      self.closest_nodes_of(IpVersion::V6, target)
+    } else {
+      vec![]
+    };
+
+    Ok((node_v4, node_v6))
+  }
+
+  /// The closest nodes of the given address family, those of the other family
+  /// are known by the sibling instance of a dual-stack DHT only.
+  fn closest_nodes_of(
+    &self,
+    ip_version: IpVersion,
+    target: InfoHash,
+  ) -> Vec<NodeHandle> {
+    if ip_version == self.ip_version() {
       self
         .routing_table
         .closest_nodes(target)
-        .filter(|node| node.addr().is_ipv6())
+        .filter(|node| match ip_version {
+          IpVersion::V4 => node.addr().is_ipv4(),
+          IpVersion::V6 => node.addr().is_ipv6(),
+        })
         .take(8)
         .map(|node| *node.handle())
         .collect()
+    } else if let Some(sibling) = &self.sibling {
+      sibling.closest_nodes(target)
     } else {
       vec![]
-    };
-
-    Ok((node_v4, node_v6))
+    }
   }
 }
 
@@ -718,18 +1918,32 @@ fn add_nodes(
   node: &Node,
   nodes: &[NodeHandle],
   routers: &HashSet<SocketAddr>,
+  enforce_node_id: bool,
 ) {
   // check the requesting node whether is exist in our routing table.
   // here this node is a good node which we are requested from.
-  if !routers.contains(&node.addr()) {
+  if !routers.contains(&node.addr())
+    && accept_node(enforce_node_id, node.handle())
+  {
     table.add_node(node.clone());
   }
 
   // Add the payload nodes as questionable
   for node in nodes {
-    if !routers.contains(&node.addr) {
+    if !routers.contains(&node.addr) && accept_node(enforce_node_id, node) {
       table.add_node(Node::as_questionable(node.id, node.addr));
     }
   }
   // log::debug!("After Add Nodes - {:#?}", table);
 }
+
+/// Check the node id against the node address (BEP42), only rejecting
+/// the node if we were told to enforce secure node ids.
+fn accept_node(enforce_node_id: bool, node: &NodeHandle) -> bool {
+  if security::is_secure_id(&node.id, node.addr.ip()) {
+    return true;
+  }
+
+  log::trace!("node {:?} has an insecure node id", node);
+  !enforce_node_id
+}
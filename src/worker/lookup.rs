@@ -1,16 +1,18 @@
 use std::{
-  collections::{HashMap, HashSet},
+  collections::{HashMap, HashSet, VecDeque},
   net::{Ipv4Addr, SocketAddr},
-  time::Duration,
+  time::{Duration, Instant},
 };
 
 use tokio::sync::mpsc;
 
 use crate::{
-  id::{Id, InfoHash, NODE_ID_LEN},
+  bloom::BloomFilter,
+  id::{Id, InfoHash, NodeId, NODE_ID_LEN},
+  item::{self, MutableItem},
   message::{
-    AnnouncePeerRequest, GetPeersRequest, Message, MessageBody, Request,
-    Response,
+    AnnouncePeerRequest, Error, FindNodeRequest, GetPeersRequest, GetRequest,
+    Message, MessageBody, PutRequest, Request, Response, Value, Want,
   },
   routing::{
     bucket,
@@ -22,13 +24,16 @@ use crate::{
 };
 
 use super::{
+  announce::ClosestTokens,
   socket::Socket,
   timer::{Timeout, Timer},
-  ActionStatus, ScheduledTaskCheck,
+  ActionStatus, AnnounceOutcome, AnnounceResult, ScheduledTaskCheck, Scrape,
+  SearchEvent, SearchOptions, SearchPriority, SearchStats,
 };
 
 const LOOKUP_TIMEOUT: Duration = Duration::from_millis(1500);
 const ENDGAME_TIMEOUT: Duration = Duration::from_millis(1500);
+const STORE_TIMEOUT: Duration = Duration::from_millis(1500);
 
 // Currently using the aggressive variant of the standard lookup procedure.
 // https://people.kth.se/~rauljc/p2p11/jimenez2011subsecond.pdf
@@ -44,6 +49,361 @@ const ANNOUNCE_PICK_NUM: usize = 8;
 type Distance = Id;
 type DistanceToBeat = Id;
 
+/// How a lookup skips the search, going back to the closest nodes of a
+/// previous one.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Shortcut {
+  /// Announce with the tokens they gave us.
+  Tokens,
+  /// Ask them for new tokens, then announce.
+  Nodes,
+}
+
+/// What we are looking up, and what to store on the closest nodes.
+pub enum LookupKind {
+  /// Search the peers of the target info hash, optionally announcing us.
+  Peers {
+    // The options of all the searches merged together.
+    options: SearchOptions,
+    // The searches sharing this lookup.
+    subscribers: Vec<PeersSubscriber>,
+  },
+  /// Estimate the swarm size of the target info hash from the bloom filters
+  /// of the closest nodes (BEP33).
+  Scrape {
+    // Send the estimation through this channel.
+    tx: mpsc::UnboundedSender<Scrape>,
+  },
+  /// Search the nodes closest to the target.
+  Nodes {
+    // Send the closest nodes which answered us through this channel.
+    tx: mpsc::UnboundedSender<Vec<NodeHandle>>,
+  },
+  /// Search the data item stored under the target (BEP44), optionally
+  /// storing the given value.
+  Item {
+    put: Option<Value>,
+    // Send the found item through this channel.
+    tx: mpsc::UnboundedSender<Value>,
+    // Send what each node answered to the put through this channel.
+    outcome_tx: Option<mpsc::UnboundedSender<AnnounceResult>>,
+  },
+  /// Search the mutable item stored under the target (BEP44), optionally
+  /// storing the given item if the stored one has the `cas` sequence number.
+  MutableItem {
+    salt: Option<Vec<u8>>,
+    put: Option<MutableItem>,
+    cas: Option<i64>,
+    // Send the item with the highest sequence number through this channel.
+    tx: mpsc::UnboundedSender<MutableItem>,
+    // Send what each node answered to the put through this channel.
+    outcome_tx: Option<mpsc::UnboundedSender<AnnounceResult>>,
+  },
+}
+
+/// One of the searches sharing a lookup of the peers of an info hash.
+pub struct PeersSubscriber {
+  ip_version: Option<IpVersion>,
+  // How the search asked to be announced, `None` if it did not.
+  announce: Option<AnnounceSettings>,
+  max_peers: Option<usize>,
+  // The number of peers sent through `tx` so far.
+  sent: usize,
+  // Send the found peers through this channel.
+  tx: mpsc::UnboundedSender<SocketAddr>,
+  // Send what each node answered to our announce through this channel.
+  outcome_tx: Option<mpsc::UnboundedSender<AnnounceResult>>,
+  // Send the progress of the search through this channel.
+  events_tx: Option<mpsc::UnboundedSender<SearchEvent>>,
+}
+
+impl PeersSubscriber {
+  pub fn new(
+    options: &SearchOptions,
+    tx: mpsc::UnboundedSender<SocketAddr>,
+    outcome_tx: Option<mpsc::UnboundedSender<AnnounceResult>>,
+    events_tx: Option<mpsc::UnboundedSender<SearchEvent>>,
+  ) -> PeersSubscriber {
+    PeersSubscriber {
+      ip_version: options.ip_version,
+      announce: AnnounceSettings::of(options),
+      max_peers: options.max_peers,
+      sent: 0,
+      tx,
+      outcome_tx,
+      events_tx,
+    }
+  }
+
+  fn is_closed(&self) -> bool {
+    self.tx.is_closed()
+      && self.outcome_tx.as_ref().is_none_or(|tx| tx.is_closed())
+      && self.events_tx.as_ref().is_none_or(|tx| tx.is_closed())
+  }
+
+  /// Whether the search got the maximum number of peers it asked for.
+  fn is_satisfied(&self) -> bool {
+    self
+      .max_peers
+      .is_some_and(|max_peers| self.sent >= max_peers)
+  }
+
+  fn send_peer(&mut self, peer: SocketAddr, node: NodeHandle) {
+    let wanted = match self.ip_version {
+      Some(IpVersion::V4) => peer.is_ipv4(),
+      Some(IpVersion::V6) => peer.is_ipv6(),
+      None => true,
+    };
+
+    if wanted && !self.is_satisfied() {
+      self.tx.send(peer).unwrap_or(());
+      self.sent += 1;
+      self.send_event(SearchEvent::PeerFound { peer, node });
+    }
+  }
+
+  fn send_event(&self, event: SearchEvent) {
+    if let Some(events_tx) = &self.events_tx {
+      events_tx.send(event).unwrap_or(())
+    }
+  }
+}
+
+/// What a search puts in its announces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct AnnounceSettings {
+  announce_port: Option<u16>,
+  implied_port: bool,
+  seed: bool,
+}
+
+impl AnnounceSettings {
+  fn of(options: &SearchOptions) -> Option<AnnounceSettings> {
+    options.announce.then_some(AnnounceSettings {
+      announce_port: options.announce_port,
+      implied_port: options.implied_port,
+      seed: options.seed,
+    })
+  }
+}
+
+impl LookupKind {
+  /// Whether all the receivers of the results are gone.
+  fn is_closed(&self) -> bool {
+    match self {
+      LookupKind::Peers { subscribers, .. } => {
+        subscribers.iter().all(PeersSubscriber::is_closed)
+      }
+      LookupKind::Scrape { tx } => tx.is_closed(),
+      LookupKind::Nodes { tx } => tx.is_closed(),
+      LookupKind::Item { tx, outcome_tx, .. } => {
+        tx.is_closed() && outcome_tx.as_ref().is_none_or(|tx| tx.is_closed())
+      }
+      LookupKind::MutableItem { tx, outcome_tx, .. } => {
+        tx.is_closed() && outcome_tx.as_ref().is_none_or(|tx| tx.is_closed())
+      }
+    }
+  }
+
+  /// Whether a search with these options can share this peers lookup: the
+  /// searches announcing us must agree on what to announce.
+  fn accepts_announce(&self, options: &SearchOptions) -> bool {
+    let LookupKind::Peers { subscribers, .. } = self else {
+      return false;
+    };
+    let Some(announce) = AnnounceSettings::of(options) else {
+      return true;
+    };
+
+    subscribers
+      .iter()
+      .filter(|subscriber| !subscriber.is_closed())
+      .filter_map(|subscriber| subscriber.announce)
+      .all(|other| other == announce)
+  }
+
+  /// Announce with the settings of the searches still waiting for this
+  /// lookup, if any of them asked for it.
+  fn merge_announces(&mut self) {
+    let LookupKind::Peers {
+      options,
+      subscribers,
+    } = self
+    else {
+      return;
+    };
+
+    let announce = subscribers
+      .iter()
+      .filter(|subscriber| !subscriber.is_closed())
+      .find_map(|subscriber| subscriber.announce);
+    options.announce = announce.is_some();
+    if let Some(announce) = announce {
+      options.announce_port = announce.announce_port;
+      options.implied_port = announce.implied_port;
+      options.seed = announce.seed;
+    }
+  }
+
+  /// Whether the lookup announces us or stores an item once it is over.
+  pub fn stores(&self) -> bool {
+    match self {
+      LookupKind::Peers { options, .. } => options.announce,
+      LookupKind::Scrape { .. } | LookupKind::Nodes { .. } => false,
+      LookupKind::Item { put, .. } => put.is_some(),
+      LookupKind::MutableItem { put, .. } => put.is_some(),
+    }
+  }
+
+  /// Let one more search share this peers lookup, which started `elapsed`
+  /// ago. Returns whether the deadline of the lookup changed.
+  pub fn join_search(
+    &mut self,
+    options: SearchOptions,
+    subscriber: PeersSubscriber,
+    elapsed: Duration,
+  ) -> bool {
+    let LookupKind::Peers {
+      options: merged,
+      subscribers,
+    } = self
+    else {
+      return false;
+    };
+    subscribers.push(subscriber);
+    merged.announce_only &= options.announce_only;
+
+    // The lookup runs until the latest deadline of its searches, counted from
+    // the start of the lookup.
+    let deadline = match (merged.deadline, options.deadline) {
+      (Some(deadline), Some(new_deadline)) => {
+        Some(deadline.max(elapsed + new_deadline))
+      }
+      _ => None,
+    };
+    let changed = deadline != merged.deadline;
+    merged.deadline = deadline;
+
+    self.merge_announces();
+    changed
+  }
+
+  fn send_event(&self, event: SearchEvent) {
+    if let LookupKind::Peers { subscribers, .. } = self {
+      for subscriber in subscribers {
+        subscriber.send_event(event.clone())
+      }
+    }
+  }
+}
+
+/// The lookups waiting for a running lookup to complete, the searches of the
+/// user go first.
+pub struct LookupQueue {
+  user: VecDeque<(InfoHash, LookupKind)>,
+  background: VecDeque<(InfoHash, LookupKind)>,
+  // Number of lookups queued before the background ones are dropped.
+  max_len: usize,
+}
+
+impl LookupQueue {
+  pub fn new(max_len: usize) -> LookupQueue {
+    LookupQueue {
+      user: VecDeque::new(),
+      background: VecDeque::new(),
+      max_len,
+    }
+  }
+
+  /// Queue the lookup. Once the queue is full, the lookup is dropped, unless
+  /// it is a search of the user and a background lookup can be dropped
+  /// instead. Returns whether a lookup was dropped, which ends its searches.
+  pub fn push(
+    &mut self,
+    priority: SearchPriority,
+    target: InfoHash,
+    kind: LookupKind,
+  ) -> bool {
+    if self.len() >= self.max_len {
+      self.retain(|kind| !kind.is_closed());
+    }
+
+    let mut dropped = false;
+    if self.len() >= self.max_len {
+      if priority == SearchPriority::Background
+        || self.background.pop_back().is_none()
+      {
+        return true;
+      }
+      dropped = true;
+    }
+
+    match priority {
+      SearchPriority::User => self.user.push_back((target, kind)),
+      SearchPriority::Background => self.background.push_back((target, kind)),
+    }
+
+    dropped
+  }
+
+  /// The next lookup to start, skipping the ones nobody waits for anymore.
+  pub fn pop(&mut self) -> Option<(InfoHash, LookupKind)> {
+    while let Some((target, kind)) = self
+      .user
+      .pop_front()
+      .or_else(|| self.background.pop_front())
+    {
+      if !kind.is_closed() {
+        return Some((target, kind));
+      }
+    }
+
+    None
+  }
+
+  pub fn len(&self) -> usize {
+    self.user.len() + self.background.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Only keep the lookups for which `keep` returns true.
+  pub fn retain(&mut self, mut keep: impl FnMut(&LookupKind) -> bool) {
+    self.user.retain(|(_, kind)| keep(kind));
+    self.background.retain(|(_, kind)| keep(kind));
+  }
+
+  /// The queued lookup of the peers of `info_hash` a search with these
+  /// options can share, if any.
+  /// A search of the user moves it ahead of the background searches.
+  pub fn find_search(
+    &mut self,
+    info_hash: InfoHash,
+    options: &SearchOptions,
+  ) -> Option<&mut LookupKind> {
+    let is_search = |(target, kind): &(InfoHash, LookupKind)| {
+      *target == info_hash
+        && kind.accepts_announce(options)
+        && !kind.is_closed()
+    };
+
+    if options.priority == SearchPriority::User {
+      if let Some(index) = self.background.iter().position(is_search) {
+        self.user.extend(self.background.remove(index));
+      }
+    }
+
+    self
+      .user
+      .iter_mut()
+      .chain(self.background.iter_mut())
+      .find(|queued| is_search(queued))
+      .map(|(_, kind)| kind)
+  }
+}
+
 pub struct TableLookup {
   name: String,
   ip_version: IpVersion,
@@ -52,18 +412,43 @@ pub struct TableLookup {
   // If we have received any values in the lookup.
   recv_values: bool,
   id_generator: MIDGenerator,
-  will_announce: bool,
+  kind: LookupKind,
   // DistanceToBeat is the distance that the responses of the current lookup needs to beat,
   // interestingly enough (and super important), this distance may not be equal to the
   // requested nodes's distance.
-  active_lookups: HashMap<TransactionID, (DistanceToBeat, Timeout)>,
+  active_lookups: HashMap<TransactionID, (DistanceToBeat, Timeout, NodeHandle)>,
   announce_tokens: HashMap<NodeHandle, Vec<u8>>,
+  // The seeds and peers bloom filters received from the nodes.
+  scrape_filters: HashMap<NodeHandle, (BloomFilter, BloomFilter)>,
   requested_nodes: HashSet<NodeHandle>,
+  // The nodes which answered our lookup requests.
+  responded_nodes: HashSet<NodeHandle>,
+  // The peers found so far along with the node which told us about them, so
+  // each is yielded once and late subscribers get them too.
+  found_peers: HashMap<SocketAddr, NodeHandle>,
+  // Stops the lookup once the deadline of the search is over.
+  deadline: Option<Timeout>,
+  endgame_timeout: Option<Timeout>,
+  // When the lookup started and how many requests it sent, for the stats.
+  started_at: Instant,
+  nodes_contacted: usize,
   // Storing whether or not it has ever been pinged so that
   // we can perform the brute-force lookup if the lookup failed
   all_sorted_nodes: Vec<(Distance, NodeHandle, bool)>,
-  // Send the found peers through this channel.
-  tx: mpsc::UnboundedSender<SocketAddr>,
+  // The mutable item with the highest sequence number received so far.
+  best_item: Option<MutableItem>,
+  // Whether the search itself is over, the lookup then only waits for the
+  // answers to the announce/put requests.
+  finished: bool,
+  store_requests: HashMap<TransactionID, (NodeHandle, Timeout)>,
+  // Whether we announce to the closest nodes of a previous lookup, without
+  // searching.
+  shortcut: Option<Shortcut>,
+  store_accepted: usize,
+  store_rejected: usize,
+  // The answers to the announces to the nodes of a previous lookup, sent
+  // once we know a full lookup is not needed.
+  held_outcomes: Vec<AnnounceResult>,
 }
 
 // Gather nodes
@@ -72,8 +457,7 @@ impl TableLookup {
   pub async fn new(
     name: String,
     target_id: InfoHash,
-    will_announce: bool,
-    tx: mpsc::UnboundedSender<SocketAddr>,
+    kind: LookupKind,
     id_generator: MIDGenerator,
     table: &mut RoutingTable,
     socket: &Socket,
@@ -111,20 +495,26 @@ impl TableLookup {
       });
 
     // Construct the lookup table structure.
-    let mut table_lookup = TableLookup {
+    let mut table_lookup = TableLookup::with_nodes(
       name,
-      ip_version: socket.ip_version(),
+      socket.ip_version(),
       target_id,
-      in_endgame: false,
-      recv_values: false,
+      kind,
       id_generator,
-      will_announce,
       all_sorted_nodes,
-      announce_tokens: HashMap::new(),
-      requested_nodes: HashSet::new(),
-      active_lookups: HashMap::with_capacity(INITIAL_PICK_NUM),
-      tx,
-    };
+    );
+
+    if let LookupKind::Peers { options, .. } = &table_lookup.kind {
+      if let Some(deadline) = options.deadline {
+        let timeout = timer.schedule_in(
+          deadline,
+          ScheduledTaskCheck::LookupDeadline(
+            table_lookup.id_generator.generate(),
+          ),
+        );
+        table_lookup.deadline = Some(timeout);
+      }
+    }
 
     // Call start_request_round with the list of initial_nodes
     // (return even if the search completed.. for now :D)
@@ -134,10 +524,202 @@ impl TableLookup {
     table_lookup
   }
 
+  /// Announce to the closest nodes found by a recent lookup with the tokens
+  /// they gave us, skipping the search.
+  pub fn from_tokens(
+    name: String,
+    target_id: InfoHash,
+    kind: LookupKind,
+    id_generator: MIDGenerator,
+    tokens: ClosestTokens,
+    socket: &Socket,
+  ) -> TableLookup {
+    let mut all_sorted_nodes = Vec::with_capacity(tokens.len());
+    for (node, _) in &tokens {
+      insert_sorted_node(&mut all_sorted_nodes, target_id, *node, true);
+    }
+
+    let mut table_lookup = TableLookup::with_nodes(
+      name,
+      socket.ip_version(),
+      target_id,
+      kind,
+      id_generator,
+      all_sorted_nodes,
+    );
+    table_lookup.announce_tokens = tokens.into_iter().collect();
+    table_lookup.shortcut = Some(Shortcut::Tokens);
+    table_lookup
+  }
+
+  /// Ask the closest nodes found by a previous lookup for new tokens and
+  /// announce to them, skipping the search.
+  pub async fn from_nodes(
+    name: String,
+    target_id: InfoHash,
+    kind: LookupKind,
+    id_generator: MIDGenerator,
+    nodes: Vec<NodeHandle>,
+    table: &mut RoutingTable,
+    socket: &Socket,
+    timer: &mut Timer<ScheduledTaskCheck>,
+  ) -> TableLookup {
+    let mut all_sorted_nodes = Vec::with_capacity(nodes.len());
+    for node in nodes {
+      insert_sorted_node(&mut all_sorted_nodes, target_id, node, false);
+    }
+
+    let mut table_lookup = TableLookup::with_nodes(
+      name,
+      socket.ip_version(),
+      target_id,
+      kind,
+      id_generator,
+      all_sorted_nodes,
+    );
+    table_lookup.shortcut = Some(Shortcut::Nodes);
+
+    // The endgame queries all the nodes at once, without iterating.
+    table_lookup.start_endgame_round(table, socket, timer).await;
+    table_lookup
+  }
+
+  fn with_nodes(
+    name: String,
+    ip_version: IpVersion,
+    target_id: InfoHash,
+    kind: LookupKind,
+    id_generator: MIDGenerator,
+    all_sorted_nodes: Vec<(Distance, NodeHandle, bool)>,
+  ) -> TableLookup {
+    TableLookup {
+      name,
+      ip_version,
+      target_id,
+      in_endgame: false,
+      recv_values: false,
+      id_generator,
+      kind,
+      all_sorted_nodes,
+      announce_tokens: HashMap::new(),
+      scrape_filters: HashMap::new(),
+      requested_nodes: HashSet::new(),
+      responded_nodes: HashSet::new(),
+      found_peers: HashMap::new(),
+      deadline: None,
+      endgame_timeout: None,
+      started_at: Instant::now(),
+      nodes_contacted: 0,
+      active_lookups: HashMap::with_capacity(INITIAL_PICK_NUM),
+      best_item: None,
+      finished: false,
+      store_requests: HashMap::new(),
+      shortcut: None,
+      store_accepted: 0,
+      store_rejected: 0,
+      held_outcomes: Vec::new(),
+    }
+  }
+
   pub fn completed(&self) -> bool {
     self.active_lookups.is_empty()
   }
 
+  /// Whether a search for the peers of `info_hash` with these options can
+  /// share this lookup.
+  pub fn accepts_search(
+    &self,
+    info_hash: InfoHash,
+    options: &SearchOptions,
+  ) -> bool {
+    self.kind.accepts_announce(options)
+      && self.target_id == info_hash
+      && !self.finished
+      && !self.is_abandoned()
+  }
+
+  /// Let one more search share this lookup. The peers found so far are
+  /// replayed to it, the lookup announces if any of the searches asked for it
+  /// and runs until the latest deadline.
+  pub fn add_subscriber(
+    &mut self,
+    options: SearchOptions,
+    mut subscriber: PeersSubscriber,
+    timer: &mut Timer<ScheduledTaskCheck>,
+  ) {
+    for (peer, node) in &self.found_peers {
+      subscriber.send_peer(*peer, *node);
+    }
+    if self.in_endgame {
+      subscriber.send_event(SearchEvent::EndgameStarted);
+    }
+
+    let elapsed = self.started_at.elapsed();
+    if !self.kind.join_search(options, subscriber, elapsed) {
+      return;
+    }
+
+    if let Some(timeout) = self.deadline.take() {
+      timer.cancel(timeout);
+    }
+    if let LookupKind::Peers { options, .. } = &self.kind {
+      if let Some(deadline) = options.deadline {
+        let timeout = timer.schedule_in(
+          deadline.saturating_sub(elapsed),
+          ScheduledTaskCheck::LookupDeadline(self.id_generator.generate()),
+        );
+        self.deadline = Some(timeout);
+      }
+    }
+  }
+
+  /// Whether the lookup announces us or stores an item once it is over.
+  pub fn stores(&self) -> bool {
+    self.kind.stores()
+  }
+
+  /// Whether the lookup sends find_node or get_peers queries.
+  pub fn finds_nodes(&self) -> bool {
+    matches!(
+      self.kind,
+      LookupKind::Peers { .. }
+        | LookupKind::Scrape { .. }
+        | LookupKind::Nodes { .. }
+    )
+  }
+
+  /// Whether nobody listens to the results of the lookup anymore.
+  pub fn is_abandoned(&self) -> bool {
+    self.kind.is_closed()
+  }
+
+  /// Stop the lookup right away, without announcing or storing anything.
+  pub fn cancel(&mut self, timer: &mut Timer<ScheduledTaskCheck>) {
+    self.finished = true;
+
+    for (_, timeout, _) in self.active_lookups.values() {
+      timer.cancel(*timeout);
+    }
+    for (_, timeout) in self.store_requests.values() {
+      timer.cancel(*timeout);
+    }
+    for timeout in [self.deadline.take(), self.endgame_timeout.take()]
+      .into_iter()
+      .flatten()
+    {
+      timer.cancel(timeout);
+    }
+
+    self.active_lookups.clear();
+    self.store_requests.clear();
+    self.in_endgame = false;
+  }
+
+  /// Whether `recv_finished` was already called.
+  pub fn is_finished(&self) -> bool {
+    self.finished
+  }
+
   pub async fn recv_response(
     &mut self,
     node: Node,
@@ -147,8 +729,14 @@ impl TableLookup {
     socket: &Socket,
     timer: &mut Timer<ScheduledTaskCheck>,
   ) -> ActionStatus {
+    if let Some((node, timeout)) = self.store_requests.remove(trans_id) {
+      timer.cancel(timeout);
+      self.send_store_outcome(node, AnnounceOutcome::Accepted);
+      return self.current_lookup_status();
+    }
+
     // Process the message transaction id.
-    let (dist_to_beat, timeout) = if let Some(lookup) =
+    let (dist_to_beat, timeout, _) = if let Some(lookup) =
       self.active_lookups.remove(trans_id)
     {
       lookup
@@ -165,6 +753,10 @@ impl TableLookup {
     if !self.in_endgame {
       timer.cancel(timeout);
     }
+    self.responded_nodes.insert(*node.handle());
+    self
+      .kind
+      .send_event(SearchEvent::NodeResponded(*node.handle()));
 
     if let Some(token) = msg.token {
       // Add the announce token to our list of tokens.
@@ -176,8 +768,41 @@ impl TableLookup {
       super::IpVersion::V6 => msg.nodes_v6,
     };
 
+    if let (Some(seeds), Some(peers)) = (msg.bf_seeds, msg.bf_peers) {
+      self.scrape_filters.insert(*node.handle(), (seeds, peers));
+    }
+
     let values = msg.values;
 
+    match (&self.kind, msg.v) {
+      (LookupKind::Item { tx, .. }, Some(value)) => {
+        // Only pass on the first item which really is stored under the target.
+        let is_valid = item::immutable_target(&value)
+          .is_ok_and(|target| target == self.target_id);
+
+        if is_valid && !self.recv_values {
+          self.recv_values = true;
+          tx.send(value).unwrap_or(())
+        }
+      }
+      (LookupKind::MutableItem { salt, .. }, Some(value)) => {
+        if let (Some(k), Some(sig), Some(seq)) = (msg.k, msg.sig, msg.seq) {
+          // Keep the newest item which is signed by the owner of the target.
+          let item =
+            MutableItem::from_parts(value, &k, &sig, seq, salt.clone())
+              .ok()
+              .filter(|item| item.target() == self.target_id);
+
+          if let Some(item) = item {
+            if self.best_item.as_ref().is_none_or(|best| best.seq < seq) {
+              self.best_item = Some(item);
+            }
+          }
+        }
+      }
+      _ => (),
+    }
+
     // Check if we beat the distance, get the next distant to beat
     let (iterator_nodes, next_dist_to_beat) = if !nodes.is_empty() {
       let requested_nodes = &self.requested_nodes;
@@ -241,8 +866,14 @@ impl TableLookup {
         self.start_endgame_round(table, socket, timer).await;
       }
 
-      for value in values {
-        self.tx.send(value).unwrap_or(())
+      if let LookupKind::Peers { subscribers, .. } = &mut self.kind {
+        for value in values {
+          if self.found_peers.insert(value, *node.handle()).is_none() {
+            for subscriber in subscribers.iter_mut() {
+              subscriber.send_peer(value, *node.handle());
+            }
+          }
+        }
       }
     }
 
@@ -256,14 +887,23 @@ impl TableLookup {
     socket: &Socket,
     timer: &mut Timer<ScheduledTaskCheck>,
   ) -> ActionStatus {
-    if self.active_lookups.remove(trans_id).is_none() {
+    if let Some((node, _)) = self.store_requests.remove(trans_id) {
+      self.send_store_outcome(node, AnnounceOutcome::TimedOut);
+      return self.current_lookup_status();
+    }
+
+    let node = if let Some((_, _, node)) = self.active_lookups.remove(trans_id)
+    {
+      node
+    } else {
       log::warn!(
         "[{}] {}: Received expired/unsolicited node timeout for an active table lookup",
         self.name,
         self.ip_version
       );
       return self.current_lookup_status();
-    }
+    };
+    self.kind.send_event(SearchEvent::NodeTimedOut(node));
 
     if !self.in_endgame {
       // If there are not more active lookups, start the endgame
@@ -275,39 +915,145 @@ impl TableLookup {
     self.current_lookup_status()
   }
 
+  /// The node answered our request with a KRPC error.
+  pub async fn recv_error(
+    &mut self,
+    trans_id: &TransactionID,
+    error: Error,
+    table: &mut RoutingTable,
+    socket: &Socket,
+    timer: &mut Timer<ScheduledTaskCheck>,
+  ) -> ActionStatus {
+    if let Some((node, timeout)) = self.store_requests.remove(trans_id) {
+      timer.cancel(timeout);
+      let outcome = AnnounceOutcome::Rejected {
+        code: error.code,
+        message: error.message,
+      };
+      self.send_store_outcome(node, outcome);
+      return self.current_lookup_status();
+    }
+
+    let timeout = match self.active_lookups.remove(trans_id) {
+      Some((_, timeout, node)) => {
+        self.kind.send_event(SearchEvent::NodeResponded(node));
+        timeout
+      }
+      None => {
+        log::debug!(
+          "[{}] {}: Received expired/unsolicited node error for an active table lookup",
+          self.name,
+          self.ip_version
+        );
+        return self.current_lookup_status();
+      }
+    };
+
+    // The node will not give us any nodes, go on as if it timed out.
+    if !self.in_endgame {
+      timer.cancel(timeout);
+
+      if self.active_lookups.is_empty() {
+        self.start_endgame_round(table, socket, timer).await;
+      }
+    }
+
+    self.current_lookup_status()
+  }
+
+  /// Announce or store the item on the closest nodes, then wait for their
+  /// answers if we need to.
   pub async fn recv_finished(
     &mut self,
     port: Option<u16>,
     table: &mut RoutingTable,
     socket: &Socket,
-  ) {
-    // Announce if we were told to
-    if self.will_announce {
-      let announce_tokens = &self.announce_tokens;
+    timer: &mut Timer<ScheduledTaskCheck>,
+  ) -> ActionStatus {
+    self.finished = true;
 
-      for (_, node, _) in self
-        .all_sorted_nodes
-        .iter()
-        .filter(|(_, node, _)| announce_tokens.contains_key(node))
-        .take(ANNOUNCE_PICK_NUM)
-      {
-        let trans_id = self.id_generator.generate();
-        let token = announce_tokens.get(node).unwrap();
+    // This may not be cleared since we didn't set a timeout
+    // for each node, any nodes that didn't respond would still
+    // be in here.
+    for (_, timeout, node) in self.active_lookups.values() {
+      timer.cancel(*timeout);
+      self.kind.send_event(SearchEvent::NodeTimedOut(*node));
+    }
+    for timeout in [self.deadline.take(), self.endgame_timeout.take()]
+      .into_iter()
+      .flatten()
+    {
+      timer.cancel(timeout);
+    }
+
+    // The searches which asked to announce may be gone.
+    self.kind.merge_announces();
 
-        let announce_peer_req = AnnouncePeerRequest {
-          id: table.node_id(),
-          info_hash: self.target_id,
-          token: token.clone(),
-          port,
+    // Announce (or store the item) if we were told to
+    if self.kind.stores() {
+      for (node, token) in &self.closest_tokens() {
+        let trans_id = self.id_generator.generate();
+        let token = token.clone();
+
+        let request = match &self.kind {
+          LookupKind::Peers { options, .. } => {
+            Request::AnnouncePeer(AnnouncePeerRequest {
+              id: table.node_id(),
+              info_hash: self.target_id,
+              token,
+              port: options.port(port),
+              seed: options.seed,
+            })
+          }
+          // `will_store` is false for a scrape.
+          LookupKind::Scrape { .. } | LookupKind::Nodes { .. } => {
+            unreachable!()
+          }
+          LookupKind::Item { put, .. } => Request::Put(PutRequest {
+            id: table.node_id(),
+            token,
+            // `unwrap` is OK because `will_store` checked it is present.
+            v: put.clone().unwrap(),
+            k: None,
+            sig: None,
+            seq: None,
+            salt: None,
+            cas: None,
+          }),
+          LookupKind::MutableItem { put, cas, .. } => {
+            // `unwrap` is OK because `will_store` checked it is present.
+            let item = put.as_ref().unwrap();
+
+            Request::Put(PutRequest {
+              id: table.node_id(),
+              token,
+              v: item.value.clone(),
+              k: Some(item.public_key.to_vec()),
+              sig: Some(item.signature.to_vec()),
+              seq: Some(item.seq),
+              salt: item.salt.clone(),
+              cas: *cas,
+            })
+          }
         };
-        let announce_peer_msg = Message {
+        let store_msg = Message {
           transaction_id: trans_id.as_ref().to_vec(),
-          body: MessageBody::Request(Request::AnnouncePeer(announce_peer_req)),
+          ip: None,
+          read_only: socket.read_only(),
+          version: socket.client_version().map(Vec::from),
+          body: MessageBody::Request(request),
         };
-        let announce_peer_msg = announce_peer_msg.encode();
+        let store_msg = store_msg.encode();
 
-        match socket.send(&announce_peer_msg, node.addr).await {
+        match socket.send(&store_msg, node.addr).await {
           Ok(()) => {
+            let timeout = timer.schedule_in(
+              STORE_TIMEOUT,
+              ScheduledTaskCheck::LookupTimeout(trans_id),
+            );
+            self.store_requests.insert(trans_id, (*node, timeout));
+            self.kind.send_event(SearchEvent::AnnounceSent(*node));
+
             // We requested from the node, mark it down if the node is in our routing table
             if let Some(n) = table.find_node_mut(node) {
               n.local_request()
@@ -315,7 +1061,7 @@ impl TableLookup {
           }
           Err(error) => {
             log::error!(
-              "[{}] {}: TableLookup announce request failed to send: {}",
+              "[{}] {}: TableLookup store request failed to send: {}",
               self.name,
               self.ip_version,
               error
@@ -324,15 +1070,213 @@ impl TableLookup {
         }
       }
     }
-    // This may not be cleared since we didn't set a timeout
-    // for each node, any nodes that didn't respond would still
-    // be in here.
+    if let LookupKind::MutableItem { tx, .. } = &self.kind {
+      if let Some(item) = self.best_item.take() {
+        tx.send(item).unwrap_or(())
+      }
+    }
+    if let LookupKind::Scrape { tx } = &self.kind {
+      if let Some(scrape) = self.merge_scrape_filters() {
+        tx.send(scrape).unwrap_or(())
+      }
+    }
+    if let LookupKind::Nodes { tx } = &self.kind {
+      let responded_nodes = &self.responded_nodes;
+      let closest_nodes = self
+        .all_sorted_nodes
+        .iter()
+        .filter(|(_, node, _)| responded_nodes.contains(node))
+        .take(bucket::MAX_BUCKET_SIZE)
+        .map(|(_, node, _)| *node)
+        .collect();
+      tx.send(closest_nodes).unwrap_or(())
+    }
+
     self.active_lookups.clear();
     self.in_endgame = false;
+
+    self.current_lookup_status()
+  }
+
+  /// The closest nodes which gave us an announce token, with their token.
+  fn closest_tokens(&self) -> ClosestTokens {
+    self
+      .all_sorted_nodes
+      .iter()
+      .filter_map(|(_, node, _)| {
+        self
+          .announce_tokens
+          .get(node)
+          .map(|token| (*node, token.clone()))
+      })
+      .take(ANNOUNCE_PICK_NUM)
+      .collect()
+  }
+
+  /// The tokens of the closest nodes to keep for the next announces, if the
+  /// lookup asked them for tokens.
+  pub fn fresh_tokens(&self) -> Option<(InfoHash, ClosestTokens)> {
+    match self.kind {
+      LookupKind::Peers { .. } | LookupKind::Scrape { .. }
+        if self.shortcut != Some(Shortcut::Tokens) =>
+      {
+        Some((self.target_id, self.closest_tokens()))
+      }
+      _ => None,
+    }
+  }
+
+  /// Whether the lookup searched the closest nodes, instead of going back to
+  /// those of a previous lookup.
+  pub fn searched(&self) -> bool {
+    self.shortcut.is_none()
   }
 
-  fn current_lookup_status(&self) -> ActionStatus {
-    if self.in_endgame || !self.active_lookups.is_empty() {
+  /// Whether the announce to the nodes of a previous lookup failed, so the
+  /// closest nodes must be searched again.
+  pub fn needs_full_lookup(&self) -> bool {
+    self.shortcut.is_some()
+      && (self.store_rejected > 0 || self.store_accepted == 0)
+  }
+
+  /// Tell the subscribers the search completed, along with the answers to
+  /// the announces held back so far. Aborted searches are not reported as
+  /// finished.
+  pub fn send_finished(&mut self) {
+    for result in std::mem::take(&mut self.held_outcomes) {
+      self.send_outcome(result);
+    }
+
+    if let LookupKind::Peers { subscribers, .. } = &self.kind {
+      for subscriber in subscribers {
+        subscriber.send_event(SearchEvent::Finished(SearchStats {
+          nodes_contacted: self.nodes_contacted,
+          duration: self.started_at.elapsed(),
+          peers_found: subscriber.sent,
+        }));
+      }
+    }
+  }
+
+  /// Hand the searches of this lookup over to a full lookup.
+  pub fn take_search(&mut self) -> (SearchPriority, InfoHash, LookupKind) {
+    let mut kind = std::mem::replace(
+      &mut self.kind,
+      LookupKind::Peers {
+        options: SearchOptions::new(),
+        subscribers: Vec::new(),
+      },
+    );
+
+    let priority = match &mut kind {
+      LookupKind::Peers { options, .. } => {
+        options.announce_only = false;
+        options.priority
+      }
+      _ => SearchPriority::User,
+    };
+
+    (priority, self.target_id, kind)
+  }
+
+  fn send_store_outcome(&mut self, node: NodeHandle, outcome: AnnounceOutcome) {
+    match outcome {
+      AnnounceOutcome::Accepted => self.store_accepted += 1,
+      AnnounceOutcome::Rejected { .. } => self.store_rejected += 1,
+      AnnounceOutcome::TimedOut => (),
+    }
+
+    let result = AnnounceResult {
+      node: node.addr,
+      outcome,
+    };
+    // The announces to cached nodes may be redone by a full lookup, whose
+    // answers are the ones to report then.
+    if self.shortcut.is_some() {
+      self.held_outcomes.push(result);
+    } else {
+      self.send_outcome(result);
+    }
+  }
+
+  fn send_outcome(&self, result: AnnounceResult) {
+    match &self.kind {
+      LookupKind::Peers { subscribers, .. } => {
+        for outcome_tx in
+          subscribers.iter().filter_map(|s| s.outcome_tx.as_ref())
+        {
+          outcome_tx.send(result.clone()).unwrap_or(())
+        }
+      }
+      LookupKind::Item {
+        outcome_tx: Some(outcome_tx),
+        ..
+      }
+      | LookupKind::MutableItem {
+        outcome_tx: Some(outcome_tx),
+        ..
+      } => outcome_tx.send(result.clone()).unwrap_or(()),
+      _ => (),
+    }
+    self
+      .kind
+      .send_event(SearchEvent::AnnounceAcknowledged(result));
+  }
+
+  /// Merge the bloom filters of the closest nodes which answered the scrape,
+  /// so the peers announced to several of them are counted once.
+  fn merge_scrape_filters(&self) -> Option<Scrape> {
+    let scrape_filters = &self.scrape_filters;
+    let mut merged: Option<(BloomFilter, BloomFilter)> = None;
+
+    for (_, node, _) in self
+      .all_sorted_nodes
+      .iter()
+      .filter(|(_, node, _)| scrape_filters.contains_key(node))
+      .take(ANNOUNCE_PICK_NUM)
+    {
+      let (seeds, peers) = &scrape_filters[node];
+
+      match &mut merged {
+        Some((all_seeds, all_peers)) => {
+          all_seeds.union(seeds);
+          all_peers.union(peers);
+        }
+        None => merged = Some((seeds.clone(), peers.clone())),
+      }
+    }
+
+    merged.map(|(seeds, peers)| Scrape {
+      seeds: seeds.estimate(),
+      peers: peers.estimate(),
+    })
+  }
+
+  /// Whether all the searches still listening got the maximum number of
+  /// peers they asked for.
+  fn enough_peers(&self) -> bool {
+    match &self.kind {
+      LookupKind::Peers { subscribers, .. } => {
+        let mut listening =
+          subscribers.iter().filter(|s| !s.is_closed()).peekable();
+
+        listening.peek().is_some()
+          && listening.all(PeersSubscriber::is_satisfied)
+      }
+      _ => false,
+    }
+  }
+
+  pub fn current_lookup_status(&self) -> ActionStatus {
+    if self.finished {
+      if self.store_requests.is_empty() {
+        ActionStatus::Completed
+      } else {
+        ActionStatus::Ongoing
+      }
+    } else if self.enough_peers() {
+      ActionStatus::Completed
+    } else if self.in_endgame || !self.active_lookups.is_empty() {
       ActionStatus::Ongoing
     } else {
       ActionStatus::Completed
@@ -365,25 +1309,32 @@ impl TableLookup {
       // beat and the timeout token
       self
         .active_lookups
-        .insert(trans_id, (dist_to_beat, timeout));
+        .insert(trans_id, (dist_to_beat, timeout, *node));
       // Send the message to the node
-      let get_peers_msg = Message {
+      let lookup_msg = Message {
         transaction_id: trans_id.as_ref().to_vec(),
-        body: MessageBody::Request(Request::GetPeers(GetPeersRequest {
-          id: table.node_id(),
-          info_hash: self.target_id,
-          want: None,
-        })),
+        ip: None,
+        read_only: socket.read_only(),
+        version: socket.client_version().map(Vec::from),
+        body: MessageBody::Request(lookup_request(
+          &self.kind,
+          table.node_id(),
+          self.target_id,
+          socket.want(),
+        )),
       }
       .encode();
 
-      if let Err(error) = socket.send(&get_peers_msg, node.addr).await {
+      if let Err(error) = socket.send(&lookup_msg, node.addr).await {
         log::error!(
           "[{}] {}: Could not send a lookup message: {}",
           self.name,
           self.ip_version,
           error
         )
+      } else {
+        self.nodes_contacted += 1;
+        self.kind.send_event(SearchEvent::NodeQueried(*node));
       }
 
       // We requested from the node, mark it down
@@ -410,12 +1361,14 @@ impl TableLookup {
   ) -> ActionStatus {
     // Entering the endgame phase
     self.in_endgame = true;
+    self.kind.send_event(SearchEvent::EndgameStarted);
 
     // Try to start a global message timeout for the endgame
     let timeout = timer.schedule_in(
       ENDGAME_TIMEOUT,
       ScheduledTaskCheck::LookupEndGame(self.id_generator.generate()),
     );
+    self.endgame_timeout = Some(timeout);
 
     if !self.recv_values {
       for node_info in
@@ -429,20 +1382,26 @@ impl TableLookup {
         // Associate the transaction id with this node's distance and its timeout
         // token we don't actually need to keep track of this information, but
         // we do still need to filter out unsolicited responses by using the active_lookups map !!
-        self.active_lookups.insert(trans_id, (*node_dist, timeout));
+        self
+          .active_lookups
+          .insert(trans_id, (*node_dist, timeout, *node));
 
         // Send the message to the node
-        let get_peers_msg = Message {
+        let lookup_msg = Message {
           transaction_id: trans_id.as_ref().to_vec(),
-          body: MessageBody::Request(Request::GetPeers(GetPeersRequest {
-            id: table.node_id(),
-            info_hash: self.target_id,
-            want: None,
-          })),
+          ip: None,
+          read_only: socket.read_only(),
+          version: socket.client_version().map(Vec::from),
+          body: MessageBody::Request(lookup_request(
+            &self.kind,
+            table.node_id(),
+            self.target_id,
+            socket.want(),
+          )),
         }
         .encode();
 
-        if let Err(error) = socket.send(&get_peers_msg, node.addr).await {
+        if let Err(error) = socket.send(&lookup_msg, node.addr).await {
           log::error!(
             "[{}] {}: Could not send an endgame message: {}",
             self.name,
@@ -451,6 +1410,8 @@ impl TableLookup {
           );
           continue;
         }
+        self.nodes_contacted += 1;
+        self.kind.send_event(SearchEvent::NodeQueried(*node));
 
         // Mark that we requested form the in the RoutingTable
         if let Some(n) = table.find_node_mut(node) {
@@ -466,6 +1427,41 @@ impl TableLookup {
   }
 }
 
+/// Build the query sent to the nodes we are iterating over.
+fn lookup_request(
+  kind: &LookupKind,
+  id: NodeId,
+  target: InfoHash,
+  want: Option<Want>,
+) -> Request {
+  match kind {
+    LookupKind::Peers { .. } => Request::GetPeers(GetPeersRequest {
+      id,
+      info_hash: target,
+      want,
+      scrape: false,
+      noseed: false,
+    }),
+    LookupKind::Scrape { .. } => Request::GetPeers(GetPeersRequest {
+      id,
+      info_hash: target,
+      want,
+      scrape: true,
+      noseed: false,
+    }),
+    LookupKind::Nodes { .. } => {
+      Request::FindNode(FindNodeRequest { id, target, want })
+    }
+    LookupKind::Item { .. } | LookupKind::MutableItem { .. } => {
+      Request::Get(GetRequest {
+        id,
+        target,
+        seq: None,
+      })
+    }
+  }
+}
+
 fn pick_iterator_nodes<I>(
   unsorted_nodes: I,
   target_id: InfoHash,
@@ -566,3 +1562,400 @@ fn insert_sorted_node(
     Err(ins_index) => nodes.insert(ins_index, (node_dist, node, pinged)),
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+  };
+
+  use pretty_assertions::assert_eq;
+  use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, error::TryRecvError},
+    time,
+  };
+
+  use crate::{
+    id::InfoHash,
+    message::{error_code, Error, Message, MessageBody, Request, Response},
+    routing::{
+      node::{Node, NodeHandle},
+      table::RoutingTable,
+    },
+    transaction::{AIDGenerator, TransactionID},
+    worker::{
+      socket::Socket, timer::Timer, ActionStatus, AnnounceResult,
+      SearchOptions, SearchPriority,
+    },
+  };
+
+  use super::{LookupKind, LookupQueue, PeersSubscriber, TableLookup};
+
+  async fn local_socket() -> Socket {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    Socket::new(socket, false, None).unwrap()
+  }
+
+  /// A node of the test, answering the queries of the lookup by hand.
+  async fn bind_node() -> (UdpSocket, NodeHandle) {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let handle = NodeHandle::new(rand::random(), socket.local_addr().unwrap());
+    (socket, handle)
+  }
+
+  async fn recv_request(socket: &UdpSocket) -> (TransactionID, Request) {
+    let mut buffer = [0; 1500];
+    let (size, _) =
+      time::timeout(Duration::from_secs(1), socket.recv_from(&mut buffer))
+        .await
+        .expect("no request received")
+        .unwrap();
+
+    let message = Message::decode(&buffer[..size]).unwrap();
+    let trans_id = TransactionID::from_bytes(&message.transaction_id).unwrap();
+    match message.body {
+      MessageBody::Request(request) => (trans_id, request),
+      body => panic!("not a request: {body:?}"),
+    }
+  }
+
+  /// A search with these options, with the receivers of the peers it finds
+  /// and of the answers to its announces.
+  fn search(
+    options: &SearchOptions,
+  ) -> (
+    PeersSubscriber,
+    mpsc::UnboundedReceiver<SocketAddr>,
+    mpsc::UnboundedReceiver<AnnounceResult>,
+  ) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (outcome_tx, outcome_rx) = mpsc::unbounded_channel();
+    let subscriber = PeersSubscriber::new(options, tx, Some(outcome_tx), None);
+    (subscriber, rx, outcome_rx)
+  }
+
+  fn peers_lookup(
+    options: SearchOptions,
+  ) -> (
+    LookupKind,
+    mpsc::UnboundedReceiver<SocketAddr>,
+    mpsc::UnboundedReceiver<AnnounceResult>,
+  ) {
+    let (subscriber, rx, outcome_rx) = search(&options);
+    let kind = LookupKind::Peers {
+      options,
+      subscribers: vec![subscriber],
+    };
+    (kind, rx, outcome_rx)
+  }
+
+  fn merged_options(kind: &LookupKind) -> &SearchOptions {
+    match kind {
+      LookupKind::Peers { options, .. } => options,
+      _ => panic!("not a peers lookup"),
+    }
+  }
+
+  #[test]
+  fn lookup_queue_drops_background_lookups() {
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let nodes = || LookupKind::Nodes { tx: tx.clone() };
+    let target = |name: &[u8]| InfoHash::sha1(name);
+
+    let mut queue = LookupQueue::new(2);
+    assert!(!queue.push(SearchPriority::User, target(b"a"), nodes()));
+    assert!(!queue.push(SearchPriority::Background, target(b"b"), nodes()));
+
+    // A full queue drops the new background lookup, or the queued one to
+    // make room for a search of the user.
+    assert!(queue.push(SearchPriority::Background, target(b"c"), nodes()));
+    assert!(queue.push(SearchPriority::User, target(b"d"), nodes()));
+    assert_eq!(queue.len(), 2);
+    assert_eq!(queue.pop().map(|(target, _)| target), Some(target(b"a")));
+    assert_eq!(queue.pop().map(|(target, _)| target), Some(target(b"d")));
+    assert!(queue.is_empty());
+
+    // The lookups nobody waits for anymore make room first.
+    let (closed_tx, _) = mpsc::unbounded_channel();
+    let closed = LookupKind::Nodes { tx: closed_tx };
+    assert!(!queue.push(SearchPriority::User, target(b"e"), closed));
+    assert!(!queue.push(SearchPriority::Background, target(b"f"), nodes()));
+    assert!(!queue.push(SearchPriority::Background, target(b"g"), nodes()));
+    assert_eq!(queue.len(), 2);
+  }
+
+  #[test]
+  fn lookup_queue_drops_user_lookups_when_full() {
+    let (tx, _rx) = mpsc::unbounded_channel();
+    let nodes = || LookupKind::Nodes { tx: tx.clone() };
+
+    let mut queue = LookupQueue::new(3);
+    for index in 0..3u8 {
+      let target = InfoHash::sha1(&[index]);
+      assert!(!queue.push(SearchPriority::User, target, nodes()));
+    }
+
+    // Without a background lookup to drop, the new search is dropped.
+    let (dropped_tx, mut dropped_rx) = mpsc::unbounded_channel();
+    let dropped = LookupKind::Nodes { tx: dropped_tx };
+    assert!(queue.push(SearchPriority::User, InfoHash::sha1(b"4"), dropped));
+    assert_eq!(queue.len(), 3);
+    assert_eq!(dropped_rx.try_recv(), Err(TryRecvError::Disconnected));
+  }
+
+  #[tokio::test]
+  async fn lookup_from_nodes_asks_them_for_new_tokens() {
+    let socket = local_socket().await;
+    let mut table = RoutingTable::new(rand::random());
+    let mut timer = Timer::new();
+    let (node_socket, node) = bind_node().await;
+    let (other_socket, other) = bind_node().await;
+    table.add_node(Node::as_good(other.id, other.addr));
+
+    let info_hash = InfoHash::sha1(b"torrent");
+    let (kind, _peers_rx, mut outcome_rx) =
+      peers_lookup(SearchOptions::new().set_announce(true));
+    let mut lookup = TableLookup::from_nodes(
+      "test".to_owned(),
+      info_hash,
+      kind,
+      AIDGenerator::default().generate(),
+      vec![node],
+      &mut table,
+      &socket,
+      &mut timer,
+    )
+    .await;
+
+    // Only the cached node is queried, not the nodes of the table.
+    let (trans_id, request) = recv_request(&node_socket).await;
+    assert!(
+      matches!(request, Request::GetPeers(request) if request.info_hash == info_hash)
+    );
+    let mut buffer = [0; 1500];
+    assert!(other_socket.try_recv_from(&mut buffer).is_err());
+
+    let response = Response {
+      token: Some(b"new token".to_vec()),
+      ..Response::new(node.id)
+    };
+    let status = lookup
+      .recv_response(
+        Node::as_good(node.id, node.addr),
+        &trans_id,
+        response,
+        &mut table,
+        &socket,
+        &mut timer,
+      )
+      .await;
+    assert_eq!(status, ActionStatus::Ongoing);
+
+    // The announce uses the new token.
+    let status = lookup
+      .recv_finished(Some(6881), &mut table, &socket, &mut timer)
+      .await;
+    assert_eq!(status, ActionStatus::Ongoing);
+    let (trans_id, request) = recv_request(&node_socket).await;
+    match request {
+      Request::AnnouncePeer(request) => {
+        assert_eq!(request.token, b"new token".to_vec())
+      }
+      request => panic!("not an announce: {request:?}"),
+    }
+
+    // The new tokens are kept, and the answer held until the lookup is over.
+    let status = lookup
+      .recv_response(
+        Node::as_good(node.id, node.addr),
+        &trans_id,
+        Response::new(node.id),
+        &mut table,
+        &socket,
+        &mut timer,
+      )
+      .await;
+    assert_eq!(status, ActionStatus::Completed);
+    assert!(!lookup.searched());
+    assert!(!lookup.needs_full_lookup());
+    assert_eq!(
+      lookup.fresh_tokens(),
+      Some((info_hash, vec![(node, b"new token".to_vec())]))
+    );
+    assert_eq!(outcome_rx.try_recv(), Err(TryRecvError::Empty));
+
+    lookup.send_finished();
+    assert_eq!(
+      outcome_rx.try_recv().map(|result| result.node),
+      Ok(node.addr)
+    );
+  }
+
+  #[test]
+  fn join_search_merges_deadlines_and_announces() {
+    let first = SearchOptions::new().set_deadline(Duration::from_secs(10));
+    let (mut kind, _first_rx, _first_outcome_rx) = peers_lookup(first);
+
+    // The lookup runs until the latest deadline, counted from its start.
+    let early = SearchOptions::new().set_deadline(Duration::from_secs(1));
+    let (subscriber, _early_rx, _early_outcome_rx) = search(&early);
+    assert!(!kind.join_search(early, subscriber, Duration::from_secs(2)));
+    assert_eq!(
+      merged_options(&kind).deadline,
+      Some(Duration::from_secs(10))
+    );
+
+    let announcing = SearchOptions::new()
+      .set_announce(true)
+      .set_announce_port(7001)
+      .set_deadline(Duration::from_secs(5));
+    let (subscriber, announcing_rx, announcing_outcome_rx) =
+      search(&announcing);
+    assert!(kind.accepts_announce(&announcing));
+    assert!(kind.join_search(announcing, subscriber, Duration::from_secs(8)));
+    assert_eq!(
+      merged_options(&kind).deadline,
+      Some(Duration::from_secs(13))
+    );
+    assert!(kind.stores());
+    assert_eq!(merged_options(&kind).announce_port, Some(7001));
+
+    // A search without a deadline keeps the lookup running until it is over.
+    let endless = SearchOptions::new();
+    let (subscriber, _endless_rx, _endless_outcome_rx) = search(&endless);
+    assert!(kind.join_search(endless, subscriber, Duration::from_secs(9)));
+    assert_eq!(merged_options(&kind).deadline, None);
+
+    // The searches announcing us must agree on what to announce, until the
+    // announcing search is gone.
+    let other_port = SearchOptions::new()
+      .set_announce(true)
+      .set_announce_port(7002);
+    assert!(!kind.accepts_announce(&other_port));
+    assert!(kind.accepts_announce(&SearchOptions::new()));
+
+    drop((announcing_rx, announcing_outcome_rx));
+    kind.merge_announces();
+    assert!(!kind.stores());
+
+    assert!(kind.accepts_announce(&other_port));
+    let (subscriber, _other_rx, _other_outcome_rx) = search(&other_port);
+    kind.join_search(other_port, subscriber, Duration::from_secs(10));
+    assert!(kind.stores());
+    assert_eq!(merged_options(&kind).announce_port, Some(7002));
+  }
+
+  #[tokio::test]
+  async fn lookup_from_tokens_falls_back_after_refused_tokens() {
+    let socket = local_socket().await;
+    let mut table = RoutingTable::new(rand::random());
+    let mut timer = Timer::new();
+    let (node_socket, node) = bind_node().await;
+
+    let info_hash = InfoHash::sha1(b"torrent");
+    let mut options = SearchOptions::new()
+      .set_announce(true)
+      .set_priority(SearchPriority::Background);
+    options.announce_only = true;
+    let (kind, _peers_rx, mut outcome_rx) = peers_lookup(options);
+    let mut lookup = TableLookup::from_tokens(
+      "test".to_owned(),
+      info_hash,
+      kind,
+      AIDGenerator::default().generate(),
+      vec![(node, b"old token".to_vec())],
+      &socket,
+    );
+    assert!(lookup.completed());
+
+    let status = lookup
+      .recv_finished(Some(6881), &mut table, &socket, &mut timer)
+      .await;
+    assert_eq!(status, ActionStatus::Ongoing);
+    let (trans_id, request) = recv_request(&node_socket).await;
+    assert!(matches!(
+      request,
+      Request::AnnouncePeer(request) if request.token == b"old token"
+    ));
+
+    let error = Error {
+      code: error_code::PROTOCOL_ERROR,
+      message: "bad token".to_owned(),
+    };
+    let status = lookup
+      .recv_error(&trans_id, error, &mut table, &socket, &mut timer)
+      .await;
+    assert_eq!(status, ActionStatus::Completed);
+
+    // The search goes on with a full lookup, whose answers are the ones
+    // reported instead.
+    assert!(lookup.needs_full_lookup());
+    assert_eq!(lookup.fresh_tokens(), None);
+    let (priority, target, kind) = lookup.take_search();
+    assert_eq!(priority, SearchPriority::Background);
+    assert_eq!(target, info_hash);
+    assert!(kind.stores());
+    assert!(!merged_options(&kind).announce_only);
+
+    drop(lookup);
+    assert_eq!(outcome_rx.try_recv(), Err(TryRecvError::Empty));
+  }
+
+  #[tokio::test]
+  async fn cancelled_lookup_ignores_late_answers() {
+    let socket = local_socket().await;
+    let mut table = RoutingTable::new(rand::random());
+    let mut timer = Timer::new();
+    let (node_socket, node) = bind_node().await;
+    table.add_node(Node::as_good(node.id, node.addr));
+
+    let info_hash = InfoHash::sha1(b"torrent");
+    let options = SearchOptions::new()
+      .set_announce(true)
+      .set_deadline(Duration::from_secs(10));
+    let (kind, mut peers_rx, mut outcome_rx) = peers_lookup(options);
+    let mut lookup = TableLookup::new(
+      "test".to_owned(),
+      info_hash,
+      kind,
+      AIDGenerator::default().generate(),
+      &mut table,
+      &socket,
+      &mut timer,
+    )
+    .await;
+    let (trans_id, _) = recv_request(&node_socket).await;
+    assert!(!timer.is_empty());
+
+    // No timeout fires for a cancelled lookup.
+    lookup.cancel(&mut timer);
+    assert!(lookup.is_finished());
+    assert!(lookup.completed());
+    assert!(timer.is_empty());
+
+    // Nor are the late answers passed on or announced to.
+    let peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 6881));
+    let response = Response {
+      values: vec![peer],
+      token: Some(b"token".to_vec()),
+      ..Response::new(node.id)
+    };
+    let status = lookup
+      .recv_response(
+        Node::as_good(node.id, node.addr),
+        &trans_id,
+        response,
+        &mut table,
+        &socket,
+        &mut timer,
+      )
+      .await;
+    assert_eq!(status, ActionStatus::Completed);
+    assert_eq!(peers_rx.try_recv(), Err(TryRecvError::Empty));
+    assert_eq!(outcome_rx.try_recv(), Err(TryRecvError::Empty));
+    let mut buffer = [0; 1500];
+    assert!(node_socket.try_recv_from(&mut buffer).is_err());
+  }
+}
@@ -1,41 +1,264 @@
 #![allow(clippy::too_many_arguments)]
 
-use futures_util::Future;
 use std::{
-  collections::HashSet,
+  collections::{HashMap, HashSet},
   io,
-  net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
-  time::Duration,
+  net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6},
+  sync::Arc,
+  time::{Duration, Instant},
 };
 use thiserror::Error;
-use tokio::{
-  sync::{mpsc, oneshot},
-  task,
-};
+use tokio::sync::{mpsc, oneshot};
 use trust_dns_resolver::{
   config::{ResolverConfig, ResolverOpts},
-  AsyncResolver, Resolver, TokioAsyncResolver,
+  TokioAsyncResolver,
 };
 
-use crate::{id::InfoHash, transaction::TransactionID};
+use crate::{
+  id::{InfoHash, NodeId},
+  item::{MutableItem, PUBLIC_KEY_LEN},
+  message::{Response, Value},
+  routing::{node::NodeHandle, table::RoutingTable},
+  transaction::TransactionID,
+};
 
+mod announce;
 mod bootstrap;
+mod crawl;
 mod handler;
 mod lookup;
 mod refresh;
+mod rpc;
+mod sibling;
 mod socket;
 mod timer;
 
 // expose the `DhtHandler` and `Socket`
-pub use self::{handler::DhtHandler, socket::Socket};
+pub use self::{handler::DhtHandler, sibling::Sibling, socket::Socket};
 
-#[derive(Copy, Clone, Debug)]
+/// A snapshot of the DHT state, see
+/// [`MainlineDht::get_state`](crate::MainlineDht::get_state).
+///
+/// `State` is not `Copy`, since it owns the per client version counts of
+/// [`State::client_versions`].
+#[derive(Clone, Debug)]
 pub struct State {
   pub is_running: bool,
   pub bootstrapped: bool,
   pub good_node_count: usize,
   pub questionable_node_count: usize,
   pub bucket_count: usize,
+  /// Number of KRPC errors the other nodes answered us with.
+  pub error_count: usize,
+  /// Number of nodes in the routing table per "v" client version.
+  pub client_versions: HashMap<Vec<u8>, usize>,
+  /// Number of lookups running.
+  pub running_lookups: usize,
+  /// Number of lookups waiting for a running lookup to complete.
+  pub pending_lookups: usize,
+}
+
+/// Which searches start first when too many lookups are running, see
+/// [`DhtBuilder::set_max_concurrent_lookups`](crate::DhtBuilder::set_max_concurrent_lookups).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SearchPriority {
+  /// A search the user is waiting for.
+  #[default]
+  User,
+  /// A search nobody waits for, like a periodic announce.
+  Background,
+}
+
+/// Options of a search for the peers of an InfoHash, see
+/// [`MainlineDht::search_with`](crate::MainlineDht::search_with).
+#[derive(Clone, Debug, Default)]
+pub struct SearchOptions {
+  pub(crate) announce: bool,
+  pub(crate) announce_port: Option<u16>,
+  pub(crate) implied_port: bool,
+  pub(crate) seed: bool,
+  pub(crate) max_peers: Option<usize>,
+  pub(crate) deadline: Option<Duration>,
+  pub(crate) ip_version: Option<IpVersion>,
+  pub(crate) priority: SearchPriority,
+  // Whether nobody waits for the peers, so the announce may skip the search.
+  pub(crate) announce_only: bool,
+}
+
+impl SearchOptions {
+  /// Search without announcing, until the closest nodes were queried.
+  pub fn new() -> SearchOptions {
+    SearchOptions::default()
+  }
+
+  /// Announce ourselves on the closest nodes once the search is over.
+  pub fn set_announce(mut self, announce: bool) -> SearchOptions {
+    self.announce = announce;
+    self
+  }
+
+  /// The port to announce, instead of the one of
+  /// [`DhtBuilder::set_announce_port`](crate::DhtBuilder::set_announce_port).
+  pub fn set_announce_port(mut self, port: u16) -> SearchOptions {
+    self.announce_port = Some(port);
+    self
+  }
+
+  /// Let the nodes store the source port of our announces (implied port),
+  /// even if an announce port is set.
+  pub fn set_implied_port(mut self, implied_port: bool) -> SearchOptions {
+    self.implied_port = implied_port;
+    self
+  }
+
+  /// Announce ourselves as a seed of the torrent (BEP33).
+  pub fn set_seed(mut self, seed: bool) -> SearchOptions {
+    self.seed = seed;
+    self
+  }
+
+  /// Stop the search once this many peers were found.
+  pub fn set_max_peers(mut self, max_peers: usize) -> SearchOptions {
+    self.max_peers = Some(max_peers);
+    self
+  }
+
+  /// Stop the search after this long, the announce then goes to the closest
+  /// nodes found so far.
+  pub fn set_deadline(mut self, deadline: Duration) -> SearchOptions {
+    self.deadline = Some(deadline);
+    self
+  }
+
+  /// Only return the peers of this address family.
+  pub fn set_ip_version(mut self, ip_version: IpVersion) -> SearchOptions {
+    self.ip_version = Some(ip_version);
+    self
+  }
+
+  /// Start the search before the queued ones of a lower priority.
+  pub fn set_priority(mut self, priority: SearchPriority) -> SearchOptions {
+    self.priority = priority;
+    self
+  }
+
+  pub(crate) fn set_announce_only(
+    mut self,
+    announce_only: bool,
+  ) -> SearchOptions {
+    self.announce_only = announce_only;
+    self
+  }
+
+  /// The port to put in our announces, `None` for the implied port.
+  pub(crate) fn port(&self, default_port: Option<u16>) -> Option<u16> {
+    if self.implied_port {
+      None
+    } else {
+      self.announce_port.or(default_port)
+    }
+  }
+}
+
+/// Progress of a search, see
+/// [`MainlineDht::search_events`](crate::MainlineDht::search_events).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SearchEvent {
+  /// A node returned a peer we did not know yet.
+  PeerFound { peer: SocketAddr, node: NodeHandle },
+  /// We sent a lookup request to the node.
+  NodeQueried(NodeHandle),
+  /// The node answered our lookup request, possibly with an error.
+  NodeResponded(NodeHandle),
+  /// The node did not answer our lookup request in time.
+  NodeTimedOut(NodeHandle),
+  /// We ran out of closer nodes, the remaining known nodes are queried.
+  EndgameStarted,
+  /// We announced ourselves to the node.
+  AnnounceSent(NodeHandle),
+  /// The node answered our announce, or did not in time.
+  AnnounceAcknowledged(AnnounceResult),
+  /// The search completed, this is the last event. A search aborted by the
+  /// shutdown of the DHT ends without it.
+  Finished(SearchStats),
+}
+
+/// Summary of a finished search.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SearchStats {
+  /// Number of lookup requests sent.
+  pub nodes_contacted: usize,
+  /// Time from the start of the search to the end of the announces.
+  pub duration: Duration,
+  /// Number of distinct peers found.
+  pub peers_found: usize,
+}
+
+/// Estimated size of a swarm (BEP33).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Scrape {
+  /// Number of peers which have the complete torrent.
+  pub seeds: usize,
+  /// Number of the other peers.
+  pub peers: usize,
+}
+
+/// What a node answered to one of our announces.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AnnounceOutcome {
+  /// The node stored us as a peer.
+  Accepted,
+  /// The node answered with a KRPC error, e.g. 203 for an invalid token.
+  Rejected { code: u16, message: String },
+  /// The node did not answer in time.
+  TimedOut,
+}
+
+/// The outcome of an announce on a single node.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AnnounceResult {
+  pub node: SocketAddr,
+  pub outcome: AnnounceOutcome,
+}
+
+/// Where we stand with an InfoHash announced periodically, see
+/// [`MainlineDht::start_announcing`](crate::MainlineDht::start_announcing).
+#[derive(Clone, Debug)]
+pub struct AnnounceStatus {
+  /// The port we announce.
+  pub port: u16,
+  /// Whether an announce is in progress.
+  pub announcing: bool,
+  /// When a node last accepted our announce.
+  pub last_success: Option<Instant>,
+  /// What the nodes answered to the last announce which is over.
+  pub last_results: Vec<AnnounceResult>,
+  /// When the next announce starts, unless one is in progress.
+  pub next_announce: Option<Instant>,
+}
+
+/// Called with the routing table once the DHT stopped, to save its state, see
+/// [`DhtBuilder::add_shutdown_hook`](crate::DhtBuilder::add_shutdown_hook).
+#[derive(Clone)]
+pub struct ShutdownHook(Arc<dyn Fn(&RoutingTable) + Send + Sync>);
+
+impl ShutdownHook {
+  pub(crate) fn new<F>(hook: F) -> Self
+  where
+    F: Fn(&RoutingTable) + Send + Sync + 'static,
+  {
+    ShutdownHook(Arc::new(hook))
+  }
+
+  pub(crate) fn call(&self, table: &RoutingTable) {
+    (self.0)(table)
+  }
+}
+
+impl std::fmt::Debug for ShutdownHook {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("ShutdownHook").finish_non_exhaustive()
+  }
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
@@ -63,6 +286,29 @@ pub enum OneShotTask {
   CheckBootstrap(oneshot::Sender<bool>, Option<Duration>),
   /// Start a lookup for the given InfoHash.
   StartLookup(StartLookup),
+  /// Start a lookup for the data item stored under the given target.
+  StartItemLookup(StartItemLookup),
+  /// Start a lookup for the mutable item of the given public key.
+  StartMutableItemLookup(StartMutableItemLookup),
+  /// Start a lookup estimating the swarm size of the given InfoHash.
+  StartScrape(StartScrape),
+  /// Start a lookup for the nodes closest to the given target.
+  StartNodesLookup(StartNodesLookup),
+  /// Start sampling the info hashes stored by the nodes (BEP51).
+  StartCrawl(mpsc::UnboundedSender<InfoHash>),
+  /// Send a request to a single node.
+  StartRpc(StartRpc),
+  /// Announce us for the InfoHash on the port periodically.
+  StartAnnouncing(InfoHash, u16),
+  /// Stop announcing us for the InfoHash.
+  StopAnnouncing(InfoHash),
+  /// Get the status of the periodic announce of the InfoHash.
+  GetAnnounceStatus(InfoHash, oneshot::Sender<Option<AnnounceStatus>>),
+  /// Add the nodes learned by the other instance of a dual-stack DHT.
+  AddNodes(Vec<NodeHandle>),
+  /// Stop the DHT, after letting the lookups which announce or store finish
+  /// for at most the given time.
+  Shutdown(Option<Duration>),
   /// Get the local address the socket is bound to.
   GetLocalAddr(oneshot::Sender<SocketAddr>),
   /// Retrieve debug information
@@ -77,6 +323,19 @@ impl std::fmt::Display for OneShotTask {
       OneShotTask::StartBootstrap() => write!(f, "StartBootstrap"),
       OneShotTask::CheckBootstrap(_, _) => write!(f, "CheckBootstrap"),
       OneShotTask::StartLookup(_) => write!(f, "StartLookup"),
+      OneShotTask::StartItemLookup(_) => write!(f, "StartItemLookup"),
+      OneShotTask::StartMutableItemLookup(_) => {
+        write!(f, "StartMutableItemLookup")
+      }
+      OneShotTask::StartScrape(_) => write!(f, "StartScrape"),
+      OneShotTask::StartNodesLookup(_) => write!(f, "StartNodesLookup"),
+      OneShotTask::StartCrawl(_) => write!(f, "StartCrawl"),
+      OneShotTask::StartRpc(_) => write!(f, "StartRpc"),
+      OneShotTask::StartAnnouncing(_, _) => write!(f, "StartAnnouncing"),
+      OneShotTask::StopAnnouncing(_) => write!(f, "StopAnnouncing"),
+      OneShotTask::GetAnnounceStatus(_, _) => write!(f, "GetAnnounceStatus"),
+      OneShotTask::AddNodes(_) => write!(f, "AddNodes"),
+      OneShotTask::Shutdown(_) => write!(f, "Shutdown"),
       OneShotTask::GetLocalAddr(_) => write!(f, "GetLocalAddr"),
       OneShotTask::GetState(_) => write!(f, "GetState"),
       OneShotTask::GetNodes(_) => write!(f, "GetNodes"),
@@ -86,8 +345,78 @@ impl std::fmt::Display for OneShotTask {
 
 pub struct StartLookup {
   pub info_hash: InfoHash,
-  pub announce: bool,
+  pub options: SearchOptions,
   pub tx: mpsc::UnboundedSender<SocketAddr>,
+  /// Report what each node answered to the announce.
+  pub outcome_tx: Option<mpsc::UnboundedSender<AnnounceResult>>,
+  /// Report the progress of the search.
+  pub events_tx: Option<mpsc::UnboundedSender<SearchEvent>>,
+}
+
+pub struct StartScrape {
+  pub info_hash: InfoHash,
+  pub tx: mpsc::UnboundedSender<Scrape>,
+}
+
+pub struct StartNodesLookup {
+  pub target: NodeId,
+  pub tx: mpsc::UnboundedSender<Vec<NodeHandle>>,
+}
+
+pub struct StartItemLookup {
+  pub target: InfoHash,
+  /// Store this value on the closest nodes once the lookup finished.
+  pub put: Option<Value>,
+  pub tx: mpsc::UnboundedSender<Value>,
+  /// Send what each node answered to the put through this channel.
+  pub outcome_tx: Option<mpsc::UnboundedSender<AnnounceResult>>,
+}
+
+pub struct StartMutableItemLookup {
+  pub public_key: [u8; PUBLIC_KEY_LEN],
+  pub salt: Option<Vec<u8>>,
+  /// Store this item on the closest nodes once the lookup finished.
+  pub put: Option<MutableItem>,
+  pub cas: Option<i64>,
+  pub tx: mpsc::UnboundedSender<MutableItem>,
+  /// Send what each node answered to the put through this channel.
+  pub outcome_tx: Option<mpsc::UnboundedSender<AnnounceResult>>,
+}
+
+pub struct StartRpc {
+  pub addr: SocketAddr,
+  pub request: RpcRequest,
+  pub tx: oneshot::Sender<Result<Response, RpcError>>,
+}
+
+/// The requests which can be sent to a single node, our node id is filled in
+/// by the handler.
+pub enum RpcRequest {
+  Ping,
+  FindNode {
+    target: NodeId,
+  },
+  GetPeers {
+    info_hash: InfoHash,
+  },
+  AnnouncePeer {
+    info_hash: InfoHash,
+    token: Vec<u8>,
+    port: Option<u16>,
+  },
+}
+
+/// Why a request sent to a single node failed.
+#[derive(Error, Debug)]
+pub enum RpcError {
+  #[error("the node did not answer in time")]
+  Timeout,
+  #[error("the node answered with error {}: {}", .0.code, .0.message)]
+  Remote(crate::message::Error),
+  #[error("socket error")]
+  Socket(#[source] io::Error),
+  #[error("DhtHandler has shutdown")]
+  Shutdown,
 }
 
 /// Signifies what has timed out in the TableBootstrap class.
@@ -110,6 +439,20 @@ pub enum ScheduledTaskCheck {
   LookupTimeout(TransactionID),
   /// Check the progress of the lookup endgame.
   LookupEndGame(TransactionID),
+  /// Stop a lookup which ran out of time.
+  LookupDeadline(TransactionID),
+  /// Check the progress of a sample_infohashes crawl.
+  CrawlTimeout(TransactionID),
+  /// Start a new round of an idle crawl.
+  CrawlWakeUp(TransactionID),
+  /// Check the answer to a single node request.
+  RpcTimeout(TransactionID),
+  /// Announce again an InfoHash announced periodically.
+  Reannounce(InfoHash),
+  /// Tell the sibling instance of a dual-stack DHT about our nodes.
+  PublishNodes,
+  /// Stop waiting for the announces before shutting down.
+  ShutdownTimeout,
 }
 
 impl std::fmt::Display for ScheduledTaskCheck {
@@ -122,6 +465,13 @@ impl std::fmt::Display for ScheduledTaskCheck {
       }
       ScheduledTaskCheck::LookupTimeout(_) => write!(f, "LookupTimeout"),
       ScheduledTaskCheck::LookupEndGame(_) => write!(f, "LookupEndgame"),
+      ScheduledTaskCheck::LookupDeadline(_) => write!(f, "LookupDeadline"),
+      ScheduledTaskCheck::CrawlTimeout(_) => write!(f, "CrawlTimeout"),
+      ScheduledTaskCheck::CrawlWakeUp(_) => write!(f, "CrawlWakeUp"),
+      ScheduledTaskCheck::RpcTimeout(_) => write!(f, "RpcTimeout"),
+      ScheduledTaskCheck::Reannounce(_) => write!(f, "Reannounce"),
+      ScheduledTaskCheck::PublishNodes => write!(f, "PublishNodes"),
+      ScheduledTaskCheck::ShutdownTimeout => write!(f, "ShutdownTimeout"),
     }
   }
 }
@@ -1,9 +1,10 @@
 use std::time::Duration;
 
 use crate::{
+  id::NodeId,
   message::{FindNodeRequest, Message, MessageBody, Request},
   routing::{
-    node::NodeStatus,
+    node::{NodeHandle, NodeStatus},
     table::{self, RoutingTable},
   },
   transaction::{ActionID, MIDGenerator},
@@ -72,6 +73,23 @@ impl TableRefresh {
       .collect::<Vec<_>>();
 
     // Ping the closest questionable nodes.
+    self.query_nodes(&nodes, target_id, table, socket).await;
+
+    timer
+      .schedule_in(REFRESH_INTERVAL_TIMEOUT, ScheduledTaskCheck::TableRefresh);
+
+    self.current_refresh_bucket += 1;
+  }
+
+  /// Ask the given nodes of our routing table for the nodes closest to the
+  /// target, the nodes which answer become good.
+  pub async fn query_nodes(
+    &mut self,
+    nodes: &[NodeHandle],
+    target_id: NodeId,
+    table: &mut RoutingTable,
+    socket: &Socket,
+  ) {
     for node in nodes {
       // Generate a transaction id for the request.
       let trans_id = self.id_generator.generate();
@@ -80,10 +98,13 @@ impl TableRefresh {
       let find_node_req = FindNodeRequest {
         id: table.node_id(),
         target: target_id,
-        want: None,
+        want: socket.want(),
       };
       let find_node_msg = Message {
         transaction_id: trans_id.as_ref().to_vec(),
+        ip: None,
+        read_only: socket.read_only(),
+        version: socket.client_version().map(Vec::from),
         body: MessageBody::Request(Request::FindNode(find_node_req)),
       };
       let find_node_msg = find_node_msg.encode();
@@ -94,14 +115,9 @@ impl TableRefresh {
       }
 
       // Mark that we requested from the node.
-      if let Some(node) = table.find_node_mut(&node) {
+      if let Some(node) = table.find_node_mut(node) {
         node.local_request();
       }
     }
-
-    timer
-      .schedule_in(REFRESH_INTERVAL_TIMEOUT, ScheduledTaskCheck::TableRefresh);
-
-    self.current_refresh_bucket += 1;
   }
 }
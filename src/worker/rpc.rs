@@ -0,0 +1,137 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use tokio::sync::oneshot;
+
+use crate::{
+  message::{Error, Message, MessageBody, Request, Response},
+  routing::table::RoutingTable,
+  transaction::{ActionID, MIDGenerator, TransactionID},
+  IpVersion,
+};
+
+use super::{
+  socket::Socket,
+  timer::{Timeout, Timer},
+  RpcError, ScheduledTaskCheck,
+};
+
+const RPC_TIMEOUT: Duration = Duration::from_millis(1500);
+
+type RpcSender = oneshot::Sender<Result<Response, RpcError>>;
+
+/// Requests sent to a single node on behalf of the user, each answered with
+/// the response of the node.
+pub struct NodeRpcs {
+  name: String,
+  ip_version: IpVersion,
+  id_generator: MIDGenerator,
+  active_requests: HashMap<TransactionID, (RpcSender, Timeout)>,
+}
+
+impl NodeRpcs {
+  pub fn new(
+    name: String,
+    ip_version: IpVersion,
+    id_generator: MIDGenerator,
+  ) -> NodeRpcs {
+    NodeRpcs {
+      name,
+      ip_version,
+      id_generator,
+      active_requests: HashMap::new(),
+    }
+  }
+
+  pub fn action_id(&self) -> ActionID {
+    self.id_generator.action_id()
+  }
+
+  pub async fn send(
+    &mut self,
+    addr: SocketAddr,
+    request: Request,
+    tx: RpcSender,
+    table: &mut RoutingTable,
+    socket: &Socket,
+    timer: &mut Timer<ScheduledTaskCheck>,
+  ) {
+    let trans_id = self.id_generator.generate();
+    let msg = Message {
+      transaction_id: trans_id.as_ref().to_vec(),
+      ip: None,
+      read_only: socket.read_only(),
+      version: socket.client_version().map(Vec::from),
+      body: MessageBody::Request(request),
+    }
+    .encode();
+
+    if let Err(error) = socket.send(&msg, addr).await {
+      log::error!(
+        "[{}] {}: Could not send a request to {}: {}",
+        self.name,
+        self.ip_version,
+        addr,
+        error
+      );
+      tx.send(Err(RpcError::Socket(error))).unwrap_or(());
+      return;
+    }
+
+    let timeout =
+      timer.schedule_in(RPC_TIMEOUT, ScheduledTaskCheck::RpcTimeout(trans_id));
+    self.active_requests.insert(trans_id, (tx, timeout));
+
+    // We requested from the node, mark it down if the node is in our routing table
+    if let Some(n) = table.find_node_by_addr_mut(addr) {
+      n.local_request()
+    }
+  }
+
+  pub fn recv_response(
+    &mut self,
+    trans_id: &TransactionID,
+    rsp: Response,
+    timer: &mut Timer<ScheduledTaskCheck>,
+  ) {
+    self.complete(trans_id, Ok(rsp), timer)
+  }
+
+  /// The node answered our request with a KRPC error.
+  pub fn recv_error(
+    &mut self,
+    trans_id: &TransactionID,
+    error: Error,
+    timer: &mut Timer<ScheduledTaskCheck>,
+  ) {
+    self.complete(trans_id, Err(RpcError::Remote(error)), timer)
+  }
+
+  pub fn recv_timeout(&mut self, trans_id: &TransactionID) {
+    if let Some((tx, _)) = self.active_requests.remove(trans_id) {
+      tx.send(Err(RpcError::Timeout)).unwrap_or(())
+    }
+  }
+
+  fn complete(
+    &mut self,
+    trans_id: &TransactionID,
+    result: Result<Response, RpcError>,
+    timer: &mut Timer<ScheduledTaskCheck>,
+  ) {
+    let (tx, timeout) = if let Some(request) =
+      self.active_requests.remove(trans_id)
+    {
+      request
+    } else {
+      log::debug!(
+        "[{}] {}: Received expired/unsolicited node response for a single node request",
+        self.name,
+        self.ip_version
+      );
+      return;
+    };
+    timer.cancel(timeout);
+
+    tx.send(result).unwrap_or(())
+  }
+}
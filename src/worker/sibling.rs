@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use tokio::sync::{mpsc, watch};
+
+use crate::{
+  id::InfoHash,
+  routing::{
+    node::{NodeHandle, NodeStatus},
+    table::RoutingTable,
+  },
+};
+
+use super::{timer::Timer, OneShotTask, ScheduledTaskCheck};
+
+/// How often we tell the sibling about the good nodes of our routing table.
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(30);
+/// How often we look again while we have no good node to tell about.
+const EMPTY_PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of nodes of the other address family put in a response.
+const MAX_CLOSEST_NODES: usize = 8;
+/// Number of nodes of a response handed to the sibling, as many as a node
+/// normally puts in a response.
+const MAX_ADDED_NODES: usize = 8;
+
+/// The instance of the other address family in a dual-stack DHT (BEP32).
+///
+/// The nodes we learn of the other family are handed to it, and its good
+/// nodes are used to answer the requests which want both families.
+pub struct Sibling {
+  // Weak so the instances do not keep each other running.
+  commands: mpsc::WeakUnboundedSender<OneShotTask>,
+  // The good nodes of the sibling's routing table.
+  nodes: watch::Receiver<Vec<NodeHandle>>,
+  // The good nodes of our routing table, for the sibling.
+  our_nodes: watch::Sender<Vec<NodeHandle>>,
+}
+
+impl Sibling {
+  /// Link the two instances of a dual-stack DHT, given the senders of their
+  /// commands.
+  pub fn pair(
+    a: &mpsc::UnboundedSender<OneShotTask>,
+    b: &mpsc::UnboundedSender<OneShotTask>,
+  ) -> (Sibling, Sibling) {
+    let (a_nodes_tx, a_nodes_rx) = watch::channel(Vec::new());
+    let (b_nodes_tx, b_nodes_rx) = watch::channel(Vec::new());
+
+    let a_sibling = Sibling {
+      commands: b.downgrade(),
+      nodes: b_nodes_rx,
+      our_nodes: a_nodes_tx,
+    };
+    let b_sibling = Sibling {
+      commands: a.downgrade(),
+      nodes: a_nodes_rx,
+      our_nodes: b_nodes_tx,
+    };
+
+    (a_sibling, b_sibling)
+  }
+
+  /// Hand the nodes of the other address family found in a response to the
+  /// sibling, which adds them to its routing table.
+  pub fn add_nodes(&self, nodes: &[NodeHandle]) {
+    if nodes.is_empty() {
+      return;
+    }
+
+    let nodes = &nodes[..nodes.len().min(MAX_ADDED_NODES)];
+    if let Some(commands) = self.commands.upgrade() {
+      commands
+        .send(OneShotTask::AddNodes(nodes.to_vec()))
+        .unwrap_or(());
+    }
+  }
+
+  /// The good nodes of the sibling closest to the target.
+  pub fn closest_nodes(&self, target: InfoHash) -> Vec<NodeHandle> {
+    let mut nodes = self.nodes.borrow().clone();
+    nodes.sort_by_key(|node| node.id ^ target);
+    nodes.truncate(MAX_CLOSEST_NODES);
+    nodes
+  }
+
+  /// Tell the sibling about the good nodes of our routing table if they
+  /// changed, and again after `PUBLISH_INTERVAL`, or sooner while we have
+  /// none.
+  pub fn publish_nodes(
+    &self,
+    table: &RoutingTable,
+    timer: &mut Timer<ScheduledTaskCheck>,
+  ) {
+    let nodes: Vec<_> = table
+      .closest_nodes(table.node_id())
+      .filter(|node| node.status() == NodeStatus::Good)
+      .map(|node| *node.handle())
+      .collect();
+
+    let interval = if nodes.is_empty() {
+      EMPTY_PUBLISH_INTERVAL
+    } else {
+      PUBLISH_INTERVAL
+    };
+
+    self.our_nodes.send_if_modified(|our_nodes| {
+      if *our_nodes == nodes {
+        false
+      } else {
+        *our_nodes = nodes;
+        true
+      }
+    });
+    timer.schedule_in(interval, ScheduledTaskCheck::PublishNodes);
+  }
+}
@@ -2,41 +2,77 @@ use async_trait::async_trait;
 use std::{io, net::SocketAddr};
 use tokio::net::UdpSocket;
 
-use crate::{IpVersion, SocketTrait};
+use crate::{message::Want, IpVersion, SocketTrait};
 
-pub struct Socket(Box<dyn SocketTrait + Send + Sync + 'static>, SocketAddr);
+pub struct Socket {
+  inner: Box<dyn SocketTrait + Send + Sync + 'static>,
+  local_addr: SocketAddr,
+  read_only: bool,
+  client_version: Option<Vec<u8>>,
+  want: Option<Want>,
+}
 
 impl Socket {
   pub fn new<S: SocketTrait + Send + Sync + 'static>(
     inner: S,
+    read_only: bool,
+    client_version: Option<Vec<u8>>,
   ) -> io::Result<Self> {
     let inner = Box::new(inner);
     let local_addr = inner.local_addr()?;
-    Ok(Socket(inner, local_addr))
+    Ok(Socket {
+      inner,
+      local_addr,
+      read_only,
+      client_version,
+      want: None,
+    })
+  }
+
+  /// Ask for the contacts of both address families in our queries, for the
+  /// sockets of a dual-stack DHT (BEP32).
+  pub fn set_dual_stack(&mut self) {
+    self.want = Some(Want::Both);
   }
 
   pub async fn send(&self, bytes: &[u8], addr: SocketAddr) -> io::Result<()> {
     // Note: if the socket fails to send the entire buffer, then there is no
     // point in trying to send the rest (no node will attempt to reassemble two or
     // more datagrams into a meaningful message).
-    self.0.send_to(bytes, &addr).await?;
+    self.inner.send_to(bytes, &addr).await?;
     Ok(())
   }
 
   /// This function is cancel safe.
   pub async fn recv(&mut self) -> io::Result<(Vec<u8>, SocketAddr)> {
     let mut buffer = vec![0u8; 1500];
-    let (size, addr) = self.0.recv_from(&mut buffer).await?;
+    let (size, addr) = self.inner.recv_from(&mut buffer).await?;
     buffer.truncate(size);
     Ok((buffer, addr))
   }
 
   pub fn local_addr(&self) -> SocketAddr {
-    self.1
+    self.local_addr
+  }
+
+  /// Whether the queries sent through this socket are flagged read-only (BEP43).
+  pub fn read_only(&self) -> bool {
+    self.read_only
+  }
+
+  /// The "v" client version sent with the messages.
+  pub fn client_version(&self) -> Option<&[u8]> {
+    self.client_version.as_deref()
+  }
+
+  /// The address families of the contacts asked for in our queries, `None`
+  /// for the family of the socket only.
+  pub fn want(&self) -> Option<Want> {
+    self.want
   }
 
   pub fn ip_version(&self) -> IpVersion {
-    match self.1 {
+    match self.local_addr {
       SocketAddr::V4(_) => IpVersion::V4,
       SocketAddr::V6(_) => IpVersion::V6,
     }
@@ -1,6 +1,15 @@
-use bt_rust_dht::{InfoHash, MainlineDht};
+use bt_rust_dht::{
+  item::{MutableItem, SigningKey},
+  message::{error_code, Message, MessageBody, Value},
+  resolver, AnnounceOutcome, AnnounceResult, InfoHash, IpVersion, MainlineDht,
+  NodeId, RpcError, SavedTable, Scrape, SearchEvent, SearchOptions,
+  SearchPriority,
+};
 use futures_util::StreamExt;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::{
+  net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+  time::{Duration, Instant},
+};
 use tokio::net::UdpSocket;
 
 use pretty_assertions::assert_eq;
@@ -56,6 +65,10 @@ async fn announce_and_lookup(addr_family: AddrFamily) {
   assert!(a_node.bootstrapped(None).await);
   assert!(b_node.bootstrapped(None).await);
 
+  // Make sure both nodes have learned about the router before searching.
+  wait_for_nodes(&a_node).await;
+  wait_for_nodes(&b_node).await;
+
   let the_info_hash = InfoHash::sha1(b"foo");
 
   // Perform a lookup with announce by A. It should not return any peers initially but it should
@@ -76,6 +89,844 @@ async fn announce_and_lookup(addr_family: AddrFamily) {
   assert_eq!(search.next().await, Some(b_addr));
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn put_and_get_immutable() {
+  let (_router, a_node, b_node) = start_network(AddrFamily::V4).await;
+
+  let value = Value::Bytes(b"Hello World!".to_vec());
+
+  // A stores the item on the closest nodes, B can then retrieve it.
+  let target = a_node.put_immutable(value.clone()).await.unwrap();
+  assert_eq!(target, InfoHash::sha1(b"12:Hello World!"));
+
+  assert_eq!(b_node.get_immutable(target).await, Some(value));
+  assert_eq!(b_node.get_immutable(InfoHash::sha1(b"missing")).await, None);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn put_and_get_mutable() {
+  let (_router, a_node, b_node) = start_network(AddrFamily::V4).await;
+
+  let signing_key = SigningKey::from_bytes(&[1u8; 32]);
+  let salt = Some(b"config".to_vec());
+  let first = MutableItem::sign(
+    Value::Bytes(b"first".to_vec()),
+    &signing_key,
+    1,
+    salt.clone(),
+  )
+  .unwrap();
+
+  // A publishes the item, B can then retrieve it by the public key.
+  let target = a_node.put_mutable(first.clone(), None).await.unwrap();
+  assert_eq!(target, first.target());
+  assert_eq!(
+    b_node.get_mutable(first.public_key, salt.clone()).await,
+    Some(first.clone())
+  );
+
+  // A updates the item, B retrieves the new version.
+  let second = MutableItem::sign(
+    Value::Bytes(b"second".to_vec()),
+    &signing_key,
+    2,
+    salt.clone(),
+  )
+  .unwrap();
+  a_node.put_mutable(second.clone(), Some(1)).await.unwrap();
+  assert_eq!(
+    b_node.get_mutable(first.public_key, salt.clone()).await,
+    Some(second.clone())
+  );
+
+  // The nodes refuse a put expecting a version they no longer store.
+  let third = MutableItem::sign(
+    Value::Bytes(b"third".to_vec()),
+    &signing_key,
+    3,
+    salt.clone(),
+  )
+  .unwrap();
+  assert!(a_node.put_mutable(third, Some(1)).await.is_err());
+  assert_eq!(
+    b_node.get_mutable(first.public_key, salt).await,
+    Some(second)
+  );
+
+  // Without the salt the item is stored under another target.
+  assert_eq!(b_node.get_mutable(first.public_key, None).await, None);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resolve_mutable_torrent() {
+  let (router, a_node, b_node) = start_network(AddrFamily::V4).await;
+  let a_addr = a_node.local_addr().await.unwrap();
+  let router_addr = router.local_addr().await.unwrap();
+
+  // A seeds the release and publishes its info hash under its key.
+  let the_info_hash = InfoHash::sha1(b"release-1");
+  let mut search = a_node.search(the_info_hash, true);
+  assert_eq!(search.next().await, None);
+
+  let signing_key = SigningKey::from_bytes(&[2u8; 32]);
+  let item = MutableItem::sign(
+    resolver::info_hash_value(the_info_hash),
+    &signing_key,
+    1,
+    None,
+  )
+  .unwrap();
+  a_node.put_mutable(item.clone(), None).await.unwrap();
+
+  // B follows the key and finds A.
+  let mut resolve =
+    b_node.resolve(item.public_key, None, Duration::from_secs(1));
+  assert_eq!(resolve.next().await, Some((the_info_hash, a_addr)));
+
+  // The peers which join later are found by the next searches, although the
+  // item did not change.
+  let mut search = router.search(the_info_hash, true);
+  while search.next().await.is_some() {}
+
+  let later_peer = (the_info_hash, router_addr);
+  let found = tokio::time::timeout(Duration::from_secs(10), async {
+    while let Some(found) = resolve.next().await {
+      if found == later_peer {
+        return true;
+      }
+    }
+    false
+  })
+  .await;
+  assert_eq!(found, Ok(true));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn crawl_infohashes() {
+  let (_router, a_node, b_node) = start_network(AddrFamily::V4).await;
+
+  // A announces the info hash, the nodes it announced to can be sampled by B.
+  let the_info_hash = InfoHash::sha1(b"crawled");
+  let mut search = a_node.search(the_info_hash, true);
+  assert_eq!(search.next().await, None);
+
+  let mut crawl = b_node.crawl_infohashes();
+  let found = tokio::time::timeout(Duration::from_secs(10), crawl.next())
+    .await
+    .unwrap();
+  assert_eq!(found, Some(the_info_hash));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn scrape_swarm() {
+  let (_router, a_node, b_node) = start_network(AddrFamily::V4).await;
+
+  // A announces itself as a peer of the info hash, B then estimates the swarm.
+  let the_info_hash = InfoHash::sha1(b"scraped");
+  let mut search = a_node.search(the_info_hash, true);
+  assert_eq!(search.next().await, None);
+
+  assert_eq!(
+    b_node.scrape(the_info_hash).await,
+    Some(Scrape { seeds: 0, peers: 1 })
+  );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn announce_outcomes() {
+  let (_router, a_node, b_node) = start_network(AddrFamily::V4).await;
+  let b_addr = b_node.local_addr().await.unwrap();
+
+  let the_info_hash = InfoHash::sha1(b"announced");
+  let results = a_node.announce(the_info_hash).await.unwrap();
+
+  assert!(!results.is_empty());
+  assert!(results.iter().any(|result| result.node == b_addr));
+  for result in results {
+    assert_eq!(result.outcome, AnnounceOutcome::Accepted);
+  }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unknown_query() {
+  let (router, _a_node, _b_node) = start_network(AddrFamily::V4).await;
+  let router_addr = router.local_addr().await.unwrap();
+  let socket = UdpSocket::bind(localhost(AddrFamily::V4)).await.unwrap();
+  let mut buffer = [0u8; 1500];
+
+  // Without a target, the query is rejected.
+  let query = b"d1:ad2:id20:abcdefghij0123456789e1:q7:vote_me1:t2:aa1:y1:qe";
+  socket.send_to(query, router_addr).await.unwrap();
+  let (len, _) = socket.recv_from(&mut buffer).await.unwrap();
+
+  let message = Message::decode(&buffer[..len]).unwrap();
+  let MessageBody::Error(error) = message.body else {
+    panic!("not an error: {:?}", message.body);
+  };
+  assert_eq!(error.code, error_code::METHOD_UNKNOWN);
+
+  // With a target, it is answered as a find_node query.
+  let query = b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q7:vote_me1:t2:ab1:y1:qe";
+  socket.send_to(query, router_addr).await.unwrap();
+  let (len, _) = socket.recv_from(&mut buffer).await.unwrap();
+
+  let message = Message::decode(&buffer[..len]).unwrap();
+  let MessageBody::Response(response) = message.body else {
+    panic!("not a response: {:?}", message.body);
+  };
+  assert!(!response.nodes_v4.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn client_versions() {
+  let (router, _a_node, _b_node) = start_network(AddrFamily::V4).await;
+  let router_addr = router.local_addr().await.unwrap();
+
+  // C sends its client version, the router records it once C is in its table.
+  let c_socket = UdpSocket::bind(localhost(AddrFamily::V4)).await.unwrap();
+  let c_node = MainlineDht::builder()
+    .add_node(router_addr)
+    .set_read_only(false)
+    .set_client_version(b"RS\x00\x01".to_vec())
+    .start("c_node", c_socket)
+    .unwrap();
+
+  assert!(c_node.bootstrapped(None).await);
+  wait_for_nodes(&c_node).await;
+
+  let state = router.get_state().await.unwrap();
+  assert_eq!(state.client_versions.get(&b"RS\x00\x01"[..]), Some(&1));
+  assert_eq!(state.client_versions.len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn single_node_rpcs() {
+  let (_router, a_node, b_node) = start_network(AddrFamily::V4).await;
+  let a_addr = a_node.local_addr().await.unwrap();
+  let b_addr = b_node.local_addr().await.unwrap();
+
+  a_node.ping(b_addr).await.unwrap();
+
+  let response = a_node.find_node(b_addr, rand::random()).await.unwrap();
+  assert!(!response.nodes_v4.is_empty());
+
+  // Announce ourselves with the token of the get_peers, B then returns us.
+  let the_info_hash = InfoHash::sha1(b"single node");
+  let response = a_node.get_peers(b_addr, the_info_hash).await.unwrap();
+  let token = response.token.unwrap();
+  a_node
+    .announce_peer(b_addr, the_info_hash, token, Some(6881))
+    .await
+    .unwrap();
+
+  let response = a_node.get_peers(b_addr, the_info_hash).await.unwrap();
+  assert_eq!(response.values, vec![SocketAddr::new(a_addr.ip(), 6881)]);
+
+  // A bad token is rejected.
+  let error = a_node
+    .announce_peer(b_addr, the_info_hash, b"bad".to_vec(), None)
+    .await
+    .unwrap_err();
+  assert!(matches!(
+    error,
+    RpcError::Remote(error) if error.code == error_code::PROTOCOL_ERROR
+  ));
+
+  // A node which does not answer.
+  let silent_socket = UdpSocket::bind(localhost(AddrFamily::V4)).await.unwrap();
+  let silent_addr = silent_socket.local_addr().unwrap();
+  assert!(matches!(
+    a_node.ping(silent_addr).await,
+    Err(RpcError::Timeout)
+  ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn find_closest_nodes() {
+  let (_router, a_node, b_node) = start_network(AddrFamily::V4).await;
+  let b_addr = b_node.local_addr().await.unwrap();
+
+  let target: NodeId = rand::random();
+  let nodes = a_node.find_closest_nodes(target).await;
+
+  assert!(nodes.iter().any(|node| node.addr == b_addr));
+  assert!(nodes
+    .windows(2)
+    .all(|pair| (pair[0].id ^ target) <= (pair[1].id ^ target)));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn search_with_options() {
+  let (_router, a_node, b_node) = start_network(AddrFamily::V4).await;
+  let a_addr = a_node.local_addr().await.unwrap();
+
+  // A announces itself as a seed on its own port.
+  let the_info_hash = InfoHash::sha1(b"with options");
+  let options = SearchOptions::new()
+    .set_announce(true)
+    .set_announce_port(7000)
+    .set_seed(true);
+  let mut search = a_node.search_with(the_info_hash, options);
+  assert_eq!(search.next().await, None);
+
+  let options = SearchOptions::new()
+    .set_max_peers(1)
+    .set_ip_version(IpVersion::V4);
+  let peers = b_node
+    .search_with(the_info_hash, options)
+    .collect::<Vec<_>>()
+    .await;
+  assert_eq!(peers, vec![SocketAddr::new(a_addr.ip(), 7000)]);
+
+  assert_eq!(
+    b_node.scrape(the_info_hash).await,
+    Some(Scrape { seeds: 1, peers: 0 })
+  );
+
+  // The search stops at the deadline, before the endgame is over.
+  let options = SearchOptions::new().set_deadline(Duration::from_millis(100));
+  let search = b_node.search_with(InfoHash::sha1(b"unknown"), options);
+  tokio::time::timeout(Duration::from_secs(1), search.collect::<Vec<_>>())
+    .await
+    .unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn search_events() {
+  let (_router, a_node, b_node) = start_network(AddrFamily::V4).await;
+  let a_addr = a_node.local_addr().await.unwrap();
+
+  let the_info_hash = InfoHash::sha1(b"with events");
+  let options = SearchOptions::new().set_announce(true);
+  let events = a_node
+    .search_events(the_info_hash, options)
+    .collect::<Vec<_>>()
+    .await;
+
+  assert!(matches!(events.first(), Some(SearchEvent::NodeQueried(_))));
+  assert!(events
+    .iter()
+    .any(|event| matches!(event, SearchEvent::NodeResponded(_))));
+  assert!(events.contains(&SearchEvent::EndgameStarted));
+  assert!(events
+    .iter()
+    .any(|event| matches!(event, SearchEvent::AnnounceSent(_))));
+  assert!(events.iter().any(|event| matches!(
+    event,
+    SearchEvent::AnnounceAcknowledged(AnnounceResult {
+      outcome: AnnounceOutcome::Accepted,
+      ..
+    })
+  )));
+  let Some(SearchEvent::Finished(stats)) = events.last() else {
+    panic!("the search did not finish: {:?}", events.last());
+  };
+  assert!(stats.nodes_contacted > 0);
+  assert_eq!(stats.peers_found, 0);
+
+  let events = b_node
+    .search_events(the_info_hash, SearchOptions::new())
+    .collect::<Vec<_>>()
+    .await;
+
+  let peers = events
+    .iter()
+    .filter_map(|event| match event {
+      SearchEvent::PeerFound { peer, .. } => Some(*peer),
+      _ => None,
+    })
+    .collect::<Vec<_>>();
+  assert_eq!(peers, vec![a_addr]);
+  let Some(SearchEvent::Finished(stats)) = events.last() else {
+    panic!("the search did not finish: {:?}", events.last());
+  };
+  assert_eq!(stats.peers_found, 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cancel_search() {
+  let (_router, a_node, b_node) = start_network(AddrFamily::V4).await;
+
+  // Neither search gets to announce.
+  let the_info_hash = InfoHash::sha1(b"cancelled");
+  let options = SearchOptions::new().set_announce(true);
+  drop(a_node.search_with(the_info_hash, options.clone()));
+
+  let mut events = a_node.search_events(the_info_hash, options);
+  assert!(matches!(
+    events.next().await,
+    Some(SearchEvent::NodeQueried(_))
+  ));
+  events.cancel();
+  while let Some(event) = events.next().await {
+    assert!(!matches!(
+      event,
+      SearchEvent::AnnounceSent(_) | SearchEvent::Finished(_)
+    ));
+  }
+
+  // Give the searches the time they would have needed to announce.
+  tokio::time::sleep(Duration::from_secs(2)).await;
+  let peers = b_node
+    .search(the_info_hash, false)
+    .collect::<Vec<_>>()
+    .await;
+  assert!(peers.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn coalesced_searches() {
+  let (_router, a_node, b_node) = start_network(AddrFamily::V4).await;
+  let a_addr = a_node.local_addr().await.unwrap();
+
+  let the_info_hash = InfoHash::sha1(b"coalesced");
+  let mut search = a_node.search(the_info_hash, true);
+  assert_eq!(search.next().await, None);
+
+  let mut first = b_node.search_events(the_info_hash, SearchOptions::new());
+  while let Some(event) = first.next().await {
+    if event == SearchEvent::EndgameStarted {
+      break;
+    }
+  }
+
+  // The second search joins the first one once all its requests are sent,
+  // the peer found so far is replayed to it.
+  let options = SearchOptions::new().set_announce(true);
+  let second = b_node
+    .search_events(the_info_hash, options)
+    .collect::<Vec<_>>()
+    .await;
+  assert!(matches!(
+    second.first(),
+    Some(SearchEvent::PeerFound { peer, .. }) if *peer == a_addr
+  ));
+  assert!(!second
+    .iter()
+    .any(|event| matches!(event, SearchEvent::NodeQueried(_))));
+
+  // The shared lookup announces since the second search asked for it.
+  assert!(second
+    .iter()
+    .any(|event| matches!(event, SearchEvent::AnnounceSent(_))));
+  let first = first.collect::<Vec<_>>().await;
+  assert!(first
+    .iter()
+    .any(|event| matches!(event, SearchEvent::AnnounceSent(_))));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn conflicting_announces() {
+  let (_router, a_node, b_node) = start_network(AddrFamily::V4).await;
+  let a_ip = a_node.local_addr().await.unwrap().ip();
+
+  // The searches announcing different ports do not share a lookup.
+  let the_info_hash = InfoHash::sha1(b"conflicting announces");
+  let options = SearchOptions::new().set_announce(true);
+  let first =
+    a_node.search_with(the_info_hash, options.clone().set_announce_port(1111));
+  let second =
+    a_node.search_with(the_info_hash, options.set_announce_port(2222));
+  tokio::join!(first.collect::<Vec<_>>(), second.collect::<Vec<_>>());
+
+  let mut peers = b_node
+    .search(the_info_hash, false)
+    .collect::<Vec<_>>()
+    .await;
+  peers.sort();
+  assert_eq!(
+    peers,
+    vec![SocketAddr::new(a_ip, 1111), SocketAddr::new(a_ip, 2222)]
+  );
+
+  // Once the search which asked to announce is gone, the shared lookup does
+  // not announce anymore.
+  let the_info_hash = InfoHash::sha1(b"announcing search gone");
+  let announcing =
+    a_node.search_with(the_info_hash, SearchOptions::new().set_announce(true));
+  let events = a_node.search_events(the_info_hash, SearchOptions::new());
+  drop(announcing);
+  let events = events.collect::<Vec<_>>().await;
+  assert!(matches!(events.last(), Some(SearchEvent::Finished(_))));
+  assert!(!events
+    .iter()
+    .any(|event| matches!(event, SearchEvent::AnnounceSent(_))));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn lookup_queue() {
+  let (router, _a_node, _b_node) = start_network(AddrFamily::V4).await;
+  let router_addr = router.local_addr().await.unwrap();
+
+  let socket = UdpSocket::bind(localhost(AddrFamily::V4)).await.unwrap();
+  let node = MainlineDht::builder()
+    .add_node(router_addr)
+    .set_read_only(false)
+    .set_max_concurrent_lookups(1)
+    .start("c_node", socket)
+    .unwrap();
+  assert!(node.bootstrapped(None).await);
+  wait_for_nodes(&node).await;
+
+  let background =
+    SearchOptions::new().set_priority(SearchPriority::Background);
+  let first = node.search(InfoHash::sha1(b"first"), false);
+  let second = node.search_with(InfoHash::sha1(b"background"), background);
+  let third = node.search(InfoHash::sha1(b"user"), false);
+
+  let state = node.get_state().await.unwrap();
+  assert_eq!(state.running_lookups, 1);
+  assert_eq!(state.pending_lookups, 2);
+
+  // The search of the user overtakes the background one.
+  let finished_at = |search: bt_rust_dht::builder::SearchStream| async {
+    search.collect::<Vec<_>>().await;
+    Instant::now()
+  };
+  let (first, second, third) =
+    tokio::join!(finished_at(first), finished_at(second), finished_at(third));
+  assert!(first < third);
+  assert!(third < second);
+
+  let state = node.get_state().await.unwrap();
+  assert_eq!(state.running_lookups, 0);
+  assert_eq!(state.pending_lookups, 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn periodic_announce() {
+  let (_router, a_node, b_node) = start_network(AddrFamily::V4).await;
+  let a_addr = a_node.local_addr().await.unwrap();
+
+  let the_info_hash = InfoHash::sha1(b"periodic");
+  a_node.start_announcing(the_info_hash, 7001).unwrap();
+
+  let mut status = a_node.announce_status(the_info_hash).await.unwrap();
+  for _ in 0..100 {
+    if !status.announcing {
+      break;
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    status = a_node.announce_status(the_info_hash).await.unwrap();
+  }
+  assert!(!status.announcing);
+  assert_eq!(status.port, 7001);
+  assert!(status.last_success.is_some());
+  assert!(status
+    .last_results
+    .iter()
+    .any(|result| result.outcome == AnnounceOutcome::Accepted));
+  assert!(status.next_announce.is_some());
+
+  let peers = b_node
+    .search(the_info_hash, false)
+    .collect::<Vec<_>>()
+    .await;
+  assert_eq!(peers, vec![SocketAddr::new(a_addr.ip(), 7001)]);
+
+  a_node.stop_announcing(the_info_hash).unwrap();
+  assert!(a_node.announce_status(the_info_hash).await.is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn announce_with_cached_tokens() {
+  let (_router, a_node, b_node) = start_network(AddrFamily::V4).await;
+  let a_addr = a_node.local_addr().await.unwrap();
+
+  // The search gets the tokens of the closest nodes.
+  let the_info_hash = InfoHash::sha1(b"cached tokens");
+  let peers = a_node
+    .search(the_info_hash, false)
+    .collect::<Vec<_>>()
+    .await;
+  assert!(peers.is_empty());
+
+  // The announce goes straight to them, without waiting for the endgame of
+  // a search.
+  let started_at = Instant::now();
+  let results = a_node.announce(the_info_hash).await.unwrap();
+  assert!(started_at.elapsed() < Duration::from_secs(1));
+  assert!(!results.is_empty());
+  assert!(results
+    .iter()
+    .all(|result| result.outcome == AnnounceOutcome::Accepted));
+
+  let peers = b_node
+    .search(the_info_hash, false)
+    .collect::<Vec<_>>()
+    .await;
+  assert_eq!(peers, vec![a_addr]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn announce_with_refused_tokens() {
+  let (router, a_node, b_node) = start_network(AddrFamily::V4).await;
+  let router_addr = router.local_addr().await.unwrap();
+  let a_addr = a_node.local_addr().await.unwrap();
+  let b_addr = b_node.local_addr().await.unwrap();
+
+  let the_info_hash = InfoHash::sha1(b"refused tokens");
+  let peers = a_node
+    .search(the_info_hash, false)
+    .collect::<Vec<_>>()
+    .await;
+  assert!(peers.is_empty());
+
+  // B restarts with a new token secret, the token A got from it is refused.
+  b_node.shutdown().await;
+  let b_socket = UdpSocket::bind(b_addr).await.unwrap();
+  let b_node = MainlineDht::builder()
+    .add_node(router_addr)
+    .set_read_only(false)
+    .start("b_node", b_socket)
+    .unwrap();
+  assert!(b_node.bootstrapped(None).await);
+  wait_for_nodes(&b_node).await;
+
+  // Only the answers of the full lookup which follows are reported.
+  let results = a_node.announce(the_info_hash).await.unwrap();
+  assert!(!results.is_empty());
+  assert!(results
+    .iter()
+    .all(|result| result.outcome == AnnounceOutcome::Accepted));
+
+  let peers = b_node
+    .search(the_info_hash, false)
+    .collect::<Vec<_>>()
+    .await;
+  assert_eq!(peers, vec![a_addr]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dual_stack() {
+  let a_node = MainlineDht::builder()
+    .set_read_only(false)
+    .start_dual_stack(
+      "a_node",
+      UdpSocket::bind(localhost(AddrFamily::V4)).await.unwrap(),
+      UdpSocket::bind(localhost(AddrFamily::V6)).await.unwrap(),
+    )
+    .unwrap();
+  let a_v4_addr = a_node.v4().local_addr().await.unwrap();
+  let a_v6_addr = a_node.v6().local_addr().await.unwrap();
+
+  // C only runs on IPv6.
+  let c_socket = UdpSocket::bind(localhost(AddrFamily::V6)).await.unwrap();
+  let c_node = MainlineDht::builder()
+    .add_node(a_v6_addr)
+    .set_read_only(false)
+    .start("c_node", c_socket)
+    .unwrap();
+  let c_addr = c_node.local_addr().await.unwrap();
+  assert!(c_node.bootstrapped(None).await);
+  wait_for_nodes(&c_node).await;
+
+  // A tells about C on IPv4 to those which want both families.
+  let socket = UdpSocket::bind(localhost(AddrFamily::V4)).await.unwrap();
+  let mut buffer = [0u8; 1500];
+  let query = b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz1234564:wantl2:n42:n6ee1:q9:find_node1:t2:aa1:y1:qe";
+  let mut nodes_v6 = Vec::new();
+  for _ in 0..50 {
+    socket.send_to(query, a_v4_addr).await.unwrap();
+    let (len, _) = socket.recv_from(&mut buffer).await.unwrap();
+    let message = Message::decode(&buffer[..len]).unwrap();
+    let MessageBody::Response(response) = message.body else {
+      panic!("not a response: {:?}", message.body);
+    };
+    nodes_v6 = response.nodes_v6;
+    if !nodes_v6.is_empty() {
+      break;
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+  }
+  assert_eq!(
+    nodes_v6.iter().map(|node| node.addr).collect::<Vec<_>>(),
+    vec![c_addr]
+  );
+
+  // B bootstraps on IPv4 only, and learns about C on IPv6 from A.
+  let b_node = MainlineDht::builder()
+    .add_node(a_v4_addr)
+    .set_read_only(false)
+    .start_dual_stack(
+      "b_node",
+      UdpSocket::bind(localhost(AddrFamily::V4)).await.unwrap(),
+      UdpSocket::bind(localhost(AddrFamily::V6)).await.unwrap(),
+    )
+    .unwrap();
+  assert!(b_node.bootstrapped(None).await);
+
+  wait_for_nodes(b_node.v6()).await;
+  assert!(b_node.v6().get_nodes().await.unwrap().contains(&c_addr));
+
+  // The search of B runs on both families, the peer is found on IPv6.
+  let the_info_hash = InfoHash::sha1(b"dual stack");
+  let results = c_node.announce(the_info_hash).await.unwrap();
+  assert!(!results.is_empty());
+
+  let peers = b_node
+    .search(the_info_hash, false)
+    .collect::<Vec<_>>()
+    .await;
+  assert_eq!(peers, vec![c_addr]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shutdown() {
+  let (router, a_node, b_node) = start_network(AddrFamily::V4).await;
+  let router_addr = router.local_addr().await.unwrap();
+
+  // Aborting the lookups, A stops before announcing.
+  let a_info_hash = InfoHash::sha1(b"shutdown");
+  let a_search = a_node.search(a_info_hash, true);
+  let a_events =
+    a_node.search_events(a_info_hash, SearchOptions::new().set_announce(true));
+  let started_at = Instant::now();
+  a_node.shutdown().await;
+  assert!(started_at.elapsed() < Duration::from_secs(1));
+  assert!(a_search.collect::<Vec<_>>().await.is_empty());
+  // The aborted search is not reported as finished.
+  assert!(!a_events
+    .collect::<Vec<_>>()
+    .await
+    .iter()
+    .any(|event| matches!(event, SearchEvent::Finished(_))));
+
+  // C saves the size of its routing table once stopped.
+  let (saved_tx, mut saved_rx) = tokio::sync::mpsc::unbounded_channel();
+  let c_socket = UdpSocket::bind(localhost(AddrFamily::V4)).await.unwrap();
+  let c_addr = c_socket.local_addr().unwrap();
+  let c_node = MainlineDht::builder()
+    .add_node(router_addr)
+    .set_read_only(false)
+    .add_shutdown_hook(move |table| {
+      saved_tx.send(table.num_good_nodes()).unwrap();
+    })
+    .start("c_node", c_socket)
+    .unwrap();
+  assert!(c_node.bootstrapped(None).await);
+  wait_for_nodes(&c_node).await;
+
+  // C waits for its announce to be sent before stopping.
+  let c_info_hash = InfoHash::sha1(b"announced before shutdown");
+  let c_search = c_node.search(c_info_hash, true);
+  c_node
+    .shutdown_after_announces(Duration::from_secs(10))
+    .await;
+  assert!(saved_rx.recv().await.unwrap() > 0);
+  c_search.collect::<Vec<_>>().await;
+
+  // The socket of C is closed.
+  UdpSocket::bind(c_addr).await.unwrap();
+
+  let peers = b_node.search(c_info_hash, false).collect::<Vec<_>>().await;
+  assert_eq!(peers, vec![c_addr]);
+  let peers = b_node.search(a_info_hash, false).collect::<Vec<_>>().await;
+  assert!(peers.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn restore_table() {
+  let (router, _a_node, _b_node) = start_network(AddrFamily::V4).await;
+  let router_addr = router.local_addr().await.unwrap();
+  let table_file = std::env::temp_dir()
+    .join(format!("bt-rust-dht-table-{}", std::process::id()));
+
+  // C saves its routing table once stopped.
+  let c_socket = UdpSocket::bind(localhost(AddrFamily::V4)).await.unwrap();
+  let c_node = MainlineDht::builder()
+    .add_node(router_addr)
+    .set_read_only(false)
+    .set_table_file(&table_file)
+    .start("c_node", c_socket)
+    .unwrap();
+  assert!(c_node.bootstrapped(None).await);
+  wait_for_nodes(&c_node).await;
+  c_node.shutdown().await;
+
+  let saved = SavedTable::load(&table_file).unwrap();
+  assert!(!saved.nodes.is_empty());
+
+  // Restarted without any router or node, C bootstraps against the nodes it
+  // saved and keeps its node id.
+  let c_socket = UdpSocket::bind(localhost(AddrFamily::V4)).await.unwrap();
+  let c_node = MainlineDht::builder()
+    .set_read_only(false)
+    .set_table_file(&table_file)
+    .start("c_node", c_socket)
+    .unwrap();
+  assert!(c_node.bootstrapped(None).await);
+  wait_for_nodes(&c_node).await;
+  c_node.shutdown().await;
+
+  assert_eq!(SavedTable::load(&table_file).unwrap().id, saved.id);
+  std::fs::remove_file(&table_file).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn read_only_node_not_added() {
+  let (router, a_node, _b_node) = start_network(AddrFamily::V4).await;
+  let router_addr = router.local_addr().await.unwrap();
+  let a_addr = a_node.local_addr().await.unwrap();
+
+  // C bootstraps as a read-only node, its queries carry the "ro" flag.
+  let c_socket = UdpSocket::bind(localhost(AddrFamily::V4)).await.unwrap();
+  let c_addr = c_socket.local_addr().unwrap();
+  let c_node = MainlineDht::builder()
+    .add_node(router_addr)
+    .set_read_only(true)
+    .start("c_node", c_socket)
+    .unwrap();
+
+  assert!(c_node.bootstrapped(None).await);
+  wait_for_nodes(&c_node).await;
+
+  let router_nodes = router.get_nodes().await.unwrap();
+  assert!(router_nodes.contains(&a_addr));
+  assert!(!router_nodes.contains(&c_addr));
+}
+
+/// Start a router node and two nodes bootstrapping against it.
+async fn start_network(
+  addr_family: AddrFamily,
+) -> (MainlineDht, MainlineDht, MainlineDht) {
+  let router_socket = UdpSocket::bind(localhost(addr_family)).await.unwrap();
+  let router_addr = router_socket.local_addr().unwrap();
+  let router = MainlineDht::builder()
+    .set_read_only(false)
+    .start("router", router_socket)
+    .unwrap();
+
+  let mut nodes = Vec::new();
+  for name in ["a_node", "b_node"] {
+    let socket = UdpSocket::bind(localhost(addr_family)).await.unwrap();
+    let node = MainlineDht::builder()
+      .add_node(router_addr)
+      .set_read_only(false)
+      .start(name, socket)
+      .unwrap();
+
+    assert!(node.bootstrapped(None).await);
+    wait_for_nodes(&node).await;
+    nodes.push(node);
+  }
+
+  let b_node = nodes.pop().unwrap();
+  let a_node = nodes.pop().unwrap();
+
+  (router, a_node, b_node)
+}
+
+async fn wait_for_nodes(node: &MainlineDht) {
+  for _ in 0..100 {
+    if node.get_state().await.unwrap().good_node_count > 0 {
+      return;
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+  }
+  panic!("node did not learn about any other node");
+}
+
 #[derive(Copy, Clone)]
 enum AddrFamily {
   V4,